@@ -1,11 +1,454 @@
-use clap::Parser;
+use crate::{AccessFormat, AccessLog, Anonymity, BanConfig, CronSchedule, Exhaustion, HealthConfig, ListFormat, Listener, PoolSource, PortRange, Probe, ProbeConfig, ProxyAuth, ProxyFilter, RotationMode, RotationPolicy, Secrets};
+use crate::config_file::ConfigFile;
+use crate::errors::ProxyError;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-#[derive(Clone, Debug, Parser)]
+#[derive(Clone, Parser)]
 pub struct Config {
-    #[arg(short, long, default_value_t = 8080)]
-    pub port: i16,
-    #[arg(long, default_value = "proxies.txt")]
+    /// TOML or YAML file of settings, flags and environment variables override it
+    #[arg(short, long, env = "QPROXY_CONFIG")]
+    pub config: Option<String>,
+    #[arg(short, long, default_value_t = 8080, env = "QPROXY_PORT")]
+    pub port: u16,
+    /// Address the listeners bind to unless they set their own
+    #[arg(long, default_value = "127.0.0.1", env = "QPROXY_BIND")]
+    pub bind: IpAddr,
+    #[arg(long, default_value = "proxies.txt", env = "QPROXY_PROXIES_PATH")]
     pub proxies_path: String,
-    #[arg(long, default_value_t = 300)] //in seconds 5m = 60 * 5 = 300
+    /// Format of the proxies file: text, json, csv or yaml, from the extension by default
+    #[arg(long, env = "QPROXY_PROXIES_FORMAT")]
+    pub proxies_format: Option<ListFormat>,
+    /// Passphrase of lists encrypted with `age -p`, `${file:PATH}` reads it from a file
+    #[arg(long, env = "QPROXY_PROXIES_PASSPHRASE", hide_env_values = true)]
+    pub proxies_passphrase: Option<String>,
+    /// age identity file of lists encrypted to a key
+    #[arg(long, env = "QPROXY_PROXIES_KEY")]
+    pub proxies_key: Option<String>,
+    #[arg(long, default_value_t = 300, env = "QPROXY_ROTATE_INTERVAL")] //in seconds 5m = 60 * 5 = 300
     pub rotate_interval: i64, //in seconds 10p = 60 * 10 = 600
+    #[arg(long, env = "QPROXY_ROTATE_JITTER")] //in seconds, the rotate interval varies by up to this much either way
+    pub rotate_jitter: Option<u64>,
+    /// Rotate after this many connections through the proxy
+    #[arg(long, env = "QPROXY_ROTATE_REQUESTS")]
+    pub rotate_requests: Option<u64>,
+    /// Rotate after this many bytes relayed through the proxy
+    #[arg(long, env = "QPROXY_ROTATE_BYTES")]
+    pub rotate_bytes: Option<u64>,
+    /// Rotate after this many connections in a row the proxy failed to open
+    #[arg(long, env = "QPROXY_ROTATE_FAILURES")]
+    pub rotate_failures: Option<u32>,
+    /// Rotate on a cron schedule in UTC, e.g. `0 */6 * * *`
+    #[arg(long, env = "QPROXY_ROTATE_CRON")]
+    pub rotate_cron: Option<CronSchedule>,
+    /// What happens to open connections on the old proxy of a rotation: soft
+    /// lets them drain, hard closes them
+    #[arg(long, env = "QPROXY_ROTATE_MODE")]
+    pub rotate_mode: Option<RotationMode>,
+    #[arg(long, env = "QPROXY_ROTATE_GRACE")] //in seconds, soft rotations close the connections left after it
+    pub rotate_grace: Option<u64>,
+    /// What listeners do with new connections while no proxy is available:
+    /// fail, queue or direct. Direct also needs --allow-direct
+    #[arg(long, default_value_t = Exhaustion::Fail, env = "QPROXY_ON_EXHAUSTED")]
+    pub on_exhausted: Exhaustion,
+    #[arg(long, default_value_t = 30, env = "QPROXY_QUEUE_TIMEOUT")] //in seconds, how long queued connections wait for a proxy
+    pub queue_timeout: u64,
+    /// Kill switch of direct connections, without it no traffic ever leaves
+    /// without a proxy
+    #[arg(long, default_value_t = false, env = "QPROXY_ALLOW_DIRECT")]
+    pub allow_direct: bool,
+    /// Inbound credentials as `user:pass`, session parameters may follow the user.
+    /// Either part may be `${env:NAME}` or `${file:PATH}`
+    #[arg(long, env = "QPROXY_AUTH", hide_env_values = true)]
+    pub auth: Option<ProxyAuth>,
+    #[arg(long, default_value_t = 600, env = "QPROXY_SESSION_LIFETIME")] //in seconds, for sessions without a lifetime parameter
+    pub session_lifetime: i64,
+    #[arg(long, default_value_t = 86400, env = "QPROXY_SESSION_MAX_LIFETIME")] //in seconds, longer lifetime parameters are capped to it
+    pub session_max_lifetime: u64,
+    #[arg(long, default_value_t = 60, env = "QPROXY_HEALTH_INTERVAL")] //in seconds, 0 disables the background health checker
+    pub health_interval: u64,
+    #[arg(long, default_value_t = 10, env = "QPROXY_HEALTH_JITTER")] //in seconds, random delay added to every health check round
+    pub health_jitter: u64,
+    /// Consecutive failures before a proxy is ejected
+    #[arg(long, default_value_t = 3, env = "QPROXY_EJECT_AFTER")]
+    pub eject_after: u32,
+    /// Consecutive successful checks before an ejected proxy is re-admitted
+    #[arg(long, default_value_t = 2, env = "QPROXY_READMIT_AFTER")]
+    pub readmit_after: u32,
+    #[arg(long, default_value_t = 3600, env = "QPROXY_MAX_BACKOFF")] //in seconds, cap of the retry backoff of ejected proxies
+    pub max_backoff: u64,
+    /// Banned looking outcomes in a row before a proxy cools down for a
+    /// destination, 0 disables ban detection
+    #[arg(long, default_value_t = 3, env = "QPROXY_BAN_THRESHOLD")]
+    pub ban_threshold: u32,
+    #[arg(long, default_value_t = 600, env = "QPROXY_BAN_COOLDOWN")] //in seconds, the proxy is not used for that destination meanwhile
+    pub ban_cooldown: u64,
+    /// Status of a forwarded HTTP response that looks like a ban, repeatable
+    #[arg(long = "ban-status", default_values_t = [403, 429], env = "QPROXY_BAN_STATUS")]
+    pub ban_statuses: Vec<u16>,
+    /// Text of a forwarded HTTP response that looks like a ban, e.g. from a
+    /// captcha page, repeatable
+    #[arg(long = "ban-marker", env = "QPROXY_BAN_MARKER")]
+    pub ban_markers: Vec<String>,
+    /// Health check probe, repeatable: tcp, handshake, connect=host:port,
+    /// http=URL[;status=200][;body=text], tls=host[:port][;insecure], handshake when unset
+    #[arg(long = "check", env = "QPROXY_CHECK")]
+    pub checks: Vec<Probe>,
+    /// Passing probes required for a proxy to be healthy, 0 means all
+    #[arg(long, default_value_t = 0, env = "QPROXY_CHECK_QUORUM")]
+    pub check_quorum: usize,
+    #[arg(long, default_value_t = 10, env = "QPROXY_CHECK_TIMEOUT")] //in seconds, per probe
+    pub check_timeout: u64,
+    /// "What is my IP" endpoint used to discover the egress IP of each proxy
+    #[arg(long, env = "QPROXY_EGRESS_URL")]
+    pub egress_url: Option<String>,
+    /// Treat proxies sharing an egress IP as one upstream when rotating
+    #[arg(long, default_value_t = false, env = "QPROXY_DEDUPE_EGRESS")]
+    pub dedupe_egress: bool,
+    /// Header-echo endpoint used to classify the anonymity of each proxy
+    #[arg(long, env = "QPROXY_ANONYMITY_URL")]
+    pub anonymity_url: Option<String>,
+    /// Our own public address, proxies forwarding it are transparent
+    #[arg(long, env = "QPROXY_CLIENT_IP")]
+    pub client_ip: Option<IpAddr>,
+    /// Only use proxies of at least this level: transparent, anonymous, elite
+    #[arg(long, env = "QPROXY_MIN_ANONYMITY")]
+    pub min_anonymity: Option<Anonymity>,
+    /// MaxMind-format country database (mmdb)
+    #[arg(long, env = "QPROXY_COUNTRY_DB")]
+    pub country_db: Option<String>,
+    /// MaxMind-format ASN database (mmdb)
+    #[arg(long, env = "QPROXY_ASN_DB")]
+    pub asn_db: Option<String>,
+    /// File of blocklisted CIDRs, repeatable
+    #[arg(long = "blocklist", env = "QPROXY_BLOCKLIST")]
+    pub blocklists: Vec<String>,
+    /// Selection filter, e.g. `country=US,asn!=AS16509,exclude-blocklisted`
+    #[arg(long, env = "QPROXY_FILTER")]
+    pub filter: Option<ProxyFilter>,
+    /// Named pool as `name=path[;key=value...]`, the tags are set on its proxies, repeatable
+    #[arg(long = "pool", env = "QPROXY_POOL")]
+    pub pools: Vec<PoolSource>,
+    #[arg(long, default_value_t = 300, env = "QPROXY_SOURCE_REFRESH")] //in seconds, refresh of remote pools without a refresh option
+    pub source_refresh: u64,
+    /// Append-only log of check results, latency history and bans, without it
+    /// the state is kept in memory only
+    #[arg(long, env = "QPROXY_STATE_PATH")]
+    pub state_path: Option<String>,
+    #[arg(long, default_value_t = 3600, env = "QPROXY_STATE_TTL")] //in seconds, older check results are re-verified on startup
+    pub state_ttl: u64,
+    /// Live proxies needed before the listeners get their proxies, the
+    /// remaining ones are checked in the background
+    #[arg(long, default_value_t = 1, env = "QPROXY_READY_AFTER")]
+    pub ready_after: usize,
+    /// Proxy checks running at the same time
+    #[arg(long, default_value_t = 64, env = "QPROXY_CHECK_CONCURRENCY")]
+    pub check_concurrency: usize,
+    /// Listener bound to a selector as `port:filter`, e.g. `9001:pool=datacenter`,
+    /// repeatable. Replaces the default listener on `--port`
+    #[arg(long = "listen", env = "QPROXY_LISTEN")]
+    pub listeners: Vec<Listener>,
+    /// One listener per live proxy on this range, e.g. `10000-10499`.
+    /// Replaces the default listener on `--port`
+    #[arg(long, env = "QPROXY_PORT_RANGE")]
+    pub port_range: Option<PortRange>,
+    /// CSV file the port to proxy table of `--port-range` is written to
+    #[arg(long, env = "QPROXY_PORT_TABLE")]
+    pub port_table: Option<String>,
+    /// OTLP/HTTP collector the connection spans are exported to, e.g. http://localhost:4318
+    #[arg(long, env = "QPROXY_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// File each client connection is logged to, one record per connection
+    #[arg(long, env = "QPROXY_ACCESS_LOG")]
+    pub access_log: Option<String>,
+    /// Access log format: json or squid
+    #[arg(long, default_value = "json", env = "QPROXY_ACCESS_LOG_FORMAT")]
+    pub access_log_format: AccessFormat,
+    #[arg(long, default_value_t = 100, env = "QPROXY_ACCESS_LOG_MAX_SIZE")] //in megabytes, 0 never rotates on size
+    pub access_log_max_size: u64,
+    #[arg(long, default_value_t = 86400, env = "QPROXY_ACCESS_LOG_MAX_AGE")] //in seconds, 0 never rotates on age
+    pub access_log_max_age: u64,
+    /// Rotated access log files kept
+    #[arg(long, default_value_t = 5, env = "QPROXY_ACCESS_LOG_KEEP")]
+    pub access_log_keep: usize,
+    /// Address serving `/metrics` without authentication, the admin API serves it as well
+    #[arg(long, env = "QPROXY_METRICS_LISTEN")]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Label metrics with the upstream address, one series per proxy
+    #[arg(long, default_value_t = false, env = "QPROXY_METRICS_PER_PROXY")]
+    pub metrics_per_proxy: bool,
+    /// Address of the admin API
+    #[arg(long, env = "QPROXY_ADMIN_LISTEN")]
+    pub admin_listen: Option<SocketAddr>,
+    /// Bearer token required by the admin API
+    #[arg(long, env = "QPROXY_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+/// The default of every setting, the arguments and the environment aren't read.
+impl Default for Config {
+    fn default() -> Self {
+        Config::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(["qproxy"])
+            .and_then(|matches| Config::from_arg_matches(&matches))
+            .expect("every setting has a valid default")
+    }
+}
+
+// written out so that secrets are masked, like in `Secrets`
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("config", &self.config)
+            .field("port", &self.port)
+            .field("bind", &self.bind)
+            .field("proxies_path", &self.proxies_path)
+            .field("proxies_format", &self.proxies_format)
+            .field("proxies_passphrase", &self.proxies_passphrase.as_ref().map(|_| "***"))
+            .field("proxies_key", &self.proxies_key)
+            .field("rotate_interval", &self.rotate_interval)
+            .field("rotate_jitter", &self.rotate_jitter)
+            .field("rotate_requests", &self.rotate_requests)
+            .field("rotate_bytes", &self.rotate_bytes)
+            .field("rotate_failures", &self.rotate_failures)
+            .field("rotate_cron", &self.rotate_cron)
+            .field("rotate_mode", &self.rotate_mode)
+            .field("rotate_grace", &self.rotate_grace)
+            .field("on_exhausted", &self.on_exhausted)
+            .field("queue_timeout", &self.queue_timeout)
+            .field("allow_direct", &self.allow_direct)
+            .field("auth", &self.auth)
+            .field("session_lifetime", &self.session_lifetime)
+            .field("session_max_lifetime", &self.session_max_lifetime)
+            .field("health_interval", &self.health_interval)
+            .field("health_jitter", &self.health_jitter)
+            .field("eject_after", &self.eject_after)
+            .field("readmit_after", &self.readmit_after)
+            .field("max_backoff", &self.max_backoff)
+            .field("ban_threshold", &self.ban_threshold)
+            .field("ban_cooldown", &self.ban_cooldown)
+            .field("ban_statuses", &self.ban_statuses)
+            .field("ban_markers", &self.ban_markers)
+            .field("checks", &self.checks)
+            .field("check_quorum", &self.check_quorum)
+            .field("check_timeout", &self.check_timeout)
+            .field("egress_url", &self.egress_url)
+            .field("dedupe_egress", &self.dedupe_egress)
+            .field("anonymity_url", &self.anonymity_url)
+            .field("client_ip", &self.client_ip)
+            .field("min_anonymity", &self.min_anonymity)
+            .field("country_db", &self.country_db)
+            .field("asn_db", &self.asn_db)
+            .field("blocklists", &self.blocklists)
+            .field("filter", &self.filter)
+            .field("pools", &self.pools)
+            .field("source_refresh", &self.source_refresh)
+            .field("state_path", &self.state_path)
+            .field("state_ttl", &self.state_ttl)
+            .field("ready_after", &self.ready_after)
+            .field("check_concurrency", &self.check_concurrency)
+            .field("listeners", &self.listeners)
+            .field("port_range", &self.port_range)
+            .field("port_table", &self.port_table)
+            .field("otlp_endpoint", &self.otlp_endpoint)
+            .field("access_log", &self.access_log)
+            .field("access_log_format", &self.access_log_format)
+            .field("access_log_max_size", &self.access_log_max_size)
+            .field("access_log_max_age", &self.access_log_max_age)
+            .field("access_log_keep", &self.access_log_keep)
+            .field("metrics_listen", &self.metrics_listen)
+            .field("metrics_per_proxy", &self.metrics_per_proxy)
+            .field("admin_listen", &self.admin_listen)
+            .field("admin_token", &self.admin_token.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl Config {
+    /// Settings from the command line, the environment and the `--config` file,
+    /// in that order of precedence. Exits on `--help` and invalid flags.
+    pub fn load() -> Result<Self, ProxyError> {
+        Config::from_matches(Config::command().get_matches())
+    }
+
+    /// Same as `load` with the given arguments instead of the process ones.
+    pub fn load_from<I, T>(args: I) -> Result<Self, ProxyError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Config::command()
+            .try_get_matches_from(args)
+            .map_err(|e| ProxyError::ConfigError(e.to_string()))?;
+        Config::from_matches(matches)
+    }
+
+    fn from_matches(matches: ArgMatches) -> Result<Self, ProxyError> {
+        let mut config = Config::from_arg_matches(&matches).map_err(|e| ProxyError::ConfigError(e.to_string()))?;
+        if let Some(path) = config.config.clone() {
+            let is_set = |id: &str| {
+                matches!(
+                    matches.value_source(id),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            };
+            ConfigFile::read(&path)
+                .and_then(|file| file.apply(&mut config, is_set))
+                .map_err(|e| ProxyError::ConfigError(format!("{}: {}", path, e)))?;
+        }
+        config.resolve_secrets().map_err(ProxyError::ConfigError)?;
+        config.validate().map_err(ProxyError::ConfigError)?;
+        Ok(config)
+    }
+
+    /// Replace the `${env:NAME}` and `${file:PATH}` references of the inbound credentials.
+    fn resolve_secrets(&mut self) -> Result<(), String> {
+        if let Some(auth) = &self.auth {
+            self.auth = Some(Secrets::resolve_auth(auth).map_err(|e| format!("auth: {}", e))?);
+        }
+        for listener in self.listeners.iter_mut() {
+            if let Some(auth) = &listener.auth {
+                let resolved = Secrets::resolve_auth(auth).map_err(|e| format!("listener {}: {}", listener.port, e))?;
+                listener.auth = Some(resolved);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys of encrypted proxy lists.
+    pub fn secrets(&self) -> Secrets {
+        Secrets::new(self.proxies_passphrase.clone(), self.proxies_key.clone())
+    }
+
+    /// Checks flags can't do on their own.
+    fn validate(&self) -> Result<(), String> {
+        let mut ports = HashSet::new();
+        for listener in self.listeners.iter() {
+            if !ports.insert(listener.port) {
+                return Err(format!("Port {} is used by several listeners", listener.port));
+            }
+            if let Some(range) = self.port_range.filter(|r| (r.start..=r.end).contains(&listener.port)) {
+                return Err(format!(
+                    "Listener port {} is in the port range {}-{}",
+                    listener.port, range.start, range.end
+                ));
+            }
+        }
+        let mut names = HashSet::new();
+        for name in self.pools.iter().filter_map(|p| p.name.as_ref()) {
+            if !names.insert(name) {
+                return Err(format!("Pool {} is defined several times", name));
+            }
+        }
+        if self.admin_listen.is_some() && self.admin_token.as_deref().unwrap_or_default().is_empty() {
+            return Err("The admin API needs a token, set admin_token".to_string());
+        }
+        if self.on_exhausted == Exhaustion::Direct && !self.allow_direct {
+            return Err("on_exhausted = direct needs allow_direct, no traffic leaves without a proxy otherwise".to_string());
+        }
+        if self.check_concurrency == 0 {
+            return Err("check_concurrency must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn credentials(&self) -> Option<ProxyAuth> {
+        self.auth.clone()
+    }
+
+    /// The unnamed `--proxies-path` list followed by the named pools.
+    pub fn pool_sources(&self) -> Vec<PoolSource> {
+        let mut sources = vec![PoolSource {
+            format: self.proxies_format,
+            ..PoolSource::unnamed(&self.proxies_path)
+        }];
+        sources.extend(self.pools.iter().cloned());
+        for source in sources.iter_mut().filter(|s| s.is_remote()) {
+            source.refresh = source.refresh.or(Some(Duration::from_secs(self.source_refresh)));
+        }
+        sources
+    }
+
+    pub fn health_config(&self) -> HealthConfig {
+        HealthConfig {
+            interval: Duration::from_secs(self.health_interval),
+            jitter: Duration::from_secs(self.health_jitter),
+            eject_after: self.eject_after,
+            readmit_after: self.readmit_after,
+            max_backoff: Duration::from_secs(self.max_backoff),
+        }
+    }
+
+    /// The exhaustion policy, direct only with the kill switch released.
+    pub fn exhaustion(&self) -> Exhaustion {
+        match self.on_exhausted {
+            Exhaustion::Direct if !self.allow_direct => Exhaustion::Fail,
+            exhaustion => exhaustion,
+        }
+    }
+
+    pub fn ban_config(&self) -> BanConfig {
+        BanConfig {
+            threshold: self.ban_threshold,
+            cooldown: Duration::from_secs(self.ban_cooldown),
+            statuses: self.ban_statuses.clone(),
+            markers: self.ban_markers.clone(),
+        }
+    }
+
+    /// Rotation triggers of the listeners that set none of their own.
+    pub fn rotation_policy(&self) -> RotationPolicy {
+        RotationPolicy {
+            jitter: self.rotate_jitter,
+            requests: self.rotate_requests,
+            bytes: self.rotate_bytes,
+            failures: self.rotate_failures,
+            schedule: self.rotate_cron.clone(),
+            mode: self.rotate_mode,
+            grace: self.rotate_grace,
+        }
+    }
+
+    /// Constraints every selected upstream has to satisfy.
+    pub fn proxy_filter(&self) -> ProxyFilter {
+        let filter = self.filter.clone().unwrap_or_default();
+        ProxyFilter {
+            min_anonymity: filter.min_anonymity.or(self.min_anonymity),
+            ..filter
+        }
+    }
+
+    /// The access log opened for appending, when there is one.
+    pub fn open_access_log(&self) -> std::io::Result<Option<AccessLog>> {
+        let Some(path) = &self.access_log else {
+            return Ok(None);
+        };
+        let max_age = (self.access_log_max_age > 0).then(|| Duration::from_secs(self.access_log_max_age));
+        let log = AccessLog::open(path, self.access_log_format)?.with_rotation(
+            self.access_log_max_size * 1024 * 1024,
+            max_age,
+            self.access_log_keep,
+        );
+        Ok(Some(log))
+    }
+
+    pub fn probe_config(&self) -> ProbeConfig {
+        let mut probes = ProbeConfig {
+            quorum: self.check_quorum,
+            timeout: Duration::from_secs(self.check_timeout),
+            egress_url: self.egress_url.clone(),
+            anonymity_url: self.anonymity_url.clone(),
+            client_ip: self.client_ip,
+            ..Default::default()
+        };
+        if !self.checks.is_empty() {
+            probes.probes = self.checks.clone();
+        }
+        probes
+    }
 }
@@ -0,0 +1,453 @@
+use crate::{Config, Listener, PoolSource, ProxyFilter, RotationPolicy};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Settings of a `--config` file, TOML or YAML. Keys follow the flag names,
+/// health checks, ban detection, pools, listeners, the admin API, metrics and the access log have
+/// sections of their own.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    port: Option<u16>,
+    bind: Option<String>,
+    proxies_path: Option<String>,
+    proxies_format: Option<String>,
+    proxies_passphrase: Option<String>,
+    proxies_key: Option<String>,
+    rotate_interval: Option<i64>,
+    rotate_jitter: Option<u64>,
+    rotate_requests: Option<u64>,
+    rotate_bytes: Option<u64>,
+    rotate_failures: Option<u32>,
+    rotate_cron: Option<String>,
+    rotate_mode: Option<String>,
+    rotate_grace: Option<u64>,
+    on_exhausted: Option<String>,
+    queue_timeout: Option<u64>,
+    allow_direct: Option<bool>,
+    auth: Option<String>,
+    session_lifetime: Option<i64>,
+    session_max_lifetime: Option<u64>,
+    filter: Option<String>,
+    min_anonymity: Option<String>,
+    dedupe_egress: Option<bool>,
+    country_db: Option<String>,
+    asn_db: Option<String>,
+    #[serde(default)]
+    blocklists: Vec<String>,
+    source_refresh: Option<u64>,
+    state_path: Option<String>,
+    state_ttl: Option<u64>,
+    ready_after: Option<usize>,
+    check_concurrency: Option<usize>,
+    port_range: Option<String>,
+    port_table: Option<String>,
+    otlp_endpoint: Option<String>,
+    health: Option<HealthSection>,
+    bans: Option<BansSection>,
+    #[serde(default)]
+    pools: Vec<PoolSection>,
+    #[serde(default)]
+    listeners: Vec<ListenerSection>,
+    admin: Option<AdminSection>,
+    metrics: Option<MetricsSection>,
+    access_log: Option<AccessLogSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthSection {
+    interval: Option<u64>,
+    jitter: Option<u64>,
+    eject_after: Option<u32>,
+    readmit_after: Option<u32>,
+    max_backoff: Option<u64>,
+    #[serde(default)]
+    checks: Vec<String>,
+    quorum: Option<usize>,
+    timeout: Option<u64>,
+    egress_url: Option<String>,
+    anonymity_url: Option<String>,
+    client_ip: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BansSection {
+    threshold: Option<u32>,
+    cooldown: Option<u64>,
+    #[serde(default)]
+    statuses: Vec<u16>,
+    #[serde(default)]
+    markers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolSection {
+    name: String,
+    path: String,
+    format: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+    root: Option<String>,
+    refresh: Option<u64>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+/// A listener of the file, also the body of the admin API call creating one.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListenerSection {
+    port: u16,
+    bind: Option<String>,
+    #[serde(default)]
+    protocols: Vec<String>,
+    auth: Option<String>,
+    /// Shorthand for `pool=<name>` in the filter.
+    pool: Option<String>,
+    filter: Option<String>,
+    strategy: Option<String>,
+    rotate_interval: Option<i64>,
+    rotate_jitter: Option<u64>,
+    rotate_requests: Option<u64>,
+    rotate_bytes: Option<u64>,
+    rotate_failures: Option<u32>,
+    rotate_cron: Option<String>,
+    rotate_mode: Option<String>,
+    rotate_grace: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminSection {
+    listen: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessLogSection {
+    path: Option<String>,
+    format: Option<String>,
+    max_size: Option<u64>,
+    max_age: Option<u64>,
+    keep: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetricsSection {
+    listen: Option<String>,
+    per_proxy: Option<bool>,
+}
+
+/// Parse the value of `key`, the error names the key.
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|e| format!("{}: {}", key, e))
+}
+
+fn parse_opt<T: FromStr>(key: &str, value: Option<String>) -> Result<Option<T>, String>
+where
+    T::Err: Display,
+{
+    value.map(|v| parse(key, &v)).transpose()
+}
+
+fn parse_all<T: FromStr>(key: &str, values: &[String]) -> Result<Vec<T>, String>
+where
+    T::Err: Display,
+{
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| parse(&format!("{}[{}]", key, i), v))
+        .collect()
+}
+
+impl PoolSection {
+    fn into_source(self, key: &str) -> Result<PoolSource, String> {
+        Ok(PoolSource {
+            name: Some(self.name),
+            path: self.path,
+            tags: self.tags,
+            format: parse_opt(&format!("{}.format", key), self.format)?,
+            headers: self.headers.into_iter().collect(),
+            fields: self.fields,
+            root: self.root,
+            refresh: self.refresh.map(Duration::from_secs),
+        })
+    }
+}
+
+impl ListenerSection {
+    pub(crate) fn into_listener(self, key: &str) -> Result<Listener, String> {
+        let mut scope: ProxyFilter = parse_opt(&format!("{}.filter", key), self.filter)?.unwrap_or_default();
+        scope.pool = self.pool.or(scope.pool);
+        Ok(Listener {
+            port: self.port,
+            scope,
+            bind: parse_opt(&format!("{}.bind", key), self.bind)?,
+            protocols: parse_all(&format!("{}.protocols", key), &self.protocols)?,
+            auth: parse_opt(&format!("{}.auth", key), self.auth)?,
+            strategy: parse_opt(&format!("{}.strategy", key), self.strategy)?.unwrap_or_default(),
+            rotate_interval: self.rotate_interval,
+            rotation: RotationPolicy {
+                jitter: self.rotate_jitter,
+                requests: self.rotate_requests,
+                bytes: self.rotate_bytes,
+                failures: self.rotate_failures,
+                schedule: parse_opt(&format!("{}.rotate_cron", key), self.rotate_cron)?,
+                mode: parse_opt(&format!("{}.rotate_mode", key), self.rotate_mode)?,
+                grace: self.rotate_grace,
+            },
+        })
+    }
+}
+
+impl ConfigFile {
+    /// Read a `.toml`, `.yaml` or `.yml` file, unknown keys are errors.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+            _ => Err("Unknown config format, expected a .toml, .yaml or .yml file".to_string()),
+        }
+    }
+
+    /// Take over the settings `is_set` reports as not given on the command line
+    /// or in the environment.
+    pub fn apply(self, config: &mut Config, is_set: impl Fn(&str) -> bool) -> Result<(), String> {
+        macro_rules! merge {
+            ($field:ident, $value:expr) => {
+                if let Some(value) = $value {
+                    if !is_set(stringify!($field)) {
+                        config.$field = value;
+                    }
+                }
+            };
+        }
+        macro_rules! merge_all {
+            ($field:ident, $values:expr) => {
+                let values = $values;
+                if !values.is_empty() && !is_set(stringify!($field)) {
+                    config.$field = values;
+                }
+            };
+        }
+
+        merge!(port, self.port);
+        merge!(bind, parse_opt("bind", self.bind)?);
+        merge!(proxies_path, self.proxies_path);
+        merge!(proxies_format, parse_opt("proxies_format", self.proxies_format)?.map(Some));
+        merge!(proxies_passphrase, self.proxies_passphrase.map(Some));
+        merge!(proxies_key, self.proxies_key.map(Some));
+        merge!(rotate_interval, self.rotate_interval);
+        merge!(rotate_jitter, self.rotate_jitter.map(Some));
+        merge!(rotate_requests, self.rotate_requests.map(Some));
+        merge!(rotate_bytes, self.rotate_bytes.map(Some));
+        merge!(rotate_failures, self.rotate_failures.map(Some));
+        merge!(rotate_cron, parse_opt("rotate_cron", self.rotate_cron)?.map(Some));
+        merge!(rotate_mode, parse_opt("rotate_mode", self.rotate_mode)?.map(Some));
+        merge!(rotate_grace, self.rotate_grace.map(Some));
+        merge!(on_exhausted, parse_opt("on_exhausted", self.on_exhausted)?);
+        merge!(queue_timeout, self.queue_timeout);
+        merge!(allow_direct, self.allow_direct);
+        merge!(auth, parse_opt("auth", self.auth)?.map(Some));
+        merge!(session_lifetime, self.session_lifetime);
+        merge!(session_max_lifetime, self.session_max_lifetime);
+        merge!(filter, parse_opt("filter", self.filter)?.map(Some));
+        merge!(min_anonymity, parse_opt("min_anonymity", self.min_anonymity)?.map(Some));
+        merge!(dedupe_egress, self.dedupe_egress);
+        merge!(country_db, self.country_db.map(Some));
+        merge!(asn_db, self.asn_db.map(Some));
+        merge_all!(blocklists, self.blocklists);
+        merge!(source_refresh, self.source_refresh);
+        merge!(state_path, self.state_path.map(Some));
+        merge!(state_ttl, self.state_ttl);
+        merge!(ready_after, self.ready_after);
+        merge!(check_concurrency, self.check_concurrency);
+        merge!(port_range, parse_opt("port_range", self.port_range)?.map(Some));
+        merge!(port_table, self.port_table.map(Some));
+        merge!(otlp_endpoint, self.otlp_endpoint.map(Some));
+
+        let health = self.health.unwrap_or_default();
+        merge!(health_interval, health.interval);
+        merge!(health_jitter, health.jitter);
+        merge!(eject_after, health.eject_after);
+        merge!(readmit_after, health.readmit_after);
+        merge!(max_backoff, health.max_backoff);
+        merge_all!(checks, parse_all("health.checks", &health.checks)?);
+        merge!(check_quorum, health.quorum);
+        merge!(check_timeout, health.timeout);
+        merge!(egress_url, health.egress_url.map(Some));
+        merge!(anonymity_url, health.anonymity_url.map(Some));
+        merge!(client_ip, parse_opt("health.client_ip", health.client_ip)?.map(Some));
+
+        let bans = self.bans.unwrap_or_default();
+        merge!(ban_threshold, bans.threshold);
+        merge!(ban_cooldown, bans.cooldown);
+        merge_all!(ban_statuses, bans.statuses);
+        merge_all!(ban_markers, bans.markers);
+
+        let pools = self
+            .pools
+            .into_iter()
+            .enumerate()
+            .map(|(i, pool)| pool.into_source(&format!("pools[{}]", i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        merge_all!(pools, pools);
+        let listeners = self
+            .listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, listener)| listener.into_listener(&format!("listeners[{}]", i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        merge_all!(listeners, listeners);
+
+        let admin = self.admin.unwrap_or_default();
+        merge!(admin_listen, parse_opt("admin.listen", admin.listen)?.map(Some));
+        merge!(admin_token, admin.token.map(Some));
+
+        let metrics = self.metrics.unwrap_or_default();
+        merge!(metrics_listen, parse_opt("metrics.listen", metrics.listen)?.map(Some));
+        merge!(metrics_per_proxy, metrics.per_proxy);
+
+        let access_log = self.access_log.unwrap_or_default();
+        merge!(access_log, access_log.path.map(Some));
+        merge!(access_log_format, parse_opt("access_log.format", access_log.format)?);
+        merge!(access_log_max_size, access_log.max_size);
+        merge!(access_log_max_age, access_log.max_age);
+        merge!(access_log_keep, access_log.keep);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AccessFormat, Config, Protocol, Strategy};
+    use std::time::Duration;
+
+    fn write(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("qproxy-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_toml_file() {
+        let path = write(
+            "config.toml",
+            r#"
+port = 9000
+rotate_interval = 120
+rotate_failures = 3
+on_exhausted = "queue"
+queue_timeout = 10
+auth = "user:pass"
+
+[health]
+interval = 30
+checks = ["handshake", "connect=example.com:443"]
+
+[bans]
+cooldown = 300
+markers = ["captcha"]
+
+[[pools]]
+name = "datacenter"
+path = "dc.txt"
+tags = { type = "datacenter" }
+headers = { X-Api-Key = "s3cret" }
+
+[[listeners]]
+port = 9001
+bind = "0.0.0.0"
+protocols = ["socks5"]
+pool = "datacenter"
+strategy = "latency"
+rotate_interval = 0
+rotate_requests = 100
+rotate_cron = "0 */6 * * *"
+rotate_mode = "hard"
+
+[admin]
+listen = "127.0.0.1:9900"
+token = "changeme"
+
+[access_log]
+format = "squid"
+keep = 2
+"#,
+        );
+        let config = Config::load_from(["qproxy", "--config", path.as_str(), "--rotate-interval", "60"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.port, 9000);
+        // flags win over the file
+        assert_eq!(config.rotate_interval, 60);
+        assert_eq!(config.credentials().unwrap().user, "user");
+        assert_eq!((config.exhaustion(), config.queue_timeout), (crate::Exhaustion::Queue, 10));
+        assert_eq!(config.health_config().interval, Duration::from_secs(30));
+        assert_eq!(config.checks.len(), 2);
+        let bans = config.ban_config();
+        assert_eq!((bans.threshold, bans.cooldown), (3, Duration::from_secs(300)));
+        assert_eq!((bans.statuses, bans.markers), (vec![403, 429], vec!["captcha".to_string()]));
+        assert_eq!(config.pools[0].tags.get("type").map(String::as_str), Some("datacenter"));
+        let listener = &config.listeners[0];
+        assert_eq!(listener.bind, Some("0.0.0.0".parse().unwrap()));
+        assert_eq!(listener.protocols, vec![Protocol::Socks5]);
+        assert_eq!(listener.scope.pool.as_deref(), Some("datacenter"));
+        assert_eq!(listener.strategy, Strategy::Latency);
+        assert_eq!(listener.rotate_interval, Some(0));
+        let rotation = listener.rotation.or(&config.rotation_policy());
+        assert_eq!((rotation.requests, rotation.failures), (Some(100), Some(3)));
+        assert_eq!(rotation.schedule.unwrap().to_string(), "0 */6 * * *");
+        assert_eq!(rotation.mode, Some(crate::RotationMode::Hard));
+        assert_eq!(config.admin_listen, Some("127.0.0.1:9900".parse().unwrap()));
+        assert_eq!((config.access_log_format, config.access_log_keep), (AccessFormat::Squid, 2));
+
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("X-Api-Key"));
+        assert!(!debug.contains("changeme") && !debug.contains("s3cret"));
+    }
+
+    #[test]
+    fn test_yaml_file_and_errors() {
+        let path = write(
+            "config.yaml",
+            "listeners:\n  - port: 9001\n    filter: country=US\n  - port: 9002\n    auth: ads:secret\n",
+        );
+        let config = Config::load_from(["qproxy", "-c", path.as_str()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.listeners[0].scope.country.as_deref(), Some("US"));
+        assert_eq!(config.listeners[1].auth.as_ref().unwrap().pass, "secret");
+
+        let invalid = [
+            ("unknown.toml", "prot = 9000\n", "unknown field `prot`"),
+            ("bind.toml", "[[listeners]]\nport = 9001\nbind = \"localhost\"\n", "listeners[0].bind"),
+            ("strategy.toml", "[[listeners]]\nport = 9001\nstrategy = \"fastest\"\n", "listeners[0].strategy"),
+            ("cron.toml", "[[listeners]]\nport = 9001\nrotate_cron = \"hourly\"\n", "listeners[0].rotate_cron"),
+            ("ports.toml", "[[listeners]]\nport = 9001\n[[listeners]]\nport = 9001\n", "Port 9001"),
+            ("admin.toml", "[admin]\nlisten = \"127.0.0.1:9900\"\n", "needs a token"),
+            ("direct.toml", "on_exhausted = \"direct\"\n", "needs allow_direct"),
+        ];
+        for (name, content, expected) in invalid {
+            let path = write(name, content);
+            let error = Config::load_from(["qproxy", "--config", path.as_str()]).unwrap_err().to_string();
+            std::fs::remove_file(&path).unwrap();
+            assert!(error.contains(expected), "{}", error);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum ProxyError {
     #[error("Failed to connect to proxy: {0}")]
     ForwardProxyError(#[from] std::io::Error),
@@ -9,8 +10,20 @@ pub enum ProxyError {
     ProxiesTooSmall(u64),
     #[error("Failed to load proxies: {0}")]
     LoadProxiesError(String),
+    #[error("Failed to load IP intelligence: {0}")]
+    LoadIpIntelError(String),
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+    #[error("Failed to open state store: {0}")]
+    StateStoreError(String),
+    #[error("Proxy not found: {0}")]
+    ProxyNotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
     #[error("Error server: {0}")]
     ServerError(String),
     #[error("Server not found {0}")]
     ServerNotFound(String),
+    #[error("Connection not found: {0}")]
+    ConnectionNotFound(u64),
 }
\ No newline at end of file
@@ -1,12 +1,21 @@
 mod config;
+mod config_file;
 mod server;
 mod manager;
 mod errors;
+mod telemetry;
 
-pub use server::ProxyServer;
+pub use server::{ProxyServer, Usage};
 
-pub use server::{Proxy};
+pub use server::{
+    AccessFormat, AccessLog, AccessRecord, Anonymity, Connection, DestinationOutcome, Exhaustion, Metrics, Outcome, Probe, ProbeConfig, Protocol, Proxy, ProxyAuth, SessionParams, Target, UpstreamSelector,
+};
 
 pub use config::Config;
 
-pub use manager::ProxyManager;
\ No newline at end of file
+pub use telemetry::Telemetry;
+
+pub use manager::{
+    BanConfig, BanTracker, CheckProgress, Cooldown, CronSchedule, HealthConfig, HealthState, IpIntel, ListFormat, Listener, PoolSource, PortRange, ProxyFilter, ProxyManager, ProxyRecord,
+    RotationMode, RotationPolicy, RunHandle, Secrets, SessionRouter, StateStore, Strategy, Trigger,
+};
\ No newline at end of file
@@ -1,12 +1,15 @@
-use clap::Parser;
-use qproxy::{Config, ProxyManager};
+use qproxy::{Config, ProxyManager, Telemetry};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::try_init().unwrap_or_default();
-    let config = Config::parse();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let _telemetry = config.otlp_endpoint.as_deref().map(Telemetry::init).transpose()?;
     let manager = ProxyManager::new(&config).await;
-    manager.start().await?;
+    manager.spawn().shutdown_on_signal().wait().await?;
 
     Ok(())
 }
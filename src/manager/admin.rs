@@ -0,0 +1,597 @@
+use crate::config_file::ListenerSection;
+use crate::errors::ProxyError;
+use crate::manager::store::ProxyRecord;
+use crate::server::constant_time_eq;
+use crate::{Anonymity, PortRange, Protocol, Proxy, ProxyManager, Usage};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_BODY: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn ok(body: Value) -> Self {
+        Response::json(200, body)
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Response::json(status, json!({ "error": message.to_string() }))
+    }
+
+    fn metrics(body: String) -> Self {
+        Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+}
+
+impl From<ProxyError> for Response {
+    fn from(e: ProxyError) -> Self {
+        let status = match &e {
+            ProxyError::ProxyNotFound(_) | ProxyError::ServerNotFound(_) | ProxyError::ConnectionNotFound(_) => 404,
+            ProxyError::Conflict(_) => 409,
+            ProxyError::ConfigError(_) => 400,
+            // the proxy failed its check
+            ProxyError::ForwardProxyError(_) => 422,
+            ProxyError::ProxyNotSet | ProxyError::ProxiesTooSmall(_) => 503,
+            _ => 500,
+        };
+        Response::error(status, e)
+    }
+}
+
+/// A pool entry as the API shows it, without credentials.
+#[derive(Debug, Serialize)]
+struct ProxyView {
+    addr: String,
+    protocol: Protocol,
+    pool: Option<String>,
+    tags: BTreeMap<String, String>,
+    working: bool,
+    paused: bool,
+    ejected: bool,
+    consecutive_failures: u32,
+    latency_ms: u64,
+    country: Option<String>,
+    asn: Option<u32>,
+    egress_ip: Option<String>,
+    anonymity: Option<Anonymity>,
+    weight: u32,
+    provider: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl ProxyView {
+    fn new(manager: &ProxyManager, proxy: &Proxy) -> Self {
+        let health = manager.health_state(proxy);
+        ProxyView {
+            addr: proxy.addr(),
+            protocol: proxy.protocol,
+            pool: proxy.pool.clone(),
+            tags: proxy.tags.clone(),
+            working: proxy.is_working,
+            paused: proxy.paused,
+            ejected: health.ejected,
+            consecutive_failures: health.consecutive_failures,
+            latency_ms: proxy.latency.as_millis() as u64,
+            country: proxy.country.clone(),
+            asn: proxy.asn,
+            egress_ip: proxy.egress_ip.clone(),
+            anonymity: proxy.anonymity,
+            weight: proxy.weight(),
+            provider: proxy.provider.clone(),
+            expires_at: proxy.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ServerView {
+    addr: SocketAddr,
+    /// A listener of the port range.
+    slot: bool,
+    upstream: Option<String>,
+    uptime_secs: u64,
+    filter: String,
+    protocols: Vec<Protocol>,
+    strategy: String,
+    rotate_interval: Option<i64>,
+    usage: Usage,
+}
+
+#[derive(Debug, Serialize)]
+struct ProxyDetail {
+    entries: Vec<ProxyView>,
+    record: Option<ProxyRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewProxy {
+    /// Same syntax as a line of a text list.
+    proxy: String,
+    pool: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Ban {
+    reason: Option<String>,
+}
+
+/// Whether `header` carries `token` as a bearer token, compared in constant time.
+fn authorized(header: Option<&str>, token: &str) -> bool {
+    let Some(given) = header.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    constant_time_eq(given, token)
+}
+
+fn parse_body<T: for<'de> Deserialize<'de> + Default>(body: &[u8]) -> Result<T, Response> {
+    if body.is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| Response::error(400, e))
+}
+
+fn parse_json<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T, Response> {
+    serde_json::from_slice(body).map_err(|e| Response::error(400, e))
+}
+
+fn to_value<T: Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Request> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(invalid("Invalid request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut authorization = None;
+    let mut length = 0;
+    let mut head = line.len();
+    loop {
+        line.clear();
+        head += reader.read_line(&mut line).await?;
+        if head > MAX_HEAD {
+            return Err(invalid("Request head too large"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(invalid("Invalid header"));
+        };
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.trim().to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse().map_err(|_| invalid("Invalid content length"))?;
+        }
+    }
+    if length > MAX_BODY {
+        return Err(invalid("Request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        authorization,
+        body,
+    })
+}
+
+async fn route(manager: &ProxyManager, request: &Request) -> Result<Response, Response> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let body = request.body.as_slice();
+    let response = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["metrics"]) => Response::metrics(manager.render_metrics().await),
+        ("GET", ["proxies"]) => {
+            let proxies = manager.proxies().await;
+            let views: Vec<ProxyView> = proxies.iter().map(|p| ProxyView::new(manager, p)).collect();
+            Response::ok(to_value(views))
+        }
+        ("POST", ["proxies"]) => {
+            let new: NewProxy = parse_json(body)?;
+            let mut proxy = Proxy::from_str(&new.proxy).map_err(|e| Response::error(400, e))?;
+            proxy.pool = new.pool;
+            proxy.tags = new.tags;
+            let added = manager.add_proxy(proxy).await?;
+            Response::json(201, to_value(ProxyView::new(manager, &added)))
+        }
+        ("GET", ["proxies", addr]) => {
+            let proxies = manager.proxies().await;
+            let entries: Vec<ProxyView> = proxies
+                .iter()
+                .filter(|p| p.addr() == *addr)
+                .map(|p| ProxyView::new(manager, p))
+                .collect();
+            let record = manager.proxy_record(addr);
+            if entries.is_empty() && record.is_none() {
+                return Err(ProxyError::ProxyNotFound(addr.to_string()).into());
+            }
+            Response::ok(to_value(ProxyDetail { entries, record }))
+        }
+        ("DELETE", ["proxies", addr]) => {
+            manager.remove_proxy(addr).await?;
+            Response::ok(json!({ "removed": addr }))
+        }
+        ("POST", ["proxies", addr, "ban"]) => {
+            let ban: Ban = parse_body(body)?;
+            manager.ban(addr, ban.reason).await;
+            Response::ok(json!({ "banned": addr }))
+        }
+        ("DELETE", ["proxies", addr, "ban"]) => {
+            manager.unban(addr).await?;
+            Response::ok(json!({ "unbanned": addr }))
+        }
+        ("POST", ["proxies", addr, "pause"]) => {
+            manager.pause_proxy(addr, true).await?;
+            Response::ok(json!({ "paused": addr }))
+        }
+        ("DELETE", ["proxies", addr, "pause"]) => {
+            manager.pause_proxy(addr, false).await?;
+            Response::ok(json!({ "resumed": addr }))
+        }
+        ("GET", ["servers"]) => {
+            let mut views = Vec::new();
+            let servers = manager.servers().await.into_iter().map(|s| (s, false));
+            for (server, slot) in servers.chain(manager.slots().await.into_iter().map(|s| (s, true))) {
+                let listener = manager.listener_of(server.get_addr()).await.unwrap_or_default();
+                views.push(ServerView {
+                    addr: server.get_addr(),
+                    slot,
+                    upstream: server.get_proxy().map(|p| p.addr()),
+                    uptime_secs: server.get_duration().as_secs(),
+                    filter: listener.scope.to_string(),
+                    protocols: listener.protocols,
+                    strategy: listener.strategy.to_string(),
+                    rotate_interval: listener.rotate_interval,
+                    usage: server.get_usage(),
+                });
+            }
+            Response::ok(to_value(views))
+        }
+        ("POST", ["servers"]) => {
+            let section: ListenerSection = parse_json(body)?;
+            let listener = section.into_listener("listener").map_err(|e| Response::error(400, e))?;
+            let addr = manager.open_listener(&listener).await?;
+            Response::json(201, json!({ "addr": addr }))
+        }
+        ("DELETE", ["servers", range]) if range.contains('-') => {
+            let range = PortRange::from_str(range).map_err(|e| Response::error(400, e))?;
+            Response::ok(json!({ "stopped": manager.stop_slots(range).await }))
+        }
+        ("DELETE", ["servers", port]) => {
+            let port = port.parse().map_err(|_| Response::error(400, "Invalid port"))?;
+            manager.stop_listener(port).await?;
+            Response::ok(json!({ "stopped": port }))
+        }
+        ("POST", ["servers", port, "rotate"]) => {
+            let port = port.parse().map_err(|_| Response::error(400, "Invalid port"))?;
+            let proxy = manager.rotate_listener(port).await?;
+            Response::ok(json!({ "upstream": proxy.addr() }))
+        }
+        ("GET", ["connections"]) => Response::ok(to_value(manager.connections().await)),
+        ("GET", ["connections", "client", ip]) => {
+            let ip: IpAddr = ip.parse().map_err(|_| Response::error(400, "Invalid IP"))?;
+            let mut connections = manager.connections().await;
+            connections.retain(|c| c.client.is_some_and(|c| c.ip() == ip));
+            Response::ok(to_value(connections))
+        }
+        ("GET", ["connections", "upstream", upstream]) => {
+            let mut connections = manager.connections().await;
+            connections.retain(|c| c.upstream == *upstream);
+            Response::ok(to_value(connections))
+        }
+        ("DELETE", ["connections", "client", ip]) => {
+            let ip = ip.parse().map_err(|_| Response::error(400, "Invalid IP"))?;
+            Response::ok(json!({ "closed": manager.close_client(ip).await }))
+        }
+        ("DELETE", ["connections", "upstream", upstream]) => {
+            Response::ok(json!({ "closed": manager.close_upstream(upstream).await }))
+        }
+        ("DELETE", ["connections", id]) => {
+            let id = id.parse().map_err(|_| Response::error(400, "Invalid connection id"))?;
+            manager.close_connection(id).await?;
+            Response::ok(json!({ "closed": 1 }))
+        }
+        ("GET", ["cooldowns"]) => Response::ok(to_value(manager.cooldowns())),
+        ("DELETE", ["cooldowns", addr]) => Response::ok(json!({ "cleared": manager.clear_cooldowns(addr) })),
+        ("POST", ["health-check"]) => {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.check_health().await });
+            Response::json(202, json!({ "started": true }))
+        }
+        (_, ["proxies" | "servers" | "connections" | "cooldowns" | "health-check" | "metrics", ..]) => Response::error(405, "Method not allowed"),
+        _ => Response::error(404, "Not found"),
+    };
+    Ok(response)
+}
+
+/// Answer one request, without a `token` only `GET /metrics` is served.
+async fn handle(manager: &ProxyManager, token: Option<&str>, mut stream: TcpStream) -> std::io::Result<()> {
+    let response = match time::timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Err(_) => Response::error(408, "Request timeout"),
+        Ok(Err(e)) => Response::error(400, e),
+        Ok(Ok(request)) if token.is_none() => match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => Response::metrics(manager.render_metrics().await),
+            _ => Response::error(404, "Not found"),
+        },
+        Ok(Ok(request)) if !authorized(request.authorization.as_deref(), token.unwrap_or_default()) => {
+            Response::error(401, "Missing or invalid token")
+        }
+        Ok(Ok(request)) => {
+            info!("Admin {} {}", request.method, request.path);
+            route(manager, &request).await.unwrap_or_else(|e| e)
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        422 => "Unprocessable Entity",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serve the admin API on `listener`, every request needs `token` as a bearer token.
+/// Without a token the listener only serves the metrics.
+pub async fn serve(manager: ProxyManager, listener: TcpListener, token: Option<String>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        let manager = manager.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&manager, token.as_deref(), stream).await {
+                error!("Failed to handle admin request: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::{connect_async, free_port, live_upstream, spawn_upstream};
+    use crate::Config;
+    use clap::Parser;
+
+    async fn send(addr: SocketAddr, method: &str, path: &str, token: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        (status, response.split_once("\r\n\r\n").unwrap().1.to_string())
+    }
+
+    async fn call(addr: SocketAddr, method: &str, path: &str, token: &str, body: &str) -> (u16, Value) {
+        let (status, body) = send(addr, method, path, token, body).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() {
+        let config = Config::parse_from(["qproxy", "--check", "handshake", "--state-path", ""]);
+        let proxies: Vec<Proxy> = (0..2)
+            .map(|_| live_upstream("ok"))
+            .collect();
+        let manager = ProxyManager::from_config(proxies.clone(), &config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(manager.clone(), listener, Some("secret".to_string())));
+
+        let (status, _) = call(addr, "GET", "/proxies", "wrong", "").await;
+        assert_eq!(status, 401);
+        let (status, body) = call(addr, "GET", "/proxies", "secret", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert!(!body.to_string().contains("pass"));
+
+        let first = proxies[0].addr();
+        let (status, body) = call(addr, "POST", &format!("/proxies/{}/pause", first), "secret", "").await;
+        assert_eq!((status, body["paused"].as_str()), (200, Some(first.as_str())));
+        let (_, body) = call(addr, "GET", &format!("/proxies/{}", first), "secret", "").await;
+        assert_eq!(body["entries"][0]["paused"], true);
+
+        let added = spawn_upstream("ok");
+        let new = json!({ "proxy": added.expose(), "pool": "api" }).to_string();
+        let (status, body) = call(addr, "POST", "/proxies", "secret", &new).await;
+        assert_eq!((status, body["pool"].as_str()), (201, Some("api")));
+        let (status, _) = call(addr, "POST", "/proxies", "secret", &new).await;
+        assert_eq!(status, 409);
+
+        let port = free_port();
+        let server = json!({ "port": port, "pool": "api" }).to_string();
+        let (status, _) = call(addr, "POST", "/servers", "secret", &server).await;
+        assert_eq!(status, 201);
+        let (_, body) = call(addr, "GET", "/servers", "secret", "").await;
+        assert_eq!(body[0]["upstream"].as_str(), Some(added.addr().as_str()));
+        assert_eq!(body[0]["filter"].as_str(), Some("pool=api"));
+        // the api pool has no other proxy to rotate to
+        let (status, _) = call(addr, "POST", &format!("/servers/{}/rotate", port), "secret", "").await;
+        assert_eq!(status, 503);
+        let (status, _) = call(addr, "DELETE", &format!("/servers/{}", port), "secret", "").await;
+        assert_eq!(status, 200);
+
+        let (status, _) = call(addr, "DELETE", "/proxies/10.0.0.1:1080", "secret", "").await;
+        assert_eq!(status, 404);
+        let (status, _) = call(addr, "PUT", "/proxies", "secret", "").await;
+        assert_eq!(status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_port_range_servers() {
+        let start = free_port();
+        let range = format!("{}-{}", start, start + 1);
+        let config = Config::parse_from(["qproxy", "--port-range", range.as_str()]);
+        let proxies: Vec<Proxy> = (0..2)
+            .map(|_| live_upstream("ok"))
+            .collect();
+        let manager = ProxyManager::from_config(proxies, &config);
+        manager.sync_slots().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(manager.clone(), listener, Some("secret".to_string())));
+
+        let (_, body) = call(addr, "GET", "/servers", "secret", "").await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["slot"], true);
+        let (status, _) = call(addr, "DELETE", &format!("/servers/{}", start), "secret", "").await;
+        assert_eq!(status, 200);
+        let (_, body) = call(addr, "DELETE", &format!("/servers/{}", range), "secret", "").await;
+        assert_eq!(body["stopped"].as_u64(), Some(1));
+        // stopped slots aren't reopened
+        manager.sync_slots().await;
+        let (_, body) = call(addr, "GET", "/servers", "secret", "").await;
+        assert!(body.as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let config = Config::parse_from(["qproxy", "--check", "handshake", "--state-path", ""]);
+        let upstream = live_upstream("ok");
+        let manager = ProxyManager::from_config(vec![upstream.clone()], &config);
+        let port = free_port();
+        let listener = crate::Listener {
+            port,
+            ..Default::default()
+        };
+        manager.create_listener(upstream, &listener).await.unwrap();
+        // the listener binds on its own thread
+        let mut client = connect_async(port).await;
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        drop(client);
+
+        let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = public.local_addr().unwrap();
+        tokio::spawn(serve(manager.clone(), public, None));
+        let (status, body) = send(addr, "GET", "/metrics", "", "").await;
+        assert_eq!(status, 200);
+        assert!(body.contains(&format!("qproxy_connections_accepted_total{{listener=\"{}\"}} 1", port)));
+        assert!(body.contains("qproxy_pool_proxies{state=\"healthy\"} 1"));
+        let (status, _) = send(addr, "GET", "/proxies", "secret", "").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_connections() {
+        let config = Config::parse_from(["qproxy", "--check", "handshake", "--state-path", ""]);
+        let upstream = live_upstream("ok");
+        let manager = ProxyManager::from_config(vec![upstream.clone()], &config);
+        let port = free_port();
+        manager.create_server(upstream.clone(), port).await.unwrap();
+        let mut client = connect_async(port).await;
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        // the relay stays open until the client closes
+        let mut response = Vec::new();
+        while !response.ends_with(b"ok") {
+            response.push(client.read_u8().await.unwrap());
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(manager.clone(), listener, Some("secret".to_string())));
+        let (status, body) = call(addr, "GET", "/connections", "secret", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["upstream"].as_str(), Some(upstream.addr().as_str()));
+        assert_eq!(body[0]["target"].as_str(), Some("example.com:80"));
+        assert!(body[0]["received"].as_u64().unwrap() > 0);
+        let id = body[0]["id"].as_u64().unwrap();
+
+        let path = format!("/connections/upstream/{}", upstream.addr());
+        let (_, body) = call(addr, "GET", &path, "secret", "").await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let (status, body) = call(addr, "DELETE", "/connections/client/127.0.0.1", "secret", "").await;
+        assert_eq!((status, body["closed"].as_u64()), (200, Some(1)));
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap_or(0), 0);
+        time::sleep(Duration::from_millis(100)).await;
+        let (status, _) = call(addr, "DELETE", &format!("/connections/{}", id), "secret", "").await;
+        assert_eq!(status, 404);
+        let (status, _) = call(addr, "POST", "/connections", "secret", "").await;
+        assert_eq!(status, 405);
+    }
+}
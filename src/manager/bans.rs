@@ -0,0 +1,180 @@
+use crate::DestinationOutcome;
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When a proxy looks banned by a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanConfig {
+    /// Banned looking outcomes in a row before a cooldown, zero disables it.
+    pub threshold: u32,
+    pub cooldown: Duration,
+    /// Statuses of forwarded HTTP responses that look like a ban.
+    pub statuses: Vec<u16>,
+    /// Text in the start of a forwarded HTTP response that looks like a ban.
+    pub markers: Vec<String>,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            threshold: 3,
+            cooldown: Duration::from_secs(600),
+            statuses: vec![403, 429],
+            markers: Vec::new(),
+        }
+    }
+}
+
+impl BanConfig {
+    pub fn looks_banned(&self, outcome: &DestinationOutcome) -> bool {
+        match outcome {
+            DestinationOutcome::Ok => false,
+            DestinationOutcome::Refused | DestinationOutcome::Reset => true,
+            DestinationOutcome::Response(head) => {
+                let text = String::from_utf8_lossy(head);
+                let status: Option<u16> = text
+                    .strip_prefix("HTTP/")
+                    .and_then(|line| line.split_whitespace().nth(1))
+                    .and_then(|code| code.parse().ok());
+                status.is_some_and(|s| self.statuses.contains(&s)) || self.markers.iter().any(|m| text.contains(m.as_str()))
+            }
+        }
+    }
+}
+
+/// A proxy cooling down for a destination host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Cooldown {
+    pub proxy: String,
+    pub host: String,
+    pub remaining_secs: u64,
+}
+
+#[derive(Debug, Default)]
+struct Strikes {
+    count: u32,
+    until: Option<Instant>,
+}
+
+/// Outcomes per proxy and destination host. A proxy that looks banned by a
+/// host is skipped for that host only, until its cooldown ends.
+#[derive(Debug, Default)]
+pub struct BanTracker {
+    config: BanConfig,
+    strikes: Mutex<HashMap<(String, String), Strikes>>,
+}
+
+fn key(proxy: &str, host: &str) -> (String, String) {
+    (proxy.to_string(), host.to_ascii_lowercase())
+}
+
+impl BanTracker {
+    pub fn new(config: BanConfig) -> Self {
+        BanTracker {
+            config,
+            strikes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record what `host` made of a connection through `proxy`, returns
+    /// whether it started a cooldown.
+    pub fn record(&self, proxy: &str, host: &str, outcome: &DestinationOutcome) -> bool {
+        if self.config.threshold == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut strikes = self.strikes.lock().unwrap();
+        let entry = strikes.entry(key(proxy, host)).or_default();
+        if !self.config.looks_banned(outcome) {
+            entry.count = 0;
+            return false;
+        }
+        entry.count += 1;
+        if entry.count < self.config.threshold || entry.until.is_some_and(|until| until > now) {
+            return false;
+        }
+        entry.count = 0;
+        entry.until = Some(now + self.config.cooldown);
+        warn!(
+            "Proxy {} looks banned by {}, cooling down for {}s",
+            proxy,
+            host,
+            self.config.cooldown.as_secs()
+        );
+        true
+    }
+
+    pub fn is_cooling(&self, proxy: &str, host: &str) -> bool {
+        let strikes = self.strikes.lock().unwrap();
+        let until = strikes.get(&key(proxy, host)).and_then(|s| s.until);
+        until.is_some_and(|until| until > Instant::now())
+    }
+
+    /// Cooldowns still running, expired entries are dropped.
+    pub fn cooldowns(&self) -> Vec<Cooldown> {
+        let now = Instant::now();
+        let mut strikes = self.strikes.lock().unwrap();
+        strikes.retain(|_, s| s.count > 0 || s.until.is_some_and(|until| until > now));
+        let mut cooldowns: Vec<Cooldown> = strikes
+            .iter()
+            .filter_map(|((proxy, host), s)| {
+                let until = s.until.filter(|until| *until > now)?;
+                Some(Cooldown {
+                    proxy: proxy.clone(),
+                    host: host.clone(),
+                    remaining_secs: (until - now).as_secs(),
+                })
+            })
+            .collect();
+        cooldowns.sort_by(|a, b| (&a.proxy, &a.host).cmp(&(&b.proxy, &b.host)));
+        cooldowns
+    }
+
+    /// End the cooldowns of `proxy`, returns how many.
+    pub fn clear(&self, proxy: &str) -> usize {
+        let mut strikes = self.strikes.lock().unwrap();
+        let before = strikes.len();
+        strikes.retain(|(p, _), _| p != proxy);
+        before - strikes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cooldown_per_destination() {
+        let tracker = BanTracker::new(BanConfig {
+            threshold: 2,
+            markers: vec!["captcha".to_string()],
+            ..Default::default()
+        });
+        let forbidden = DestinationOutcome::Response(b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec());
+        assert!(!tracker.record("1.2.3.4:1080", "shop.example", &forbidden));
+        // a success in between starts over
+        assert!(!tracker.record("1.2.3.4:1080", "shop.example", &DestinationOutcome::Ok));
+        assert!(!tracker.record("1.2.3.4:1080", "shop.example", &DestinationOutcome::Reset));
+        assert!(tracker.record("1.2.3.4:1080", "Shop.Example", &DestinationOutcome::Refused));
+        assert!(tracker.is_cooling("1.2.3.4:1080", "shop.example"));
+        assert!(!tracker.is_cooling("1.2.3.4:1080", "news.example"));
+        assert!(!tracker.is_cooling("5.6.7.8:1080", "shop.example"));
+        assert_eq!(tracker.cooldowns()[0].host, "shop.example");
+
+        let config = BanConfig {
+            markers: vec!["captcha".to_string()],
+            ..Default::default()
+        };
+        assert!(config.looks_banned(&DestinationOutcome::Response(
+            b"HTTP/1.1 200 OK\r\n\r\n<div class=captcha>".to_vec()
+        )));
+        assert!(!config.looks_banned(&DestinationOutcome::Response(b"HTTP/1.1 200 OK\r\n\r\n".to_vec())));
+        assert!(config.looks_banned(&DestinationOutcome::Response(b"HTTP/1.0 429 Too Many\r\n".to_vec())));
+
+        assert_eq!(tracker.clear("1.2.3.4:1080"), 1);
+        assert!(tracker.cooldowns().is_empty());
+    }
+}
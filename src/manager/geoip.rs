@@ -0,0 +1,199 @@
+use crate::errors::ProxyError;
+use crate::Proxy;
+use ipnet::IpNet;
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Offline IP intelligence: MaxMind-format country and ASN databases plus
+/// CIDR blocklists, used to tag proxies by their exit address.
+#[derive(Default)]
+pub struct IpIntel {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    blocklist: Vec<IpNet>,
+}
+
+impl std::fmt::Debug for IpIntel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IpIntel")
+            .field("country", &self.country.is_some())
+            .field("asn", &self.asn.is_some())
+            .field("blocklist", &self.blocklist.len())
+            .finish()
+    }
+}
+
+fn open_db(path: &str) -> Result<Reader<Vec<u8>>, ProxyError> {
+    Reader::open_readfile(path).map_err(|e| ProxyError::LoadIpIntelError(format!("{}: {}", path, e)))
+}
+
+/// Parse a blocklist: one CIDR or address per line, `#` starts a comment.
+pub fn parse_blocklist(content: &str) -> Result<Vec<IpNet>, String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            IpNet::from_str(line)
+                .or_else(|_| IpAddr::from_str(line).map(IpNet::from))
+                .map_err(|_| format!("Invalid blocklist entry: {}", line))
+        })
+        .collect()
+}
+
+impl IpIntel {
+    pub fn open(
+        country_db: Option<&str>,
+        asn_db: Option<&str>,
+        blocklists: &[String],
+    ) -> Result<Self, ProxyError> {
+        let mut blocklist = Vec::new();
+        for path in blocklists {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| ProxyError::LoadIpIntelError(format!("{}: {}", path, e)))?;
+            let nets = parse_blocklist(&content)
+                .map_err(|e| ProxyError::LoadIpIntelError(format!("{}: {}", path, e)))?;
+            blocklist.extend(nets);
+        }
+        Ok(IpIntel {
+            country: country_db.map(open_db).transpose()?,
+            asn: asn_db.map(open_db).transpose()?,
+            blocklist,
+        })
+    }
+
+    /// Tag `proxy` with the country and ASN of its exit address (the egress IP
+    /// when known, the proxy address otherwise) and flag it when the proxy or
+    /// its exit is blocklisted.
+    pub fn enrich(&self, proxy: &mut Proxy) {
+        let own = IpAddr::from_str(&proxy.ip).ok();
+        let egress = proxy.egress_ip.as_ref().and_then(|ip| IpAddr::from_str(ip).ok());
+        let Some(exit) = egress.or(own) else {
+            return;
+        };
+
+        if let Some(reader) = &self.country {
+            let code = reader
+                .lookup::<geoip2::Country>(exit)
+                .ok()
+                .and_then(|c| c.country)
+                .and_then(|c| c.iso_code);
+            if let Some(code) = code {
+                proxy.country = Some(code.to_ascii_uppercase());
+            }
+        }
+        if let Some(reader) = &self.asn {
+            if let Ok(asn) = reader.lookup::<geoip2::Asn>(exit) {
+                proxy.asn = asn.autonomous_system_number.or(proxy.asn);
+                if let Some(org) = asn.autonomous_system_organization {
+                    proxy.asn_org = Some(org.to_string());
+                }
+            }
+        }
+        proxy.blocklisted = [own, egress]
+            .iter()
+            .flatten()
+            .any(|ip| self.blocklist.iter().any(|net| net.contains(ip)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal MaxMind DB writer: one IPv4 /24 network mapped to `record`.
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = match s.len() {
+            len @ 0..=28 => vec![(2 << 5) | len as u8],
+            len => vec![(2 << 5) | 29, (len - 29) as u8],
+        };
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn uint(kind: u8, value: u64) -> Vec<u8> {
+        let bytes: Vec<u8> = value.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+        let mut out = match kind {
+            5 | 6 => vec![(kind << 5) | bytes.len() as u8],
+            _ => vec![bytes.len() as u8, kind - 7],
+        };
+        out.extend(bytes);
+        out
+    }
+
+    fn map(entries: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        let mut out = vec![(7 << 5) | entries.len() as u8];
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend(value);
+        }
+        out
+    }
+
+    fn mmdb(network: [u8; 3], database_type: &str, record: Vec<u8>) -> Vec<u8> {
+        let node_count: u32 = 24;
+        let no_data = node_count;
+        let data = node_count + 16;
+        let mut out = Vec::new();
+        for i in 0..24 {
+            let bit = (network[i / 8] >> (7 - i % 8)) & 1;
+            let next = if i == 23 { data } else { i as u32 + 1 };
+            let (left, right) = if bit == 0 { (next, no_data) } else { (no_data, next) };
+            out.extend_from_slice(&left.to_be_bytes()[1..]);
+            out.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        out.extend([0u8; 16]);
+        out.extend(record);
+        out.extend(b"\xAB\xCD\xEFMaxMind.com");
+        out.extend(map(vec![
+            ("node_count", uint(6, node_count as u64)),
+            ("record_size", uint(5, 24)),
+            ("ip_version", uint(5, 4)),
+            ("database_type", string(database_type)),
+            ("languages", vec![0, 4]),
+            ("binary_format_major_version", uint(5, 2)),
+            ("binary_format_minor_version", uint(5, 0)),
+            ("build_epoch", uint(9, 1)),
+            ("description", map(vec![])),
+        ]));
+        out
+    }
+
+    #[test]
+    fn test_enrich() {
+        let country = mmdb(
+            [203, 0, 113],
+            "GeoLite2-Country",
+            map(vec![("country", map(vec![("iso_code", string("us"))]))]),
+        );
+        let asn = mmdb(
+            [203, 0, 113],
+            "GeoLite2-ASN",
+            map(vec![
+                ("autonomous_system_number", uint(6, 16509)),
+                ("autonomous_system_organization", string("AMAZON-02")),
+            ]),
+        );
+        let intel = IpIntel {
+            country: Some(Reader::from_source(country).unwrap()),
+            asn: Some(Reader::from_source(asn).unwrap()),
+            blocklist: parse_blocklist("# bad exits\n203.0.113.0/28\n198.51.100.7 # single\n").unwrap(),
+        };
+
+        let mut proxy = Proxy::from_str("10.0.0.1:1080").unwrap();
+        proxy.egress_ip = Some("203.0.113.9".to_string());
+        intel.enrich(&mut proxy);
+        assert_eq!(proxy.country.as_deref(), Some("US"));
+        assert_eq!(proxy.asn, Some(16509));
+        assert_eq!(proxy.asn_org.as_deref(), Some("AMAZON-02"));
+        assert!(proxy.blocklisted);
+
+        let mut proxy = Proxy::from_str("198.51.100.8:1080").unwrap();
+        intel.enrich(&mut proxy);
+        assert_eq!(proxy.country, None);
+        assert!(!proxy.blocklisted);
+
+        assert!(parse_blocklist("not-an-ip").is_err());
+    }
+}
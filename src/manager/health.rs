@@ -0,0 +1,178 @@
+use crate::Proxy;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Settings for the background health checker and the in-band circuit breaker.
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Time between two health check rounds, zero disables the checker.
+    pub interval: Duration,
+    /// Random delay added to every round.
+    pub jitter: Duration,
+    /// Consecutive failures (checks or in-band) before a proxy is ejected.
+    pub eject_after: u32,
+    /// Consecutive successful checks before an ejected proxy is re-admitted.
+    pub readmit_after: u32,
+    /// Upper bound of the retry backoff of ejected proxies.
+    pub max_backoff: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval: Duration::from_secs(60),
+            jitter: Duration::from_secs(10),
+            eject_after: 3,
+            readmit_after: 2,
+            max_backoff: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl HealthConfig {
+    /// Interval plus a random share of the jitter.
+    pub fn next_delay(&self) -> Duration {
+        let jitter = self.jitter.as_millis() as u64;
+        let extra = if jitter == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(0..=jitter)
+        };
+        self.interval + Duration::from_millis(extra)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthState {
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub ejected: bool,
+    /// Failed retries since the ejection, drives the backoff.
+    pub retries: u32,
+    pub next_check: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Ejected,
+    Readmitted,
+}
+
+impl HealthState {
+    fn backoff(&self, config: &HealthConfig) -> Duration {
+        let factor = 2u32.saturating_pow(self.retries.min(16));
+        config.interval.saturating_mul(factor).min(config.max_backoff)
+    }
+
+    /// Whether the proxy should be probed in the round starting at `now`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        !self.ejected || self.next_check.is_none_or(|at| at <= now)
+    }
+
+    pub fn record(&mut self, ok: bool, config: &HealthConfig, now: Instant) -> Option<Transition> {
+        if ok {
+            self.consecutive_failures = 0;
+            self.consecutive_successes += 1;
+            if self.ejected && self.consecutive_successes >= config.readmit_after {
+                self.ejected = false;
+                self.retries = 0;
+                self.next_check = None;
+                return Some(Transition::Readmitted);
+            }
+            return None;
+        }
+
+        self.consecutive_successes = 0;
+        self.consecutive_failures += 1;
+        if self.ejected {
+            self.retries += 1;
+            self.next_check = Some(now + self.backoff(config));
+            return None;
+        }
+        if self.consecutive_failures >= config.eject_after {
+            self.ejected = true;
+            self.retries = 0;
+            self.next_check = Some(now + self.backoff(config));
+            return Some(Transition::Ejected);
+        }
+        None
+    }
+}
+
+/// Health state of every known proxy, keyed by `ip:port`.
+#[derive(Debug, Default)]
+pub struct HealthTracker {
+    config: HealthConfig,
+    states: Mutex<HashMap<String, HealthState>>,
+}
+
+impl HealthTracker {
+    pub fn new(config: HealthConfig) -> Self {
+        HealthTracker {
+            config,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &HealthConfig {
+        &self.config
+    }
+
+    pub fn state(&self, proxy: &Proxy) -> HealthState {
+        let states = self.states.lock().unwrap();
+        states.get(&proxy.addr()).cloned().unwrap_or_default()
+    }
+
+    pub fn is_due(&self, proxy: &Proxy, now: Instant) -> bool {
+        self.state(proxy).is_due(now)
+    }
+
+    /// Record the outcome of a check or an in-band connection attempt.
+    pub fn record(&self, proxy: &Proxy, ok: bool) -> Option<Transition> {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(proxy.addr()).or_default();
+        state.record(ok, &self.config, Instant::now())
+    }
+}
+
+/// Reflect a transition on the matching entry of the pool.
+pub fn apply_transition(proxies: &mut [Proxy], proxy: &Proxy, transition: Transition) {
+    for p in proxies.iter_mut().filter(|p| p.addr() == proxy.addr()) {
+        p.is_working = transition == Transition::Readmitted;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eject_backoff_and_readmit() {
+        let config = HealthConfig {
+            interval: Duration::from_secs(10),
+            jitter: Duration::ZERO,
+            eject_after: 3,
+            readmit_after: 2,
+            max_backoff: Duration::from_secs(30),
+        };
+        let now = Instant::now();
+        let mut state = HealthState::default();
+        assert_eq!(state.record(false, &config, now), None);
+        assert_eq!(state.record(false, &config, now), None);
+        assert_eq!(state.record(false, &config, now), Some(Transition::Ejected));
+        assert!(!state.is_due(now));
+        assert!(state.is_due(now + Duration::from_secs(10)));
+
+        // failed retries back off exponentially up to the cap
+        state.record(false, &config, now);
+        assert_eq!(state.next_check, Some(now + Duration::from_secs(20)));
+        state.record(false, &config, now);
+        assert_eq!(state.next_check, Some(now + Duration::from_secs(30)));
+
+        assert_eq!(state.record(true, &config, now), None);
+        assert_eq!(state.record(true, &config, now), Some(Transition::Readmitted));
+        assert!(state.is_due(now));
+    }
+}
@@ -1,160 +1,592 @@
-#![allow(unused)]
 use crate::errors::ProxyError;
-use crate::{Config, Proxy, ProxyServer};
-use log::{error, info, warn};
-use rayon::iter::{ParallelBridge, ParallelIterator};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::process::Command;
-use std::str::FromStr;
-use std::sync::atomic::AtomicI16;
-use std::sync::mpsc::channel;
+use crate::manager::admin;
+use crate::manager::bans::{BanTracker, Cooldown};
+use crate::manager::geoip::IpIntel;
+use crate::manager::health::{apply_transition, HealthState, HealthTracker, Transition};
+use crate::manager::pool::{Listener, PoolSource, PortRange};
+use crate::manager::reload;
+use crate::manager::rotation::RotationPolicy;
+use crate::manager::source::Fetcher;
+use crate::manager::store::{ProxyRecord, StateStore};
+use crate::manager::supervisor::RunHandle;
+use crate::manager::selector::{rotation_key, ProxyFilter, SessionRouter, Strategy};
+use crate::{AccessLog, Config, Connection, Exhaustion, Metrics, ProbeConfig, Proxy, ProxyAuth, ProxyServer};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicUsize};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex, Notify, Semaphore};
+use tokio::task::JoinHandle;
 use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::info_span;
 
-#[derive(Debug)]
+/// Progress of the checks of the proxies loaded on startup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckProgress {
+    /// Proxies to check, the ones trusted from the state store aren't counted.
+    pub total: usize,
+    pub checked: usize,
+    /// Live proxies, trusted ones included.
+    pub live: usize,
+}
+
+impl CheckProgress {
+    pub fn is_done(&self) -> bool {
+        self.checked >= self.total
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ProxyManager {
-    proxies: Arc<Mutex<Vec<Proxy>>>,
+    proxies: Arc<std::sync::Mutex<Vec<Proxy>>>,
     servers: Arc<Mutex<Vec<ProxyServer>>>,
-    port_seq: AtomicI16,
+    /// Accept loop of each server and slot.
+    listener_threads: Arc<Mutex<HashMap<SocketAddr, thread::JoinHandle<()>>>>,
+    port_seq: Arc<AtomicU16>,
     rotate_interval: i64, // in seconds
+    rotation: RotationPolicy,
+    /// Jittered interval of each server on its current proxy.
+    intervals: Arc<Mutex<HashMap<SocketAddr, i64>>>,
+    /// Picks of each listener port, for the strategies that count them.
+    cursors: Arc<std::sync::Mutex<HashMap<u16, Arc<AtomicUsize>>>>,
+    /// Wakes the run loop when a server's usage may have fired a trigger.
+    usage_changed: Arc<Notify>,
+    /// What listeners without a proxy do with new connections.
+    exhaustion: Exhaustion,
+    queue_timeout: Duration,
+    credentials: Option<ProxyAuth>,
+    router: Arc<SessionRouter>,
+    health: Arc<HealthTracker>,
+    bans: Arc<BanTracker>,
+    health_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Gives the listeners their proxies once enough of them are live.
+    ready_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    probes: Arc<ProbeConfig>,
+    dedupe_egress: bool,
+    filter: ProxyFilter,
+    intel: Arc<IpIntel>,
+    listeners: Vec<Listener>,
+    bind: IpAddr,
+    /// Settings of the listener behind each server.
+    bound: Arc<Mutex<HashMap<SocketAddr, Listener>>>,
+    port_range: Option<PortRange>,
+    port_table: Option<String>,
+    slots: Arc<Mutex<BTreeMap<u16, ProxyServer>>>,
+    /// Ports of the range stopped through the admin API, not reopened.
+    stopped_slots: Arc<Mutex<BTreeSet<u16>>>,
+    assignments: Arc<Mutex<HashMap<String, u16>>>,
+    sources: Vec<PoolSource>,
+    reload_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    admin_listen: Option<SocketAddr>,
+    admin_token: Option<String>,
+    admin_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    metrics: Arc<Metrics>,
+    metrics_listen: Option<SocketAddr>,
+    metrics_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    access_log: Option<Arc<AccessLog>>,
+    fetcher: Fetcher,
+    store: Arc<StateStore>,
+    ready_after: usize,
+    check_concurrency: usize,
+    progress: Arc<watch::Sender<CheckProgress>>,
+}
+
+impl Default for ProxyManager {
+    fn default() -> Self {
+        let config = Config {
+            rotate_interval: 0,
+            ..Default::default()
+        };
+        ProxyManager::from_config(Vec::new(), &config)
+    }
 }
 
 impl ProxyManager {
     pub async fn new(config: &Config) -> Self {
-        let proxies = ProxyManager::load_proxies(config.proxies_path.to_string())
+        let fetcher = Fetcher::default().with_secrets(config.secrets());
+        let store = match config.state_path.as_deref().unwrap_or_default() {
+            "" => StateStore::default(),
+            path => StateStore::open(path, Duration::from_secs(config.state_ttl)).unwrap_or_else(|e| {
+                error!("{}", e);
+                StateStore::default()
+            }),
+        };
+        let listed = ProxyManager::read_pools(&config.pool_sources(), &fetcher, &store)
             .await
-            .unwrap_or_default();
-        ProxyManager {
-            proxies: Arc::new(Mutex::new(proxies)),
-            servers: Arc::new(Mutex::new(Vec::new())),
-            port_seq: AtomicI16::new(config.port),
-            rotate_interval: config.rotate_interval,
-        }
+            .unwrap_or_else(|e| {
+                error!("{}", e);
+                Vec::new()
+            });
+        let (trusted, unchecked) = ProxyManager::trust_fresh(listed, &store);
+        let manager = ProxyManager {
+            fetcher,
+            store: Arc::new(store),
+            ..ProxyManager::from_config(trusted, config)
+        };
+        manager.spawn_initial_checks(unchecked).await;
+        manager
     }
 
-    pub fn default() -> Self {
+    pub(crate) fn from_config(mut proxies: Vec<Proxy>, config: &Config) -> Self {
+        let intel = IpIntel::open(
+            config.country_db.as_deref(),
+            config.asn_db.as_deref(),
+            &config.blocklists,
+        )
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            IpIntel::default()
+        });
+        proxies.iter_mut().for_each(|p| intel.enrich(p));
+        let access_log = config.open_access_log().unwrap_or_else(|e| {
+            error!("Failed to open access log: {}", e);
+            None
+        });
+        let progress = CheckProgress {
+            live: proxies.len(),
+            ..Default::default()
+        };
+        let proxies = Arc::new(std::sync::Mutex::new(proxies));
+        let health = Arc::new(HealthTracker::new(config.health_config()));
+        let bans = Arc::new(BanTracker::new(config.ban_config()));
+        let session_lifetime = Duration::from_secs(config.session_lifetime as u64);
         ProxyManager {
-            proxies: Arc::new(Mutex::new(Vec::new())),
+            router: Arc::new(
+                SessionRouter::new(proxies.clone(), health.clone(), session_lifetime)
+                    .with_egress_dedupe(config.dedupe_egress)
+                    .with_filter(config.proxy_filter())
+                    .with_max_lifetime(Duration::from_secs(config.session_max_lifetime))
+                    .with_bans(bans.clone()),
+            ),
+            bans,
+            proxies,
             servers: Arc::new(Mutex::new(Vec::new())),
-            port_seq: AtomicI16::new(8080),
-            rotate_interval: 0,
+            listener_threads: Arc::new(Mutex::new(HashMap::new())),
+            port_seq: Arc::new(AtomicU16::new(config.port)),
+            rotate_interval: config.rotate_interval,
+            rotation: config.rotation_policy(),
+            intervals: Arc::new(Mutex::new(HashMap::new())),
+            cursors: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            usage_changed: Arc::new(Notify::new()),
+            exhaustion: config.exhaustion(),
+            queue_timeout: Duration::from_secs(config.queue_timeout),
+            credentials: config.credentials(),
+            health,
+            health_task: Arc::new(Mutex::new(None)),
+            ready_task: Arc::new(Mutex::new(None)),
+            probes: Arc::new(config.probe_config()),
+            dedupe_egress: config.dedupe_egress,
+            filter: config.proxy_filter(),
+            intel: Arc::new(intel),
+            listeners: config.listeners.clone(),
+            bind: config.bind,
+            bound: Arc::new(Mutex::new(HashMap::new())),
+            port_range: config.port_range,
+            port_table: config.port_table.clone(),
+            slots: Arc::new(Mutex::new(BTreeMap::new())),
+            stopped_slots: Arc::new(Mutex::new(BTreeSet::new())),
+            assignments: Arc::new(Mutex::new(HashMap::new())),
+            sources: config.pool_sources(),
+            reload_task: Arc::new(Mutex::new(None)),
+            admin_listen: config.admin_listen,
+            admin_token: config.admin_token.clone(),
+            admin_task: Arc::new(Mutex::new(None)),
+            metrics: Arc::new(Metrics::new(config.metrics_per_proxy)),
+            metrics_listen: config.metrics_listen,
+            metrics_task: Arc::new(Mutex::new(None)),
+            access_log: access_log.map(Arc::new),
+            fetcher: Fetcher::default(),
+            store: Arc::new(StateStore::default()),
+            ready_after: config.ready_after,
+            check_concurrency: config.check_concurrency.max(1),
+            progress: Arc::new(watch::Sender::new(progress)),
         }
     }
 
-    async fn load_proxies(proxies_path: String) -> Result<Vec<Proxy>, ProxyError> {
-        let mut proxies = Vec::<Proxy>::new();
+    /// Split listed proxies into the ones that passed a check within the TTL of
+    /// the state store, trusted until the health checker re-validates them, and
+    /// the ones still to check.
+    fn trust_fresh(listed: Vec<Proxy>, store: &StateStore) -> (Vec<Proxy>, Vec<Proxy>) {
+        let (mut trusted, unchecked): (Vec<Proxy>, Vec<Proxy>) = listed
+            .into_iter()
+            .map(|mut proxy| {
+                if let Some(record) = store.fresh(&proxy.addr()) {
+                    proxy.is_working = true;
+                    proxy.latency = record.mean_latency();
+                    proxy.egress_ip = record.egress_ip;
+                }
+                proxy
+            })
+            .partition(|proxy| proxy.is_working);
+        trusted.sort_by_key(|p| p.latency);
+        (trusted, unchecked)
+    }
 
-        let file = File::open(proxies_path)
-            .await
-            .map_err(|e| ProxyError::LoadProxiesError(e.to_string()))?;
-        let mut content = String::new();
-        BufReader::new(file)
-            .read_to_string(&mut content)
-            .await
-            .map_err(|e| ProxyError::LoadProxiesError(e.to_string()))?;
-        let checked_proxies = "checked_proxies.txt";
-        if let Ok(file) = File::open(checked_proxies).await {
-            let mut content = String::new();
-            BufReader::new(file)
-                .read_to_string(&mut content)
-                .await
-                .map_err(|e| ProxyError::LoadProxiesError(e.to_string()))?;
-            proxies = content
-                .lines()
-                .map(|line| Proxy::from_str(line))
-                .filter_map(Result::ok)
-                .collect();
-        }
-
-        let (sender, receiver) = channel::<Proxy>();
-
-        // Check each proxy in parallel
-        content
-            .lines()
-            .par_bridge()
-            .for_each_with(sender, |s, line| {
-                Proxy::from_str(line)
-                    .map_err(|e| {
-                        error!("Failed to parse proxy: {}", e);
-                    })
-                    .and_then(|proxy| {
-                        if proxies.contains(&proxy) {
-                            return Ok(());
-                        }
-                        ProxyServer::check_proxy(proxy)
-                            .map_err(|e| {
-                                error!("Failed to check proxy: {}", e);
-                            })
-                            .and_then(|proxy| {
-                                info!("proxy: {} live", proxy.to_string());
-                                s.send(proxy).map_err(|e| {
-                                    error!("Failed to send proxy: {}", e);
-                                })
-                            })
-                    });
-            });
+    /// Check `proxies` on the blocking pool with at most `check_concurrency`
+    /// checks in flight. Results arrive as the checks finish.
+    fn run_checks(&self, proxies: Vec<Proxy>) -> mpsc::Receiver<(Proxy, std::io::Result<Proxy>)> {
+        let (sender, receiver) = mpsc::channel(self.check_concurrency);
+        let limit = Arc::new(Semaphore::new(self.check_concurrency));
+        let probes = self.probes.clone();
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            for proxy in proxies {
+                let Ok(permit) = limit.clone().acquire_owned().await else {
+                    break;
+                };
+                let probes = probes.clone();
+                let metrics = metrics.clone();
+                let sender = sender.clone();
+                tokio::task::spawn_blocking(move || {
+                    let _span = info_span!("health_check", upstream = proxy.addr()).entered();
+                    let result = ProxyServer::check_proxy_with(proxy.clone(), &probes);
+                    metrics.check(&proxy, result.is_ok());
+                    let _ = sender.blocking_send((proxy, result));
+                    drop(permit);
+                });
+            }
+        });
+        receiver
+    }
 
-        while let Ok(proxy) = receiver.recv() {
-            proxies.push(proxy);
+    /// Check the proxies loaded on startup in the background, each one joins
+    /// the pool as soon as it passes.
+    async fn spawn_initial_checks(&self, unchecked: Vec<Proxy>) {
+        let total = unchecked.len();
+        let live = self.proxies().await.len();
+        info!("{} proxies checked recently, checking {}", live, total);
+        self.progress.send_replace(CheckProgress {
+            total,
+            checked: 0,
+            live,
+        });
+        if total == 0 {
+            return;
         }
+        let step = (total / 10).max(1);
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut results = manager.run_checks(unchecked);
+            while let Some((proxy, result)) = results.recv().await {
+                let passed = match result {
+                    Ok(mut checked) => {
+                        info!("proxy: {} live", checked);
+                        manager.store.record_check(&checked, true);
+                        manager.intel.enrich(&mut checked);
+                        manager.proxies.lock().unwrap().push(checked);
+                        true
+                    }
+                    Err(e) => {
+                        manager.store.record_check(&proxy, false);
+                        error!("Failed to check proxy: {}", e);
+                        false
+                    }
+                };
+                manager.progress.send_modify(|p| {
+                    p.checked += 1;
+                    p.live += passed as usize;
+                });
+                let progress = manager.progress();
+                if progress.checked.is_multiple_of(step) || progress.is_done() {
+                    info!("Checked {}/{} proxies, {} live", progress.checked, progress.total, progress.live);
+                    manager.sync_slots().await;
+                }
+            }
+        });
+    }
 
-        // Sort proxies by latency
-        proxies.sort_by(|a, b| a.latency.cmp(&b.latency));
+    pub fn progress(&self) -> CheckProgress {
+        *self.progress.borrow()
+    }
 
-        info!("Loaded {} live proxies", proxies.len());
+    /// Whether `ready_after` proxies are live or every proxy loaded on
+    /// startup has been checked.
+    pub fn is_ready(&self) -> bool {
+        let progress = self.progress();
+        progress.live >= self.ready_after || progress.is_done()
+    }
 
-        // save to checked_proxies.txt
-        let mut file = File::create("checked_proxies.txt").await?;
-        for proxy in proxies.iter() {
-            file.write_all(format!("{}\n", proxy.to_string()).as_bytes())
-                .await?;
-        }
+    /// Wait until `ready_after` proxies are live or every proxy loaded on
+    /// startup has been checked.
+    pub async fn wait_ready(&self) -> CheckProgress {
+        let ready_after = self.ready_after;
+        let mut progress = self.progress.subscribe();
+        let ready = progress.wait_for(|p| p.live >= ready_after || p.is_done()).await;
+        ready.map(|p| *p).unwrap_or_default()
+    }
 
-        Ok(proxies)
+    /// Parse every pool list, unreadable lists and banned proxies are skipped.
+    async fn read_pools(
+        sources: &[PoolSource],
+        fetcher: &Fetcher,
+        store: &StateStore,
+    ) -> Result<Vec<Proxy>, ProxyError> {
+        let mut listed = Vec::<Proxy>::new();
+        let mut loaded = 0;
+        for source in sources {
+            let proxies = if source.is_remote() {
+                fetcher.fetch(source).await
+            } else {
+                fetcher.read(source).await
+            };
+            let proxies = match proxies {
+                Ok(proxies) => proxies,
+                Err(e) => {
+                    error!("Failed to load pool {}: {}", source.path, e);
+                    continue;
+                }
+            };
+            loaded += 1;
+            let proxies = proxies.into_iter().filter(|p| !store.is_banned(&p.addr()));
+            listed.extend(proxies.map(|mut proxy| {
+                proxy.pool = source.name.clone();
+                proxy.tags.extend(source.tags.clone());
+                proxy
+            }));
+        }
+        if loaded == 0 {
+            return Err(ProxyError::LoadProxiesError("No proxy list could be read".to_string()));
+        }
+        Ok(listed)
     }
 
     pub async fn proxies(&self) -> Vec<Proxy> {
-        self.proxies.lock().await.clone().to_vec()
+        self.proxies.lock().unwrap().clone().to_vec()
     }
 
     pub async fn servers(&self) -> Vec<ProxyServer> {
         self.servers.lock().await.clone().to_vec()
     }
 
-    pub async fn create_server(&self, proxy: Proxy, port: i16) -> Result<SocketAddr, ProxyError> {
-        let server = ProxyServer::new_with_proxy(port, proxy.clone())?;
+    pub fn session_router(&self) -> Arc<SessionRouter> {
+        self.router.clone()
+    }
+
+    pub async fn create_server(&self, proxy: Proxy, port: u16) -> Result<SocketAddr, ProxyError> {
+        self.create_server_with_scope(proxy, port, ProxyFilter::default()).await
+    }
+
+    /// Create a listener bound to the proxies matching `scope`, both for its
+    /// rotation and for the upstreams picked by session parameters.
+    pub async fn create_server_with_scope(
+        &self,
+        proxy: Proxy,
+        port: u16,
+        scope: ProxyFilter,
+    ) -> Result<SocketAddr, ProxyError> {
+        let listener = Listener {
+            port,
+            scope,
+            ..Default::default()
+        };
+        self.create_listener(proxy, &listener).await
+    }
+
+    /// Create the server of `listener`, its settings override the global ones.
+    pub async fn create_listener(&self, proxy: Proxy, listener: &Listener) -> Result<SocketAddr, ProxyError> {
+        self.bind_listener(Some(proxy), listener).await
+    }
+
+    /// Create the server of `listener`, without a proxy it follows the
+    /// exhaustion policy until one is available.
+    async fn bind_listener(&self, proxy: Option<Proxy>, listener: &Listener) -> Result<SocketAddr, ProxyError> {
+        let server = self.build_server(proxy, listener)?;
         let server_addr = server.get_addr();
+        self.bound.lock().await.insert(server_addr, listener.clone());
         let mut servers = self.servers.lock().await;
         servers.push(server.clone());
-        tokio::spawn(async move { server.start() });
+        self.listener_threads.lock().await.insert(server_addr, spawn_listener(server));
         Ok(server_addr)
     }
 
-    async fn get_last_proxy(&self) -> Option<Proxy> {
-        let mut proxies = self.proxies().await;
-        if proxies.is_empty() {
-            return None;
+    /// Server of `listener` with the manager's selector, policies and sinks,
+    /// shared by the listeners and the port range slots.
+    fn build_server(&self, proxy: Option<Proxy>, listener: &Listener) -> Result<ProxyServer, ProxyError> {
+        let selector = if listener.scope == ProxyFilter::default() && listener.strategy == Strategy::default() {
+            self.router.clone()
+        } else {
+            Arc::new(self.router.scoped(listener.scope.clone()).with_strategy(listener.strategy))
+        };
+        let server = match proxy {
+            Some(proxy) => ProxyServer::new_with_proxy(listener.port, proxy)?,
+            None => ProxyServer::new(listener.port)?,
+        };
+        let server = server
+            .with_bind(listener.bind.unwrap_or(self.bind))
+            .with_protocols(listener.protocols.clone())
+            .with_credentials(listener.auth.clone().or_else(|| self.credentials.clone()))
+            .with_selector(selector)
+            .with_exhaustion(self.exhaustion, self.queue_timeout)
+            .with_probes(self.probes.clone())
+            .with_metrics(self.metrics.clone())
+            .with_access_log(self.access_log.clone())
+            .with_usage_changed(self.usage_changed.clone());
+        Ok(server)
+    }
+
+    /// Create the server of `listener` on the next proxy of its scope, unless
+    /// its port is already served.
+    pub async fn open_listener(&self, listener: &Listener) -> Result<SocketAddr, ProxyError> {
+        let taken = self.servers().await.iter().any(|s| s.get_addr().port() == listener.port)
+            || self.slots.lock().await.contains_key(&listener.port);
+        if taken {
+            return Err(ProxyError::Conflict(format!("Port {} is already served", listener.port)));
+        }
+        let proxy = self.get_last_proxy(None, listener).await.ok_or(ProxyError::ProxyNotSet)?;
+        self.create_listener(proxy, listener).await
+    }
+
+    /// Port range mode: make sure every live proxy has a listener pinned to it on
+    /// its assigned port and retire the listeners of proxies that went away.
+    pub async fn sync_slots(&self) {
+        let Some(range) = self.port_range else {
+            return;
+        };
+        let proxies = self.proxies().await;
+        let known: HashSet<String> = proxies.iter().map(Proxy::addr).collect();
+        let mut seen = HashSet::new();
+        let live = proxies
+            .iter()
+            .filter(|p| p.is_working && self.filter.matches(p) && seen.insert(p.addr()));
+
+        let mut assignments = self.assignments.lock().await;
+        let mut wanted = HashMap::new();
+        for proxy in live {
+            match range.assign(&mut assignments, &proxy.addr(), &known) {
+                Some(port) => {
+                    wanted.insert(port, proxy.clone());
+                }
+                None => warn!("Port range {}-{} is full, no listener for {}", range.start, range.end, proxy),
+            }
+        }
+        drop(assignments);
+
+        let stopped = self.stopped_slots.lock().await.clone();
+        let mut slots = self.slots.lock().await;
+        slots.retain(|port, server| {
+            let current = server.get_proxy().map(|p| p.addr());
+            let keep = wanted.get(port).map(Proxy::addr) == current;
+            if !keep {
+                server.stop();
+            }
+            keep
+        });
+        for (port, proxy) in wanted {
+            if slots.contains_key(&port) || stopped.contains(&port) {
+                continue;
+            }
+            let listener = Listener {
+                port,
+                ..Default::default()
+            };
+            let server = match self.build_server(Some(proxy), &listener) {
+                Ok(server) => server,
+                Err(e) => {
+                    error!("Failed to create listener on port {}: {}", port, e);
+                    continue;
+                }
+            };
+            slots.insert(port, server.clone());
+            self.listener_threads.lock().await.insert(server.get_addr(), spawn_listener(server));
+        }
+        info!("Port range {}-{}: {} listeners", range.start, range.end, slots.len());
+        drop(slots);
+
+        if let Err(e) = self.export_port_table().await {
+            error!("Failed to write port table: {}", e);
+        }
+    }
+
+    /// Listeners of the port range.
+    pub async fn slots(&self) -> Vec<ProxyServer> {
+        self.slots.lock().await.values().cloned().collect()
+    }
+
+    /// Stop the port range listeners on the ports of `range`, they stay closed
+    /// until a restart. Returns how many were stopped.
+    pub async fn stop_slots(&self, range: PortRange) -> usize {
+        let mut slots = self.slots.lock().await;
+        let ports: Vec<u16> = slots.range(range.start..=range.end).map(|(port, _)| *port).collect();
+        for port in ports.iter() {
+            if let Some(server) = slots.remove(port) {
+                server.stop();
+            }
+        }
+        drop(slots);
+        self.stopped_slots.lock().await.extend(ports.iter().copied());
+        if let Err(e) = self.export_port_table().await {
+            error!("Failed to write port table: {}", e);
+        }
+        ports.len()
+    }
+
+    /// Current port to proxy mapping of the port range mode.
+    pub async fn port_table(&self) -> Vec<(u16, Proxy)> {
+        let slots = self.slots.lock().await;
+        slots
+            .iter()
+            .filter_map(|(port, server)| server.get_proxy().map(|p| (*port, p)))
+            .collect()
+    }
+
+    async fn export_port_table(&self) -> Result<(), ProxyError> {
+        let Some(path) = &self.port_table else {
+            return Ok(());
+        };
+        let mut table = String::from("port,upstream,pool,country,egress_ip\n");
+        for (port, proxy) in self.port_table().await {
+            table.push_str(&format!(
+                "{},{},{},{},{}\n",
+                port,
+                proxy.addr(),
+                proxy.pool.unwrap_or_default(),
+                proxy.country.unwrap_or_default(),
+                proxy.egress_ip.unwrap_or_default()
+            ));
         }
-        let proxy = self.proxies.lock().await.remove(0);
-        self.proxies.lock().await.push(proxy.clone());
-        Some(proxy)
+        tokio::fs::write(path, table).await?;
+        Ok(())
+    }
+
+    /// Next working proxy of `listener`, from the rotation queue within its scope
+    /// unless it picks by another strategy. With `avoid` set, proxies with the same
+    /// rotation key (address, or egress IP when deduplicating) are skipped.
+    async fn get_last_proxy(&self, avoid: Option<&Proxy>, listener: &Listener) -> Option<Proxy> {
+        let avoid = avoid.map(|p| rotation_key(p, self.dedupe_egress));
+        let eligible = |proxy: &Proxy| {
+            let same = avoid.as_ref() == Some(&rotation_key(proxy, self.dedupe_egress));
+            proxy.is_working && !same && self.filter.matches(proxy) && listener.scope.matches(proxy)
+        };
+        let mut proxies = self.proxies.lock().unwrap();
+        if listener.strategy != Strategy::RoundRobin {
+            let candidates: Vec<&Proxy> = proxies.iter().filter(|p| eligible(p)).collect();
+            let cursor = self.cursors.lock().unwrap().entry(listener.port).or_default().clone();
+            return listener.strategy.pick(&candidates, &cursor).cloned();
+        }
+        // ejected proxies go to the back of the queue as well but are not handed out
+        for _ in 0..proxies.len() {
+            let proxy = proxies.remove(0);
+            proxies.push(proxy.clone());
+            if eligible(&proxy) {
+                return Some(proxy);
+            }
+        }
+        None
     }
 
     pub async fn set_proxies(&self, list: Vec<Proxy>) {
-        let mut proxies = self.proxies.lock().await;
+        let mut proxies = self.proxies.lock().unwrap();
         *proxies = list;
     }
 
     async fn get_server_by_proxy(&self, proxy: &Proxy) -> Option<ProxyServer> {
         let servers = self.servers().await;
         for x in servers {
-            if x.get_proxy().unwrap().ip == proxy.ip {
+            if x.get_proxy().is_some_and(|p| p.ip == proxy.ip) {
                 return Some(x);
             }
         }
@@ -162,103 +594,894 @@ impl ProxyManager {
     }
 
     pub async fn stop_server(&self, proxy: &Proxy) -> Result<(), ProxyError> {
-        let mut servers = self.servers().await;
         let server = self.get_server_by_proxy(proxy).await;
         match server {
-            Some(server) => {
-                let addr = server.get_addr();
-                let mut servers = self.servers.lock().await;
-                servers.retain(|x| x.get_addr() != addr);
-                server.stop();
-                Ok(())
-            }
+            Some(server) => self.stop_listener(server.get_addr().port()).await,
             None => Err(ProxyError::ServerError("Server not found".to_string())),
         }
     }
 
+    async fn get_server_by_port(&self, port: u16) -> Result<ProxyServer, ProxyError> {
+        let servers = self.servers().await;
+        servers
+            .into_iter()
+            .find(|s| s.get_addr().port() == port)
+            .ok_or_else(|| ProxyError::ServerNotFound(port.to_string()))
+    }
+
+    /// Stop the listener on `port`, open connections finish on their upstream.
+    pub async fn stop_listener(&self, port: u16) -> Result<(), ProxyError> {
+        let server = match self.get_server_by_port(port).await {
+            Ok(server) => server,
+            Err(e) => {
+                let range = PortRange { start: port, end: port };
+                return match self.stop_slots(range).await {
+                    0 => Err(e),
+                    _ => Ok(()),
+                };
+            }
+        };
+        let addr = server.get_addr();
+        self.servers.lock().await.retain(|x| x.get_addr() != addr);
+        self.bound.lock().await.remove(&addr);
+        self.intervals.lock().await.remove(&addr);
+        server.stop();
+        Ok(())
+    }
+
+    /// Settings of the listener behind the server on `addr`.
+    pub async fn listener_of(&self, addr: SocketAddr) -> Option<Listener> {
+        self.bound.lock().await.get(&addr).cloned()
+    }
+
+    /// Move `server` to the next proxy of `listener`.
+    async fn advance(&self, server: &ProxyServer, listener: &Listener) -> Result<Proxy, ProxyError> {
+        let old_proxy = server.get_proxy();
+        let new_proxy = self
+            .get_last_proxy(old_proxy.as_ref(), listener)
+            .await
+            .ok_or(ProxyError::ProxyNotSet)?;
+        let span = info_span!(
+            "rotate",
+            listener = server.get_addr().port(),
+            from = old_proxy.as_ref().map(|p| p.addr()),
+            to = new_proxy.addr()
+        );
+        if let Err(e) = switch_proxy(server, new_proxy.clone(), span).await {
+            self.record_health(&new_proxy, false).await;
+            return Err(e.into());
+        }
+        self.metrics.rotation(server.get_addr().port());
+        self.intervals.lock().await.remove(&server.get_addr());
+        let drain_after = listener.rotation.or(&self.rotation).drain_after();
+        let old_proxy = old_proxy.filter(|p| p.addr() != new_proxy.addr());
+        if let (Some(old_proxy), Some(grace)) = (old_proxy, drain_after) {
+            server.drain(&old_proxy.addr(), grace);
+        }
+        Ok(new_proxy)
+    }
+
+    /// Take `server` off `old_proxy` when nothing is left to move it to, the
+    /// connections still on it go like on a rotation.
+    async fn exhaust(&self, server: &ProxyServer, listener: &Listener, old_proxy: &Proxy) {
+        server.clear_proxy();
+        self.intervals.lock().await.remove(&server.get_addr());
+        if let Some(grace) = listener.rotation.or(&self.rotation).drain_after() {
+            server.drain(&old_proxy.addr(), grace);
+        }
+    }
+
+    /// Give the servers left without a proxy the next one of their scope, not
+    /// before the manager is ready.
+    async fn refill(&self, servers: &[ProxyServer]) {
+        if !self.is_ready() {
+            return;
+        }
+        for server in servers.iter().filter(|s| s.get_proxy().is_none()) {
+            let listener = self.listener_of(server.get_addr()).await.unwrap_or_default();
+            let Some(proxy) = self.get_last_proxy(None, &listener).await else {
+                continue;
+            };
+            match switch_proxy(server, proxy.clone(), tracing::Span::current()).await {
+                Ok(()) => info!("Server {} is back on a proxy: {}", server.get_addr(), proxy),
+                Err(_) => {
+                    self.record_health(&proxy, false).await;
+                }
+            }
+        }
+    }
+
+    /// Rotate the listener on `port` now, whatever its rotation interval.
+    pub async fn rotate_listener(&self, port: u16) -> Result<Proxy, ProxyError> {
+        let server = self.get_server_by_port(port).await?;
+        let listener = self.listener_of(server.get_addr()).await.unwrap_or_default();
+        self.advance(&server, &listener).await
+    }
+
     pub async fn rotate_proxy(&self) -> Result<(), ProxyError> {
         //check list proxy
         let proxies = self.proxies().await;
-        if proxies.is_empty() || proxies.len() < 2 {
-            return Err(ProxyError::ProxiesTooSmall(proxies.len() as u64));
-        }
+        // set_proxy probes, the servers stay unlocked meanwhile
+        let servers = self.servers().await;
+        self.refill(&servers).await;
+        // a pool too small to rotate still has to move off ejected proxies
+        let too_small = proxies.len() < 2;
 
-        info!("Rotating proxies: {}", proxies.len());
-        let servers = self.servers.lock().await;
-        info!("Check and rotating proxies for {} servers", servers.len());
+        debug!("Rotating proxies: {}", proxies.len());
+        debug!("Check and rotating proxies for {} servers", servers.len());
         for server in servers.iter() {
-            let old_proxy = server.get_proxy().unwrap();
+            let Some(old_proxy) = server.get_proxy() else {
+                continue;
+            };
+            let ejected = self.health.state(&old_proxy).ejected;
+            if too_small && !ejected {
+                continue;
+            }
             let duration = server.get_duration().as_secs();
-            info!(
+            debug!(
                 "Checking proxy: {} | server time {}s",
-                old_proxy.to_string(),
+                old_proxy,
                 duration
             );
 
-            if duration >= self.rotate_interval as u64 {
-                let new_proxy = match self.get_last_proxy().await {
-                    Some(p) => p,
-                    None => {
-                        error!("No proxies available for rotation");
-                        continue;
+            let listener = self.bound.lock().await.get(&server.get_addr()).cloned().unwrap_or_default();
+            let policy = listener.rotation.or(&self.rotation);
+            let interval = *self
+                .intervals
+                .lock()
+                .await
+                .entry(server.get_addr())
+                .or_insert_with(|| policy.jittered(listener.rotate_interval.unwrap_or(self.rotate_interval)));
+            let trigger = policy.due(interval, server.get_duration(), &server.get_usage(), chrono::Utc::now());
+            if ejected || trigger.is_some() {
+                match trigger {
+                    Some(trigger) => info!("Rotating server {} on {}", server.get_addr(), trigger),
+                    None => info!("Rotating server {} away from ejected {}", server.get_addr(), old_proxy),
+                }
+                match self.advance(server, &listener).await {
+                    Ok(_) => {}
+                    Err(ProxyError::ProxyNotSet) if ejected => self.exhaust(server, &listener, &old_proxy).await,
+                    Err(ProxyError::ProxyNotSet) => {
+                        debug!("No other proxy for server {}, staying on {}", server.get_addr(), old_proxy)
                     }
-                };
-                if new_proxy.eq(&old_proxy) {
-                    warn!("New proxy is the same as the old proxy");
-                    continue;
+                    Err(e) => error!("Failed to rotate server {}: {}", server.get_addr(), e),
                 }
-                server.set_proxy(new_proxy.clone());
             } else {
-                info!(
+                debug!(
                     "Proxy {} is still fresh {} seconds",
-                    old_proxy.to_string(),
+                    old_proxy,
                     duration
                 );
             }
         }
+        if too_small {
+            return Err(ProxyError::ProxiesTooSmall(proxies.len() as u64));
+        }
         Ok(())
     }
 
-    async fn auto_rotate_proxy(&self) -> Result<(), ProxyError> {
-        info!("Rotating proxies");
-        loop {
-            if self.rotate_interval == 0 || self.proxies().await.is_empty() {
-                error!("No proxies available for rotation");
-                break;
+    /// Rotate the servers that are due, a pool of one has nothing to rotate to.
+    async fn auto_rotate_proxy(&self) {
+        match self.rotate_proxy().await {
+            Ok(()) | Err(ProxyError::ProxiesTooSmall(_)) => {}
+            Err(e) => error!("Failed to rotate proxies: {}", e),
+        }
+    }
+
+    async fn record_health(&self, proxy: &Proxy, ok: bool) -> Option<Transition> {
+        let transition = self.health.record(proxy, ok)?;
+        match transition {
+            Transition::Ejected => warn!("Proxy ejected: {}", proxy),
+            Transition::Readmitted => info!("Proxy re-admitted: {}", proxy),
+        }
+        apply_transition(&mut self.proxies.lock().unwrap(), proxy, transition);
+        Some(transition)
+    }
+
+    /// Run one health check round over the healthy proxies and the ejected ones
+    /// whose backoff has expired. Servers on a freshly ejected proxy are rotated.
+    pub async fn check_health(&self) {
+        let now = Instant::now();
+        let due: Vec<Proxy> = self
+            .proxies()
+            .await
+            .into_iter()
+            .filter(|p| self.health.is_due(p, now))
+            .collect();
+        info!("Health checking {} proxies", due.len());
+
+        let mut results = self.run_checks(due);
+        let mut ejected = false;
+        while let Some((proxy, result)) = results.recv().await {
+            match &result {
+                Ok(checked) => self.store.record_check(checked, true),
+                Err(_) => self.store.record_check(&proxy, false),
+            }
+            if let Ok(checked) = &result {
+                let mut proxies = self.proxies.lock().unwrap();
+                for p in proxies.iter_mut().filter(|p| p.addr() == proxy.addr()) {
+                    p.latency = checked.latency;
+                    if checked.egress_ip.is_some() {
+                        p.egress_ip = checked.egress_ip.clone();
+                    }
+                    if checked.anonymity.is_some() {
+                        p.anonymity = checked.anonymity;
+                    }
+                    self.intel.enrich(p);
+                }
             }
-            time::sleep(Duration::from_secs(self.rotate_interval as u64)).await;
-            self.rotate_proxy().await?;
+            let transition = self.record_health(&proxy, result.is_ok()).await;
+            ejected |= transition == Some(Transition::Ejected);
         }
-        info!("Rotating proxies finished");
+
+        if ejected {
+            if let Err(e) = self.rotate_proxy().await {
+                error!("Failed to rotate away from ejected proxies: {}", e);
+            }
+        }
+        self.sync_slots().await;
+    }
+
+    /// Start the background health checker, does nothing if it already runs
+    /// or the interval is zero.
+    pub async fn spawn_health_checker(&self) {
+        let mut task = self.health_task.lock().await;
+        if task.is_some() || self.health.config().interval.is_zero() {
+            return;
+        }
+        let manager = self.clone();
+        *task = Some(tokio::spawn(async move {
+            loop {
+                time::sleep(manager.health.config().next_delay()).await;
+                manager.check_health().await;
+            }
+        }));
+    }
+
+    /// Re-read the pool lists. New entries join once they pass a check, removed
+    /// ones are retired and the listeners using them move on while their open
+    /// connections finish. Rotation order, health state and sticky sessions of
+    /// the remaining entries are kept.
+    pub async fn reload(&self) -> Result<(), ProxyError> {
+        let listed = ProxyManager::read_pools(&self.sources, &self.fetcher, &self.store).await?;
+        let changes = reload::diff(&self.proxies().await, &listed);
+        info!(
+            "Reloading proxies: {} added, {} removed",
+            changes.added.len(),
+            changes.removed.len()
+        );
+
+        let mut results = self.run_checks(changes.added);
+        let mut added = Vec::new();
+        while let Some((proxy, result)) = results.recv().await {
+            match result {
+                Ok(mut checked) => {
+                    info!("proxy: {} live", checked);
+                    self.store.record_check(&checked, true);
+                    self.intel.enrich(&mut checked);
+                    added.push(checked);
+                }
+                Err(e) => {
+                    self.store.record_check(&proxy, false);
+                    error!("Failed to check proxy: {}", e);
+                }
+            }
+        }
+
+        {
+            let mut proxies = self.proxies.lock().unwrap();
+            proxies.retain(|p| !changes.removed.iter().any(|r| reload::same_entry(p, r)));
+            reload::refresh(&mut proxies, &listed);
+            proxies.extend(added);
+        }
+        self.retire_removed().await;
+        self.sync_slots().await;
+        Ok(())
+    }
+
+    /// Take the upstream `addr` out of every pool until it is unbanned, the ban
+    /// is persisted in the state store.
+    pub async fn ban(&self, addr: &str, reason: Option<String>) {
+        warn!("Banning proxy {}", addr);
+        self.store.ban(addr, reason);
+        self.proxies.lock().unwrap().retain(|p| p.addr() != addr);
+        self.retire_removed().await;
+        self.sync_slots().await;
+    }
+
+    /// Lift a ban, the upstream rejoins its pools once it passes a check.
+    pub async fn unban(&self, addr: &str) -> Result<(), ProxyError> {
+        info!("Unbanning proxy {}", addr);
+        self.store.unban(addr);
+        self.reload().await
+    }
+
+    /// Check `proxy` and add it to the pool once it passes. Unless one of the
+    /// lists has it, it goes away on the next reload.
+    pub async fn add_proxy(&self, proxy: Proxy) -> Result<Proxy, ProxyError> {
+        if self.proxies.lock().unwrap().iter().any(|p| reload::same_entry(p, &proxy)) {
+            return Err(ProxyError::Conflict(format!("{} is already in the pool", proxy.addr())));
+        }
+        let probes = self.probes.clone();
+        let unchecked = proxy.clone();
+        let result = tokio::task::spawn_blocking(move || ProxyServer::check_proxy_with(unchecked, &probes))
+            .await
+            .map_err(|e| ProxyError::ServerError(e.to_string()))?;
+        let mut checked = match result {
+            Ok(checked) => checked,
+            Err(e) => {
+                self.store.record_check(&proxy, false);
+                return Err(e.into());
+            }
+        };
+        info!("proxy: {} live", checked);
+        self.store.record_check(&checked, true);
+        self.intel.enrich(&mut checked);
+        self.proxies.lock().unwrap().push(checked.clone());
+        self.sync_slots().await;
+        Ok(checked)
+    }
+
+    /// Take the upstream `addr` out of every pool until the next reload lists it again.
+    pub async fn remove_proxy(&self, addr: &str) -> Result<(), ProxyError> {
+        {
+            let mut proxies = self.proxies.lock().unwrap();
+            let before = proxies.len();
+            proxies.retain(|p| p.addr() != addr);
+            if proxies.len() == before {
+                return Err(ProxyError::ProxyNotFound(addr.to_string()));
+            }
+        }
+        self.retire_removed().await;
+        self.sync_slots().await;
+        Ok(())
+    }
+
+    /// Pause or resume the upstream `addr`. Paused proxies aren't selected,
+    /// listeners on them move on.
+    pub async fn pause_proxy(&self, addr: &str, paused: bool) -> Result<(), ProxyError> {
+        {
+            let mut proxies = self.proxies.lock().unwrap();
+            let mut found = false;
+            for proxy in proxies.iter_mut().filter(|p| p.addr() == addr) {
+                proxy.paused = paused;
+                found = true;
+            }
+            if !found {
+                return Err(ProxyError::ProxyNotFound(addr.to_string()));
+            }
+        }
+        info!("Proxy {} {}", addr, if paused { "paused" } else { "resumed" });
+        self.retire_removed().await;
+        self.sync_slots().await;
+        Ok(())
+    }
+
+    /// Every metric in the Prometheus text format, with the pool sizes of now.
+    pub async fn render_metrics(&self) -> String {
+        let proxies = self.proxies().await;
+        let ejected = proxies.iter().filter(|p| self.health.state(p).ejected).count();
+        let paused = proxies.iter().filter(|p| p.paused).count();
+        let healthy = proxies.len() - proxies.iter().filter(|p| p.paused || self.health.state(p).ejected).count();
+        self.metrics.set_pool("healthy", healthy);
+        self.metrics.set_pool("ejected", ejected);
+        self.metrics.set_pool("paused", paused);
+        self.metrics.set_pool("banned", self.store.banned());
+        self.metrics.render()
+    }
+
+    pub fn health_state(&self, proxy: &Proxy) -> HealthState {
+        self.health.state(proxy)
+    }
+
+    /// Proxies cooling down for a destination that looked like it banned them.
+    pub fn cooldowns(&self) -> Vec<Cooldown> {
+        self.bans.cooldowns()
+    }
+
+    /// End the cooldowns of the upstream `addr`, returns how many.
+    pub fn clear_cooldowns(&self, addr: &str) -> usize {
+        self.bans.clear(addr)
+    }
+
+    /// What the state store knows about the upstream `addr`.
+    pub fn proxy_record(&self, addr: &str) -> Option<ProxyRecord> {
+        self.store.record(addr)
+    }
+
+    /// Move servers whose proxy is no longer listed or paused to the next one of their scope.
+    async fn retire_removed(&self) {
+        let proxies = self.proxies().await;
+        for server in self.servers().await {
+            let Some(current) = server.get_proxy() else {
+                continue;
+            };
+            if proxies.iter().any(|p| p.addr() == current.addr() && !p.paused) {
+                continue;
+            }
+            let listener = self.bound.lock().await.get(&server.get_addr()).cloned().unwrap_or_default();
+            match self.get_last_proxy(Some(&current), &listener).await {
+                Some(proxy) => {
+                    if switch_proxy(&server, proxy.clone(), tracing::Span::current()).await.is_err() {
+                        self.record_health(&proxy, false).await;
+                    }
+                }
+                None => self.exhaust(&server, &listener, &current).await,
+            }
+        }
+    }
+
+    /// Reload the pool lists when one of them changes, on SIGHUP and when a
+    /// remote list is due for a refresh. Does nothing if the reloader already runs.
+    pub async fn spawn_reloader(&self) {
+        let mut task = self.reload_task.lock().await;
+        if task.is_some() {
+            return;
+        }
+        let manager = self.clone();
+        *task = Some(tokio::spawn(async move {
+            let (_watcher, mut changes) = match reload::watch(&manager.sources) {
+                Ok((watcher, changes)) => (Some(watcher), changes),
+                Err(e) => {
+                    error!("Failed to watch proxy lists: {}", e);
+                    (None, tokio::sync::mpsc::unbounded_channel().1)
+                }
+            };
+            let mut hangups = reload::hangups();
+            let mut refresh = manager
+                .sources
+                .iter()
+                .filter(|s| s.is_remote())
+                .filter_map(|s| s.refresh)
+                .min()
+                .map(|period| time::interval_at(time::Instant::now() + period, period.max(Duration::from_secs(1))));
+            loop {
+                tokio::select! {
+                    _ = reload::tick(&mut refresh) => {}
+                    Some(()) = changes.recv() => {
+                        // editors save in several steps, let the list settle
+                        time::sleep(Duration::from_millis(500)).await;
+                        while changes.try_recv().is_ok() {}
+                    }
+                    Some(()) = hangups.recv() => info!("SIGHUP received"),
+                    else => break,
+                }
+                if let Err(e) = manager.reload().await {
+                    error!("Failed to reload proxies: {}", e);
+                }
+            }
+        }));
+    }
+
+    /// Serve the admin API when it has an address, does nothing if it already runs.
+    pub async fn spawn_admin(&self) -> Result<(), ProxyError> {
+        let mut task = self.admin_task.lock().await;
+        let (Some(addr), None) = (self.admin_listen, task.as_ref()) else {
+            return Ok(());
+        };
+        let token = self
+            .admin_token
+            .clone()
+            .ok_or_else(|| ProxyError::ConfigError("The admin API needs a token".to_string()))?;
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Admin API on: {}", addr);
+        *task = Some(tokio::spawn(admin::serve(self.clone(), listener, Some(token))));
         Ok(())
     }
 
+    /// Serve `/metrics` alone, without a token, when it has its own address.
+    /// Does nothing if it already runs.
+    pub async fn spawn_metrics(&self) -> Result<(), ProxyError> {
+        let mut task = self.metrics_task.lock().await;
+        let (Some(addr), None) = (self.metrics_listen, task.as_ref()) else {
+            return Ok(());
+        };
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Metrics on: http://{}/metrics", addr);
+        *task = Some(tokio::spawn(admin::serve(self.clone(), listener, None)));
+        Ok(())
+    }
+
+    /// Start the listeners, they get their proxies once `ready_after` of them
+    /// are live and the ones loaded on startup keep joining meanwhile.
     pub async fn start(&self) -> Result<(), ProxyError> {
-        if self.proxies().await.is_empty() {
-            return Err(ProxyError::ProxyNotSet);
+        let ready = self.is_ready();
+        if !ready {
+            info!(
+                "Waiting for {} live proxies, listeners use the {} policy meanwhile",
+                self.ready_after, self.exhaustion
+            );
         }
         if self.servers().await.is_empty() {
-            let last_proxy = self.get_last_proxy().await.expect("No proxies available");
-            let last_port = self.port_seq.load(std::sync::atomic::Ordering::SeqCst);
-            self.port_seq
-                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            let addr = self.create_server(last_proxy, last_port).await?;
-            info!("Started proxy server on: {}", addr);
-        }
-        self.rotate_proxy().await?;
+            let listeners = match self.listeners.is_empty() && self.port_range.is_none() {
+                true => vec![Listener {
+                    port: self.port_seq.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+                    ..Default::default()
+                }],
+                false => self.listeners.clone(),
+            };
+            for listener in listeners.iter() {
+                let proxy = match ready {
+                    true => self.get_last_proxy(None, listener).await,
+                    false => None,
+                };
+                if ready && proxy.is_none() {
+                    warn!(
+                        "No proxies available for listener on port {}, starting it with the {} policy",
+                        listener.port, self.exhaustion
+                    );
+                }
+                let addr = self.bind_listener(proxy, listener).await?;
+                info!("Started proxy server on: {}", addr);
+            }
+        }
+        self.sync_slots().await;
+        self.spawn_health_checker().await;
+        self.spawn_reloader().await;
+        self.spawn_admin().await?;
+        self.spawn_metrics().await?;
+        if !ready {
+            let manager = self.clone();
+            *self.ready_task.lock().await = Some(tokio::spawn(async move {
+                let progress = manager.wait_ready().await;
+                info!("{} live proxies, listeners ready", progress.live);
+                manager.refill(&manager.servers().await).await;
+                manager.sync_slots().await;
+            }));
+        }
+        // more proxies may still be on their way
+        if let Err(e) = self.rotate_proxy().await {
+            warn!("Failed to rotate proxies: {}", e);
+        }
         Ok(())
     }
+
+    /// Servers of the listeners and of the port range.
+    async fn all_servers(&self) -> Vec<ProxyServer> {
+        let mut servers = self.servers().await;
+        servers.extend(self.slots.lock().await.values().cloned());
+        servers
+    }
+
+    /// Connections being relayed by every listener, oldest first.
+    pub async fn connections(&self) -> Vec<Connection> {
+        let mut connections: Vec<Connection> = self.all_servers().await.iter().flat_map(ProxyServer::connections).collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    pub async fn close_connection(&self, id: u64) -> Result<(), ProxyError> {
+        let servers = self.all_servers().await;
+        match servers.iter().any(|s| s.close_connection(id)) {
+            true => Ok(()),
+            false => Err(ProxyError::ConnectionNotFound(id)),
+        }
+    }
+
+    /// Close every connection of the client `ip`, returns how many.
+    pub async fn close_client(&self, ip: IpAddr) -> usize {
+        self.all_servers().await.iter().map(|s| s.close_client(ip)).sum()
+    }
+
+    /// Close every connection through `upstream`, returns how many.
+    pub async fn close_upstream(&self, upstream: &str) -> usize {
+        self.all_servers().await.iter().map(|s| s.close_upstream(upstream)).sum()
+    }
+
+    /// Restart the accept loops that ended without their server being stopped,
+    /// e.g. on a failed bind or accept.
+    async fn restart_listeners(&self) {
+        let servers = self.all_servers().await;
+        let mut threads = self.listener_threads.lock().await;
+        for server in servers {
+            let addr = server.get_addr();
+            let down = threads.get(&addr).is_none_or(|t| t.is_finished());
+            if down && !server.is_stopped() {
+                warn!("Listener on {} is down, restarting", addr);
+                threads.insert(addr, spawn_listener(server));
+            }
+        }
+        threads.retain(|_, t| !t.is_finished());
+    }
+
+    /// Start again the background tasks that ended, they only do on a panic.
+    async fn restart_tasks(&self) {
+        let tasks = [
+            ("health checker", &self.health_task),
+            ("reloader", &self.reload_task),
+            ("admin API", &self.admin_task),
+            ("metrics endpoint", &self.metrics_task),
+        ];
+        for (name, task) in tasks {
+            let mut task = task.lock().await;
+            if task.as_ref().is_some_and(JoinHandle::is_finished) {
+                warn!("The {} stopped, restarting", name);
+                *task = None;
+            }
+        }
+        self.spawn_health_checker().await;
+        self.spawn_reloader().await;
+        if let Err(e) = self.spawn_admin().await {
+            error!("Failed to restart the admin API: {}", e);
+        }
+        if let Err(e) = self.spawn_metrics().await {
+            error!("Failed to restart the metrics endpoint: {}", e);
+        }
+    }
+
+    /// Stop the listeners and the background tasks, open connections finish
+    /// on their upstream.
+    async fn shutdown(&self) {
+        for task in [
+            &self.health_task,
+            &self.ready_task,
+            &self.reload_task,
+            &self.admin_task,
+            &self.metrics_task,
+        ] {
+            if let Some(task) = task.lock().await.take() {
+                task.abort();
+            }
+        }
+        let mut servers: Vec<ProxyServer> = self.servers.lock().await.drain(..).collect();
+        servers.extend(std::mem::take(&mut *self.slots.lock().await).into_values());
+        self.bound.lock().await.clear();
+        servers.iter().for_each(ProxyServer::stop);
+        let threads: Vec<thread::JoinHandle<()>> = self.listener_threads.lock().await.drain().map(|(_, t)| t).collect();
+        let joined = tokio::task::spawn_blocking(move || threads.into_iter().for_each(|t| drop(t.join()))).await;
+        if let Err(e) = joined {
+            error!("Failed to wait for the listeners: {}", e);
+        }
+        info!("Stopped {} listeners", servers.len());
+    }
+
+    /// Start the listeners and keep them going until `shutdown` is cancelled:
+    /// rotate them when due, restart the listeners and background tasks that
+    /// died, then stop everything.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<(), ProxyError> {
+        let started = tokio::select! {
+            started = self.start() => started,
+            _ = shutdown.cancelled() => Ok(()),
+        };
+        if started.is_ok() {
+            let mut tick = time::interval(SUPERVISE_INTERVAL);
+            tick.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            loop {
+                let supervise = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tick.tick() => true,
+                    _ = self.usage_changed.notified() => false,
+                };
+                if !supervise {
+                    // every connection notifies, let a burst of them settle
+                    time::sleep(USAGE_DEBOUNCE).await;
+                }
+                self.auto_rotate_proxy().await;
+                if supervise {
+                    self.restart_listeners().await;
+                    self.restart_tasks().await;
+                }
+            }
+        }
+        self.shutdown().await;
+        started
+    }
+
+    /// `run` on a task of its own, the handle stops it and waits for it.
+    pub fn spawn(&self) -> RunHandle {
+        let shutdown = CancellationToken::new();
+        let manager = self.clone();
+        let token = shutdown.clone();
+        RunHandle::new(shutdown, tokio::spawn(async move { manager.run(token).await }))
+    }
+}
+
+/// How often the run loop checks rotations, listeners and background tasks.
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the run loop lets usage notifications pile up before rotating.
+const USAGE_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Move `server` to `proxy`, the probes of `set_proxy` block so they run
+/// off the runtime.
+async fn switch_proxy(server: &ProxyServer, proxy: Proxy, span: tracing::Span) -> std::io::Result<()> {
+    let server = server.clone();
+    tokio::task::spawn_blocking(move || span.in_scope(|| server.set_proxy(proxy)))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Run the accept loop of `server`, it blocks, keep it off the runtime. One OS
+/// thread per listener, a large port range would starve the runtime.
+fn spawn_listener(server: ProxyServer) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        if let Err(e) = server.start() {
+            error!("Listener on {} failed: {}", server.get_addr(), e);
+        }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::testing::{dead_proxy, free_port, live_upstream, spawn_upstream};
+    use clap::Parser;
     use tokio::time;
 
     #[tokio::test]
     async fn test_load_proxies() {
-        let proxies_path = "proxies.txt".to_string();
-        let proxies = ProxyManager::load_proxies(proxies_path).await.unwrap();
+        let dir = std::env::temp_dir();
+        let proxies_path = dir.join(format!("qproxy-list-{}.txt", std::process::id()));
+        let state_path = dir.join(format!("qproxy-list-{}.jsonl", std::process::id()));
+        let mut lines = vec!["# datacenter".to_string()];
+        lines.extend((0..100).map(|i| format!("10.0.{}.{}:1080:user:pass", i / 250, i % 250 + 1)));
+        std::fs::write(&proxies_path, lines.join("\n")).unwrap();
+
+        let store = StateStore::open(&state_path.to_string_lossy(), Duration::from_secs(60)).unwrap();
+        let sources = vec![PoolSource::unnamed(&proxies_path.to_string_lossy())];
+        let proxies = ProxyManager::read_pools(&sources, &Fetcher::default(), &store).await.unwrap();
         assert_eq!(proxies.len(), 100);
+        assert_eq!(proxies[0].auth.as_ref().unwrap().user, "user");
+
+        // banned upstreams are left out of the list
+        store.ban(&proxies[0].addr(), None);
+        let proxies = ProxyManager::read_pools(&sources, &Fetcher::default(), &store).await.unwrap();
+        assert_eq!(proxies.len(), 99);
+        drop(store);
+        std::fs::remove_file(proxies_path).unwrap();
+        std::fs::remove_file(state_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_progressive_load() {
+        let proxies_path = std::env::temp_dir().join(format!("qproxy-{}.txt", std::process::id()));
+        let proxies_path = proxies_path.to_string_lossy().to_string();
+        let mut lines: Vec<String> = (0..3).map(|_| spawn_upstream("ok").expose()).collect();
+        lines.push(dead_proxy().expose());
+        std::fs::write(&proxies_path, lines.join("\n")).unwrap();
+
+        let config = Config::parse_from([
+            "qproxy",
+            "--proxies-path",
+            proxies_path.as_str(),
+            "--check",
+            "handshake",
+            "--check-timeout",
+            "2",
+            "--state-path",
+            "",
+            "--ready-after",
+            "2",
+            "--check-concurrency",
+            "2",
+        ]);
+        let manager = ProxyManager::new(&config).await;
+        std::fs::remove_file(proxies_path).unwrap();
+        assert!(manager.wait_ready().await.live >= 2);
+
+        let mut progress = manager.progress.subscribe();
+        let done = *progress.wait_for(CheckProgress::is_done).await.unwrap();
+        assert_eq!(
+            done,
+            CheckProgress {
+                total: 4,
+                checked: 4,
+                live: 3
+            }
+        );
+        let proxies = manager.proxies().await;
+        assert_eq!(proxies.len(), 3);
+        assert!(proxies.iter().all(|p| p.is_working));
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("qproxy-reload-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let config = Config::parse_from(["qproxy", "--proxies-path", path.as_str(), "--check", "handshake"]);
+        let kept = live_upstream("ok");
+        let removed = live_upstream("ok");
+        let added = spawn_upstream("ok");
+        let manager = ProxyManager::from_config(vec![kept.clone(), removed.clone()], &config);
+        manager.health.record(&kept, false);
+
+        std::fs::write(&path, format!("{}\n{}\n{}\n", kept.expose(), added.expose(), dead_proxy().expose())).unwrap();
+        manager.reload().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let proxies = manager.proxies().await;
+        let addrs: Vec<String> = proxies.iter().map(Proxy::addr).collect();
+        assert_eq!(addrs, vec![kept.addr(), added.addr()]);
+        // state of the kept entry survives the reload
+        assert_eq!(manager.health.state(&kept).consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_default() {
+        let config = Config::default();
+        assert_eq!((config.port, config.state_path), (8080, None));
+        let manager = ProxyManager::default();
+        assert_eq!(manager.rotate_interval, 0);
+        assert!(manager.proxies().await.is_empty());
+        assert!(manager.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_port_range_slots() {
+        let start = free_port();
+        let range = format!("{}-{}", start, start + 2);
+        let config = Config::parse_from(["qproxy", "--port-range", range.as_str()]);
+        let proxies: Vec<Proxy> = (0..2)
+            .map(|_| live_upstream("ok"))
+            .collect();
+        let manager = ProxyManager::from_config(proxies.clone(), &config);
+
+        manager.sync_slots().await;
+        let table = manager.port_table().await;
+        assert_eq!(table.len(), 2);
+        let second = table.iter().find(|(_, p)| p.addr() == proxies[1].addr()).unwrap().0;
+
+        // the first proxy goes away, the second keeps its port
+        manager.set_proxies(proxies[1..].to_vec()).await;
+        manager.sync_slots().await;
+        assert_eq!(manager.port_table().await, vec![(second, proxies[1].clone())]);
+    }
+
+    #[tokio::test]
+    async fn test_start_before_ready() {
+        let port = free_port();
+        let config = Config::parse_from(["qproxy", "--state-path", "", "--port", &port.to_string()]);
+        let manager = ProxyManager::from_config(Vec::new(), &config);
+        // a proxy is still being checked
+        manager.progress.send_replace(CheckProgress {
+            total: 1,
+            ..Default::default()
+        });
+        time::timeout(Duration::from_secs(5), manager.start()).await.unwrap().unwrap();
+        let server = manager.get_server_by_port(port).await.unwrap();
+        assert!(server.get_proxy().is_none());
+
+        let proxy = live_upstream("ok");
+        manager.proxies.lock().unwrap().push(proxy.clone());
+        manager.progress.send_modify(|p| {
+            p.checked += 1;
+            p.live += 1;
+        });
+        for _ in 0..50 {
+            if server.get_proxy().is_some() {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(server.get_proxy().map(|p| p.addr()), Some(proxy.addr()));
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_restarts_listeners() {
+        // the port is taken, the first accept loop fails to bind
+        let blocker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = blocker.local_addr().unwrap().port();
+        let config = Config::parse_from(["qproxy", "--state-path", ""]);
+        let proxy = live_upstream("ok");
+        let manager = ProxyManager::from_config(vec![proxy.clone()], &config);
+        manager.create_server(proxy, port).await.unwrap();
+        let handle = manager.spawn();
+        time::sleep(Duration::from_millis(200)).await;
+        drop(blocker);
+
+        let mut up = false;
+        for _ in 0..50 {
+            time::sleep(Duration::from_millis(100)).await;
+            if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                up = true;
+                break;
+            }
+        }
+        assert!(up, "listener was not restarted");
+
+        handle.stop().await.unwrap();
+        assert!(manager.servers().await.is_empty());
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
     }
 }
@@ -1,3 +1,26 @@
+#[allow(clippy::module_inception)]
 mod manager;
+mod admin;
+mod bans;
+mod geoip;
+mod health;
+mod pool;
+mod reload;
+mod rotation;
+mod secrets;
+mod source;
+mod store;
+mod supervisor;
+mod selector;
 
-pub use manager::ProxyManager;
+pub use bans::{BanConfig, BanTracker, Cooldown};
+pub use geoip::IpIntel;
+pub use health::{HealthConfig, HealthState};
+pub use manager::{CheckProgress, ProxyManager};
+pub use pool::{Listener, PoolSource, PortRange};
+pub use rotation::{CronSchedule, RotationMode, RotationPolicy, Trigger};
+pub use source::ListFormat;
+pub use secrets::Secrets;
+pub use store::{ProxyRecord, StateStore};
+pub use supervisor::RunHandle;
+pub use selector::{ProxyFilter, SessionRouter, Strategy};
@@ -0,0 +1,234 @@
+use crate::manager::rotation::RotationPolicy;
+use crate::manager::selector::{ProxyFilter, Strategy};
+use crate::manager::source::ListFormat;
+use crate::{Protocol, ProxyAuth};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A named pool built from its own proxy list, a file or an HTTP(S) URL, e.g.
+/// `residential-us=resi.txt;type=residential;region=us`. The `key=value`
+/// options after the location are tags set on every proxy of the pool, except
+/// for the reserved keys:
+///
+/// - `format=text|json|csv|yaml`, detected from the content type or extension by default
+/// - `header=Name: value`, sent with every request, repeatable
+/// - `field.<name>=source-name`, where records keep one of our fields: `ip`, `port`,
+///   `user`, `pass`, `country`, `protocol`, `tags`, `weight`, `provider`, `expires`
+/// - `root=/pointer`, JSON pointer of the array in the document
+/// - `refresh=<seconds>`, minimum time between two fetches of a URL
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct PoolSource {
+    pub name: Option<String>,
+    pub path: String,
+    pub tags: BTreeMap<String, String>,
+    pub format: Option<ListFormat>,
+    pub headers: Vec<(String, String)>,
+    pub fields: BTreeMap<String, String>,
+    pub root: Option<String>,
+    pub refresh: Option<Duration>,
+}
+
+impl std::fmt::Debug for PoolSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // header values are often API keys
+        let headers: Vec<(&str, &str)> = self.headers.iter().map(|(name, _)| (name.as_str(), "***")).collect();
+        f.debug_struct("PoolSource")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .field("tags", &self.tags)
+            .field("format", &self.format)
+            .field("headers", &headers)
+            .field("fields", &self.fields)
+            .field("root", &self.root)
+            .field("refresh", &self.refresh)
+            .finish()
+    }
+}
+
+impl PoolSource {
+    /// The unnamed pool of the legacy `--proxies-path` list.
+    pub fn unnamed(path: &str) -> Self {
+        PoolSource {
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.path.starts_with("http://") || self.path.starts_with("https://")
+    }
+
+    /// Name of our field `name` in the source's records.
+    pub fn field<'a>(&'a self, name: &'a str) -> &'a str {
+        self.fields.get(name).map(String::as_str).unwrap_or(name)
+    }
+}
+
+impl FromStr for PoolSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(';');
+        let head = parts.next().unwrap_or_default();
+        let (name, path) = head
+            .split_once('=')
+            .filter(|(name, path)| !name.is_empty() && !path.is_empty())
+            .ok_or_else(|| format!("Invalid pool, expected name=path: {}", s))?;
+        let mut source = PoolSource {
+            name: Some(name.to_string()),
+            path: path.to_string(),
+            ..Default::default()
+        };
+        for option in parts.filter(|t| !t.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid pool option, expected key=value: {}", option))?;
+            match key {
+                "format" => source.format = Some(ListFormat::from_str(value)?),
+                "header" => {
+                    let (name, value) = value
+                        .split_once(':')
+                        .ok_or_else(|| format!("Invalid header, expected Name: value: {}", value))?;
+                    source.headers.push((name.trim().to_string(), value.trim().to_string()));
+                }
+                "root" => source.root = Some(value.to_string()),
+                "refresh" => {
+                    let secs = value.parse().map_err(|_| format!("Invalid refresh: {}", value))?;
+                    source.refresh = Some(Duration::from_secs(secs));
+                }
+                key => match key.strip_prefix("field.") {
+                    Some(field) => {
+                        source.fields.insert(field.to_string(), value.to_string());
+                    }
+                    None => {
+                        source.tags.insert(key.to_string(), value.to_string());
+                    }
+                },
+            }
+        }
+        Ok(source)
+    }
+}
+
+/// A listener bound to the proxies matching `scope`, parsed from `port:filter`
+/// such as `9001:pool=datacenter` or `9002:tag.team=ads,country=US`. The other
+/// settings come from the config file and fall back to the global ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listener {
+    pub port: u16,
+    pub scope: ProxyFilter,
+    pub bind: Option<IpAddr>,
+    /// Inbound protocols accepted, all of them when empty.
+    pub protocols: Vec<Protocol>,
+    pub auth: Option<ProxyAuth>,
+    pub strategy: Strategy,
+    pub rotate_interval: Option<i64>, //in seconds, 0 keeps the proxy until it fails
+    pub rotation: RotationPolicy,
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, scope) = s.split_once(':').unwrap_or((s, ""));
+        Ok(Listener {
+            port: port.parse().map_err(|_| format!("Invalid listener port: {}", port))?,
+            scope: ProxyFilter::from_str(scope)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Inclusive range of ports for one-listener-per-proxy mode, e.g. `10000-10499`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid port range, expected start-end: {}", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl PortRange {
+    /// Port of the upstream `addr`. Upstreams keep their port for the life of
+    /// the process, new ones take the lowest unassigned port and only reclaim
+    /// the port of an upstream that is no longer `known` once the range is full.
+    pub fn assign(
+        &self,
+        assignments: &mut HashMap<String, u16>,
+        addr: &str,
+        known: &HashSet<String>,
+    ) -> Option<u16> {
+        if let Some(port) = assignments.get(addr) {
+            return Some(*port);
+        }
+        let taken: HashSet<u16> = assignments.values().copied().collect();
+        let port = match (self.start..=self.end).find(|port| !taken.contains(port)) {
+            Some(port) => port,
+            None => {
+                let stale = assignments
+                    .iter()
+                    .filter(|(addr, _)| !known.contains(*addr))
+                    .min_by_key(|(_, port)| **port)
+                    .map(|(addr, port)| (addr.clone(), *port))?;
+                assignments.remove(&stale.0);
+                stale.1
+            }
+        };
+        assignments.insert(addr.to_string(), port);
+        Some(port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pool_and_listener() {
+        let pool = PoolSource::from_str("residential-us=resi.txt;type=residential;region=us").unwrap();
+        assert_eq!(pool.name.as_deref(), Some("residential-us"));
+        assert_eq!(pool.path, "resi.txt");
+        assert_eq!(pool.tags.get("region").map(String::as_str), Some("us"));
+        assert!(PoolSource::from_str("resi.txt").is_err());
+        assert!(PoolSource::from_str("resi=resi.txt;residential").is_err());
+
+        let listener = Listener::from_str("9002:tag.team=ads,pool=datacenter").unwrap();
+        assert_eq!(listener.port, 9002);
+        assert_eq!(listener.scope.pool.as_deref(), Some("datacenter"));
+        assert_eq!(listener.scope.tags, vec![("team".to_string(), "ads".to_string())]);
+        assert_eq!(Listener::from_str("9003").unwrap().scope, ProxyFilter::default());
+    }
+
+    #[test]
+    fn test_assign_ports() {
+        let range = PortRange::from_str("10000-10001").unwrap();
+        assert!(PortRange::from_str("10001-10000").is_err());
+        let mut assignments = HashMap::new();
+        let known: HashSet<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(range.assign(&mut assignments, "a", &known), Some(10000));
+        assert_eq!(range.assign(&mut assignments, "b", &known), Some(10001));
+        assert_eq!(range.assign(&mut assignments, "a", &known), Some(10000));
+        // full, every assigned upstream is still known
+        assert_eq!(range.assign(&mut assignments, "c", &known), None);
+
+        let known: HashSet<String> = ["b", "c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(range.assign(&mut assignments, "c", &known), Some(10000));
+        assert_eq!(range.assign(&mut assignments, "b", &known), Some(10001));
+    }
+}
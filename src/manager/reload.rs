@@ -0,0 +1,147 @@
+use crate::manager::pool::PoolSource;
+use crate::Proxy;
+use log::{error, info};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Identity of a pool entry, the same upstream may be listed in several pools.
+fn entry_key(proxy: &Proxy) -> (Option<&str>, String) {
+    (proxy.pool.as_deref(), proxy.addr())
+}
+
+pub fn same_entry(a: &Proxy, b: &Proxy) -> bool {
+    entry_key(a) == entry_key(b)
+}
+
+/// Entries of a freshly read list compared to the pool in use.
+#[derive(Debug, Default)]
+pub struct ListDiff {
+    /// Listed but not in use yet, they still need a check.
+    pub added: Vec<Proxy>,
+    /// In use but no longer listed.
+    pub removed: Vec<Proxy>,
+}
+
+pub fn diff(current: &[Proxy], listed: &[Proxy]) -> ListDiff {
+    let added = listed
+        .iter()
+        .filter(|l| !current.iter().any(|c| entry_key(c) == entry_key(l)))
+        .cloned()
+        .collect();
+    let removed = current
+        .iter()
+        .filter(|c| !listed.iter().any(|l| entry_key(c) == entry_key(l)))
+        .cloned()
+        .collect();
+    ListDiff { added, removed }
+}
+
+/// Take over credentials and metadata of the listed entries, the rest of the
+/// state (check results, position in the rotation) stays.
+pub fn refresh(current: &mut [Proxy], listed: &[Proxy]) {
+    for proxy in current.iter_mut() {
+        if let Some(entry) = listed.iter().find(|l| entry_key(l) == entry_key(proxy)) {
+            proxy.auth = entry.auth.clone();
+            proxy.tags = entry.tags.clone();
+            proxy.protocol = entry.protocol;
+            proxy.weight = entry.weight;
+            proxy.provider = entry.provider.clone();
+            proxy.expires_at = entry.expires_at;
+        }
+    }
+}
+
+/// Watch the directories of the proxy lists, editors often replace files
+/// instead of writing them in place. Every change to one of the lists yields
+/// a message, the watcher stops when it is dropped.
+pub fn watch(sources: &[PoolSource]) -> notify::Result<(RecommendedWatcher, UnboundedReceiver<()>)> {
+    let files: Vec<PathBuf> = sources
+        .iter()
+        .filter(|s| !s.is_remote())
+        .map(|s| absolute(&s.path))
+        .collect();
+    let (sender, receiver) = unbounded_channel();
+    let watched = files.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) if event.paths.iter().any(|p| watched.contains(p)) => {
+            let _ = sender.send(());
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to watch proxy lists: {}", e),
+    })?;
+    let mut dirs: Vec<&Path> = files.iter().filter_map(|f| f.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        info!("Watching {} for proxy list changes", dir.display());
+    }
+    Ok((watcher, receiver))
+}
+
+/// Next tick of `interval`, never without one.
+pub async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// A message on every SIGHUP.
+#[cfg(unix)]
+pub fn hangups() -> UnboundedReceiver<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (sender, receiver) = unbounded_channel();
+    match signal(SignalKind::hangup()) {
+        Ok(mut hangup) => {
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() && sender.send(()).is_ok() {}
+            });
+        }
+        Err(e) => error!("Failed to listen for SIGHUP: {}", e),
+    }
+    receiver
+}
+
+#[cfg(not(unix))]
+pub fn hangups() -> UnboundedReceiver<()> {
+    unbounded_channel().1
+}
+
+fn absolute(path: &str) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_diff_and_refresh() {
+        let mut current = vec![
+            Proxy::from_str("10.0.0.1:1080:user:old").unwrap(),
+            Proxy::from_str("10.0.0.2:1080").unwrap(),
+        ];
+        current[0].latency = std::time::Duration::from_millis(50);
+        let mut listed = vec![
+            Proxy::from_str("10.0.0.1:1080:user:new").unwrap(),
+            Proxy::from_str("10.0.0.3:1080").unwrap(),
+        ];
+
+        let changes = diff(&current, &listed);
+        assert_eq!(changes.added[0].addr(), "10.0.0.3:1080");
+        assert_eq!(changes.removed[0].addr(), "10.0.0.2:1080");
+
+        refresh(&mut current, &listed);
+        assert_eq!(current[0].auth.as_ref().unwrap().pass, "new");
+        assert_eq!(current[0].latency, std::time::Duration::from_millis(50));
+
+        // the same upstream moving to another pool is a new entry
+        listed[0].pool = Some("datacenter".to_string());
+        assert_eq!(diff(&current, &listed).added.len(), 2);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::server::Usage;
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use rand::Rng;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Cron expression, `min hour day month weekday` in UTC. A leading seconds
+/// field (six or seven fields) is accepted too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expr: String,
+    schedule: Schedule,
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = s.trim();
+        let full = match expr.split_whitespace().count() {
+            5 => format!("0 {}", expr),
+            _ => expr.to_string(),
+        };
+        let schedule = Schedule::from_str(&full).map_err(|e| format!("Invalid cron schedule {}: {}", s, e))?;
+        Ok(CronSchedule {
+            expr: expr.to_string(),
+            schedule,
+        })
+    }
+}
+
+impl Display for CronSchedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl CronSchedule {
+    /// Whether a scheduled time falls after `since` and not after `now`.
+    pub fn fired(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.schedule.after(&since).next().is_some_and(|next| next <= now)
+    }
+}
+
+/// What happens to the connections still open on the old proxy of a rotation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RotationMode {
+    /// They drain, up to the grace period when there is one.
+    #[default]
+    Soft,
+    /// They are closed, no traffic leaves through the old proxy afterwards.
+    Hard,
+}
+
+impl FromStr for RotationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "soft" => Ok(RotationMode::Soft),
+            "hard" => Ok(RotationMode::Hard),
+            _ => Err(format!("Unknown rotation mode: {}, expected soft or hard", s)),
+        }
+    }
+}
+
+impl Display for RotationMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RotationMode::Soft => write!(f, "soft"),
+            RotationMode::Hard => write!(f, "hard"),
+        }
+    }
+}
+
+/// What made a listener move to its next proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Interval,
+    Requests,
+    Bytes,
+    Failures,
+    Schedule,
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trigger::Interval => write!(f, "interval"),
+            Trigger::Requests => write!(f, "request count"),
+            Trigger::Bytes => write!(f, "bytes relayed"),
+            Trigger::Failures => write!(f, "upstream failures"),
+            Trigger::Schedule => write!(f, "schedule"),
+        }
+    }
+}
+
+/// Rotation triggers on top of the interval, the first one to fire rotates.
+/// Unset triggers of a listener fall back to the global ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    pub jitter: Option<u64>, //in seconds, the interval varies by up to this much either way
+    /// Connections through the proxy.
+    pub requests: Option<u64>,
+    /// Bytes relayed both ways through the proxy.
+    pub bytes: Option<u64>,
+    /// Consecutive connections the proxy failed to open.
+    pub failures: Option<u32>,
+    pub schedule: Option<CronSchedule>,
+    pub mode: Option<RotationMode>,
+    pub grace: Option<u64>, //in seconds, soft rotations close what is left after it
+}
+
+impl RotationPolicy {
+    /// This policy with the unset triggers taken from `fallback`.
+    pub fn or(&self, fallback: &RotationPolicy) -> RotationPolicy {
+        RotationPolicy {
+            jitter: self.jitter.or(fallback.jitter),
+            requests: self.requests.or(fallback.requests),
+            bytes: self.bytes.or(fallback.bytes),
+            failures: self.failures.or(fallback.failures),
+            schedule: self.schedule.clone().or_else(|| fallback.schedule.clone()),
+            mode: self.mode.or(fallback.mode),
+            grace: self.grace.or(fallback.grace),
+        }
+    }
+
+    /// When the connections left on the old proxy are closed, never for a
+    /// soft rotation without a grace period.
+    pub fn drain_after(&self) -> Option<Duration> {
+        match self.mode.unwrap_or_default() {
+            RotationMode::Hard => Some(Duration::ZERO),
+            RotationMode::Soft => self.grace.map(Duration::from_secs),
+        }
+    }
+
+    /// `interval` moved by a random amount within the jitter, at least a second.
+    pub fn jittered(&self, interval: i64) -> i64 {
+        match self.jitter.filter(|j| *j > 0 && interval > 0) {
+            Some(jitter) => {
+                let jitter = jitter as i64;
+                (interval + rand::thread_rng().gen_range(-jitter..=jitter)).max(1)
+            }
+            None => interval,
+        }
+    }
+
+    /// The trigger that fired for a proxy in use for `age`, `interval` is
+    /// already jittered and 0 disables it.
+    pub fn due(&self, interval: i64, age: Duration, usage: &Usage, now: DateTime<Utc>) -> Option<Trigger> {
+        if self.failures.is_some_and(|n| n > 0 && usage.failures >= n) {
+            return Some(Trigger::Failures);
+        }
+        if self.requests.is_some_and(|n| n > 0 && usage.connections >= n) {
+            return Some(Trigger::Requests);
+        }
+        if self.bytes.is_some_and(|n| n > 0 && usage.bytes >= n) {
+            return Some(Trigger::Bytes);
+        }
+        if interval > 0 && age.as_secs() >= interval as u64 {
+            return Some(Trigger::Interval);
+        }
+        let since = now - chrono::Duration::from_std(age).unwrap_or_default();
+        match &self.schedule {
+            Some(schedule) if schedule.fired(since, now) => Some(Trigger::Schedule),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triggers() {
+        let policy = RotationPolicy {
+            requests: Some(10),
+            failures: Some(3),
+            schedule: Some(CronSchedule::from_str("0 */6 * * *").unwrap()),
+            ..Default::default()
+        };
+        let now: DateTime<Utc> = "2024-05-01T11:59:00Z".parse().unwrap();
+        let idle = Usage::default();
+        let minute = Duration::from_secs(60);
+        assert_eq!(policy.due(0, minute, &idle, now), None);
+        assert_eq!(policy.due(30, minute, &idle, now), Some(Trigger::Interval));
+        let busy = Usage {
+            connections: 10,
+            ..Default::default()
+        };
+        assert_eq!(policy.due(0, minute, &busy, now), Some(Trigger::Requests));
+        let failing = Usage {
+            connections: 10,
+            failures: 3,
+            ..Default::default()
+        };
+        assert_eq!(policy.due(0, minute, &failing, now), Some(Trigger::Failures));
+        // 12:00 passed since the proxy was set
+        let later = now + chrono::Duration::minutes(2);
+        assert_eq!(policy.due(0, Duration::from_secs(90), &idle, later), Some(Trigger::Schedule));
+        assert_eq!(policy.due(0, Duration::from_secs(30), &idle, later), None);
+        assert!(CronSchedule::from_str("every hour").is_err());
+
+        let jittered = RotationPolicy {
+            jitter: Some(30),
+            ..Default::default()
+        };
+        let interval = jittered.jittered(300);
+        assert!((270..=330).contains(&interval));
+        assert_eq!(jittered.jittered(0), 0);
+        assert_eq!(policy.or(&jittered).jitter, Some(30));
+
+        assert_eq!(policy.drain_after(), None);
+        let soft = RotationPolicy {
+            grace: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(soft.drain_after(), Some(Duration::from_secs(60)));
+        let hard = RotationPolicy {
+            mode: Some(RotationMode::from_str("hard").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(hard.or(&soft).drain_after(), Some(Duration::ZERO));
+    }
+}
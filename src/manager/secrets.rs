@@ -0,0 +1,124 @@
+use crate::ProxyAuth;
+use age::armor::ArmoredReader;
+use age::secrecy::SecretString;
+use age::{Decryptor, Identity, IdentityFile};
+use std::io::Read;
+
+const AGE_HEADER: &[u8] = b"age-encryption.org/";
+const AGE_ARMOR: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Unlocks proxy lists encrypted with age, to a passphrase or to the
+/// identities of a key file.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    passphrase: Option<String>,
+    key_file: Option<String>,
+}
+
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secrets")
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "***"))
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+impl Secrets {
+    pub fn new(passphrase: Option<String>, key_file: Option<String>) -> Self {
+        Secrets { passphrase, key_file }
+    }
+
+    /// A credential as written in a list or a flag: `${env:NAME}` reads an
+    /// environment variable, `${file:PATH}` a secret file (trailing newline
+    /// dropped), anything else is the value itself.
+    pub fn resolve(value: &str) -> Result<String, String> {
+        let Some(reference) = value.strip_prefix("${").and_then(|v| v.strip_suffix('}')) else {
+            return Ok(value.to_string());
+        };
+        match reference.split_once(':') {
+            Some(("env", name)) => std::env::var(name).map_err(|_| format!("Environment variable {} is not set", name)),
+            Some(("file", path)) => std::fs::read_to_string(path)
+                .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| format!("Failed to read secret file {}: {}", path, e)),
+            _ => Err(format!("Unknown secret reference: {}", value)),
+        }
+    }
+
+    /// `auth` with its references resolved.
+    pub fn resolve_auth(auth: &ProxyAuth) -> Result<ProxyAuth, String> {
+        Ok(ProxyAuth {
+            user: Secrets::resolve(&auth.user)?,
+            pass: Secrets::resolve(&auth.pass)?,
+        })
+    }
+
+    pub fn is_encrypted(body: &[u8]) -> bool {
+        body.starts_with(AGE_HEADER) || body.starts_with(AGE_ARMOR)
+    }
+
+    /// The text of a list, decrypted when it is an age file.
+    pub fn unlock(&self, body: Vec<u8>) -> Result<String, String> {
+        if !Secrets::is_encrypted(&body) {
+            return String::from_utf8(body).map_err(|e| e.to_string());
+        }
+        let decryptor = Decryptor::new(ArmoredReader::new(body.as_slice())).map_err(|e| e.to_string())?;
+        let identities: Vec<Box<dyn Identity>> = if decryptor.is_scrypt() {
+            let passphrase = self
+                .passphrase
+                .as_deref()
+                .ok_or("The list is encrypted to a passphrase, none is set")?;
+            let passphrase = SecretString::from(Secrets::resolve(passphrase)?);
+            vec![Box::new(age::scrypt::Identity::new(passphrase))]
+        } else {
+            let key_file = self.key_file.clone().ok_or("The list is encrypted to a key, no key file is set")?;
+            IdentityFile::from_file(key_file.clone())
+                .map_err(|e| format!("Failed to read key file {}: {}", key_file, e))?
+                .into_identities()
+                .map_err(|e| e.to_string())?
+        };
+        let mut reader = decryptor
+            .decrypt(identities.iter().map(|i| i.as_ref()))
+            .map_err(|e| format!("Failed to decrypt the list: {}", e))?;
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(|e| e.to_string())?;
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::io::Write;
+
+    #[test]
+    fn test_resolve_and_unlock() {
+        let dir = std::env::temp_dir().join(format!("qproxy-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("pass");
+        std::fs::write(&secret, "s3cret\n").unwrap();
+        std::env::set_var("QPROXY_TEST_SECRET", "from-env");
+        assert_eq!(Secrets::resolve("plain").unwrap(), "plain");
+        assert_eq!(Secrets::resolve("${env:QPROXY_TEST_SECRET}").unwrap(), "from-env");
+        assert_eq!(Secrets::resolve(&format!("${{file:{}}}", secret.display())).unwrap(), "s3cret");
+        assert!(Secrets::resolve("${env:QPROXY_TEST_UNSET}").is_err());
+        assert!(Secrets::resolve("${vault:x}").is_err());
+
+        let identity = age::x25519::Identity::generate();
+        let key_file = dir.join("key.txt");
+        std::fs::write(&key_file, identity.to_string().expose_secret()).unwrap();
+        let recipient = identity.to_public();
+        let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as _)).unwrap();
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted).unwrap();
+        writer.write_all(b"10.0.0.1:1080:user:pass\n").unwrap();
+        writer.finish().unwrap();
+
+        assert!(Secrets::default().unlock(encrypted.clone()).is_err());
+        let secrets = Secrets::new(None, Some(key_file.display().to_string()));
+        assert_eq!(secrets.unlock(encrypted).unwrap(), "10.0.0.1:1080:user:pass\n");
+        assert_eq!(secrets.unlock(b"10.0.0.2:1080".to_vec()).unwrap(), "10.0.0.2:1080");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
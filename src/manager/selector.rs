@@ -0,0 +1,618 @@
+use crate::manager::bans::BanTracker;
+use crate::manager::health::{apply_transition, HealthTracker};
+use crate::{Anonymity, DestinationOutcome, Proxy, SessionParams, UpstreamSelector};
+use log::warn;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Constraints an upstream must satisfy to be selected.
+///
+/// Parsed from comma separated terms, e.g.
+/// `country=US,asn!=AS16509,anonymity=elite,exclude-blocklisted,tag.team=ads`.
+/// `country` and `asn` accept `=` and `!=`, `pool` and `tag.<key>` only `=`,
+/// `anonymity` is a minimum level.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyFilter {
+    pub pool: Option<String>,
+    pub country: Option<String>,
+    pub min_anonymity: Option<Anonymity>,
+    pub exclude_countries: Vec<String>,
+    pub asn: Option<u32>,
+    pub exclude_asns: Vec<u32>,
+    pub exclude_blocklisted: bool,
+    /// Tags the proxy must carry with these values.
+    pub tags: Vec<(String, String)>,
+}
+
+fn parse_asn(value: &str) -> Result<u32, String> {
+    let digits = value
+        .strip_prefix("AS")
+        .or_else(|| value.strip_prefix("as"))
+        .unwrap_or(value);
+    digits.parse().map_err(|_| format!("Invalid ASN: {}", value))
+}
+
+impl FromStr for ProxyFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = ProxyFilter::default();
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if term == "exclude-blocklisted" {
+                filter.exclude_blocklisted = true;
+                continue;
+            }
+            let (key, negated, value) = match term.split_once("!=") {
+                Some((key, value)) => (key, true, value),
+                None => match term.split_once('=') {
+                    Some((key, value)) => (key, false, value),
+                    None => return Err(format!("Invalid filter term: {}", term)),
+                },
+            };
+            match (key.trim(), negated) {
+                ("pool", false) => filter.pool = Some(value.to_string()),
+                ("country", false) => filter.country = Some(value.to_ascii_uppercase()),
+                ("country", true) => filter.exclude_countries.push(value.to_ascii_uppercase()),
+                ("asn", false) => filter.asn = Some(parse_asn(value)?),
+                ("asn", true) => filter.exclude_asns.push(parse_asn(value)?),
+                ("anonymity", false) => filter.min_anonymity = Some(Anonymity::from_str(value)?),
+                (key, false) if key.starts_with("tag.") && key.len() > 4 => {
+                    filter.tags.push((key[4..].to_string(), value.to_string()))
+                }
+                _ => return Err(format!("Invalid filter term: {}", term)),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl Display for ProxyFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut terms = Vec::new();
+        if let Some(pool) = &self.pool {
+            terms.push(format!("pool={}", pool));
+        }
+        if let Some(country) = &self.country {
+            terms.push(format!("country={}", country));
+        }
+        terms.extend(self.exclude_countries.iter().map(|c| format!("country!={}", c)));
+        if let Some(asn) = self.asn {
+            terms.push(format!("asn=AS{}", asn));
+        }
+        terms.extend(self.exclude_asns.iter().map(|asn| format!("asn!=AS{}", asn)));
+        if let Some(level) = self.min_anonymity {
+            terms.push(format!("anonymity={}", level));
+        }
+        if self.exclude_blocklisted {
+            terms.push("exclude-blocklisted".to_string());
+        }
+        terms.extend(self.tags.iter().map(|(k, v)| format!("tag.{}={}", k, v)));
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+impl ProxyFilter {
+    /// Fill the constraints left open with the ones of `base`, exclusions add up.
+    pub fn or(self, base: &ProxyFilter) -> ProxyFilter {
+        ProxyFilter {
+            pool: self.pool.or_else(|| base.pool.clone()),
+            country: self.country.or_else(|| base.country.clone()),
+            min_anonymity: self.min_anonymity.or(base.min_anonymity),
+            exclude_countries: [self.exclude_countries, base.exclude_countries.clone()].concat(),
+            asn: self.asn.or(base.asn),
+            exclude_asns: [self.exclude_asns, base.exclude_asns.clone()].concat(),
+            exclude_blocklisted: self.exclude_blocklisted || base.exclude_blocklisted,
+            tags: [self.tags, base.tags.clone()].concat(),
+        }
+    }
+
+    pub fn matches(&self, proxy: &Proxy) -> bool {
+        // neither does an expired rental or a paused proxy
+        if proxy.is_expired() || proxy.paused {
+            return false;
+        }
+        if let Some(pool) = &self.pool {
+            if proxy.pool.as_ref() != Some(pool) {
+                return false;
+            }
+        }
+        if let Some(country) = &self.country {
+            match &proxy.country {
+                Some(c) if c.eq_ignore_ascii_case(country) => {}
+                _ => return false,
+            }
+        }
+        if let Some(country) = &proxy.country {
+            if self.exclude_countries.iter().any(|c| c.eq_ignore_ascii_case(country)) {
+                return false;
+            }
+        }
+        if self.asn.is_some() && proxy.asn != self.asn {
+            return false;
+        }
+        if proxy.asn.is_some_and(|asn| self.exclude_asns.contains(&asn)) {
+            return false;
+        }
+        if self.exclude_blocklisted && proxy.blocklisted {
+            return false;
+        }
+        if !self.tags.iter().all(|(k, v)| proxy.tags.get(k) == Some(v)) {
+            return false;
+        }
+        if let Some(min) = self.min_anonymity {
+            // unclassified proxies don't satisfy a requirement
+            if proxy.anonymity.is_none_or(|level| level < min) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// How an upstream is picked among the ones matching a selection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Weighted round-robin.
+    #[default]
+    RoundRobin,
+    /// Weighted random.
+    Random,
+    /// Lowest check latency first.
+    Latency,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "latency" => Ok(Strategy::Latency),
+            _ => Err(format!("Unknown strategy, expected round-robin, random or latency: {}", s)),
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::Random => write!(f, "random"),
+            Strategy::Latency => write!(f, "latency"),
+        }
+    }
+}
+
+impl Strategy {
+    /// One of `candidates`, `cursor` counts the picks of a round-robin.
+    pub fn pick<'a>(&self, candidates: &[&'a Proxy], cursor: &AtomicUsize) -> Option<&'a Proxy> {
+        let total: u64 = candidates.iter().map(|p| p.weight() as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut slot = match self {
+            Strategy::Latency => return candidates.iter().min_by_key(|p| p.latency).copied(),
+            Strategy::RoundRobin => cursor.fetch_add(1, Ordering::Relaxed) as u64 % total,
+            Strategy::Random => rand::thread_rng().gen_range(0..total),
+        };
+        for proxy in candidates {
+            if slot < proxy.weight() as u64 {
+                return Some(proxy);
+            }
+            slot -= proxy.weight() as u64;
+        }
+        None
+    }
+}
+
+impl From<&SessionParams> for ProxyFilter {
+    fn from(params: &SessionParams) -> Self {
+        ProxyFilter {
+            pool: params.pool.clone(),
+            country: params.country.clone(),
+            min_anonymity: params.anonymity,
+            ..Default::default()
+        }
+    }
+}
+
+/// Identity a proxy is rotated by: its egress IP when deduplication is on and
+/// the egress is known, its own address otherwise.
+pub fn rotation_key(proxy: &Proxy, dedupe_egress: bool) -> String {
+    match &proxy.egress_ip {
+        Some(ip) if dedupe_egress => ip.clone(),
+        _ => proxy.addr(),
+    }
+}
+
+/// Keep the first proxy of every rotation key.
+pub fn distinct_egress(proxies: Vec<&Proxy>, dedupe_egress: bool) -> Vec<&Proxy> {
+    let mut seen = std::collections::HashSet::new();
+    proxies
+        .into_iter()
+        .filter(|p| seen.insert(rotation_key(p, dedupe_egress)))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    proxy: Proxy,
+    expires_at: Instant,
+}
+
+/// Selects upstreams for connections carrying session parameters and keeps
+/// sessions pinned to the same upstream for their lifetime.
+#[derive(Debug)]
+pub struct SessionRouter {
+    proxies: Arc<Mutex<Vec<Proxy>>>,
+    health: Arc<HealthTracker>,
+    bans: Arc<BanTracker>,
+    sessions: Mutex<HashMap<String, Session>>,
+    default_lifetime: Duration,
+    /// Longest lifetime a session parameter can ask for.
+    max_lifetime: Duration,
+    cursor: AtomicUsize,
+    dedupe_egress: bool,
+    base: ProxyFilter,
+    scope: ProxyFilter,
+    strategy: Strategy,
+}
+
+impl SessionRouter {
+    pub fn new(
+        proxies: Arc<Mutex<Vec<Proxy>>>,
+        health: Arc<HealthTracker>,
+        default_lifetime: Duration,
+    ) -> Self {
+        SessionRouter {
+            proxies,
+            health,
+            bans: Arc::new(BanTracker::default()),
+            sessions: Mutex::new(HashMap::new()),
+            default_lifetime,
+            max_lifetime: Duration::MAX,
+            cursor: AtomicUsize::new(0),
+            dedupe_egress: false,
+            base: ProxyFilter::default(),
+            scope: ProxyFilter::default(),
+            strategy: Strategy::default(),
+        }
+    }
+
+    /// A router over the same proxies restricted to `scope`, with sessions of
+    /// its own. Unlike the base filter the scope can't be widened by session
+    /// parameters, so listeners bound to different pools never share upstreams.
+    pub fn scoped(&self, scope: ProxyFilter) -> Self {
+        SessionRouter {
+            proxies: self.proxies.clone(),
+            health: self.health.clone(),
+            bans: self.bans.clone(),
+            sessions: Mutex::new(HashMap::new()),
+            default_lifetime: self.default_lifetime,
+            max_lifetime: self.max_lifetime,
+            cursor: AtomicUsize::new(0),
+            dedupe_egress: self.dedupe_egress,
+            base: self.base.clone(),
+            scope,
+            strategy: self.strategy,
+        }
+    }
+
+    /// Constraints applied to every selection, session parameters take precedence.
+    pub fn with_filter(mut self, base: ProxyFilter) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Cooldowns of proxies that look banned by a destination, shared by the
+    /// scoped routers.
+    pub fn with_bans(mut self, bans: Arc<BanTracker>) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    /// Cap the lifetimes session parameters ask for.
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Treat proxies sharing an egress IP as a single upstream.
+    pub fn with_egress_dedupe(mut self, dedupe_egress: bool) -> Self {
+        self.dedupe_egress = dedupe_egress;
+        self
+    }
+
+    /// One of the working `proxies` matching `filter`, picked by the strategy.
+    /// Proxies cooling down for `host` are left out.
+    fn pick(&self, proxies: &[Proxy], filter: &ProxyFilter, host: Option<&str>) -> Option<Proxy> {
+        let candidates: Vec<&Proxy> = proxies
+            .iter()
+            .filter(|p| p.is_working && self.scope.matches(p) && filter.matches(p))
+            .filter(|p| host.is_none_or(|host| !self.bans.is_cooling(&p.addr(), host)))
+            .collect();
+        let candidates = distinct_egress(candidates, self.dedupe_egress);
+        self.strategy.pick(&candidates, &self.cursor).cloned()
+    }
+
+    /// Number of sessions currently pinned to an upstream.
+    pub fn active_sessions(&self) -> usize {
+        let now = Instant::now();
+        let sessions = self.sessions.lock().unwrap();
+        sessions.values().filter(|s| s.expires_at > now).count()
+    }
+
+    /// Upstream of a connection, re-pinning sessions whose upstream is cooling
+    /// down for `host`.
+    fn route(&self, params: &SessionParams, host: Option<&str>) -> Option<Proxy> {
+        let filter = ProxyFilter::from(params).or(&self.base);
+        // always locked before the sessions
+        let proxies = self.proxies.lock().unwrap();
+        let Some(id) = &params.session else {
+            return self.pick(&proxies, &filter, host);
+        };
+        let key = format!("{}-{}", params.user, id);
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires_at > now);
+        if let Some(session) = sessions.get(&key) {
+            // sessions stay pinned unless their upstream got ejected, removed
+            // or banned by where they are going
+            let banned = host.is_some_and(|host| self.bans.is_cooling(&session.proxy.addr(), host));
+            let listed = proxies.iter().any(|p| p.addr() == session.proxy.addr());
+            if !self.health.state(&session.proxy).ejected && listed && !banned {
+                return Some(session.proxy.clone());
+            }
+        }
+        let proxy = self.pick(&proxies, &filter, host)?;
+        let lifetime = params.lifetime.unwrap_or(self.default_lifetime).min(self.max_lifetime);
+        sessions.insert(
+            key,
+            Session {
+                proxy: proxy.clone(),
+                // past the end of time the session is not kept
+                expires_at: now.checked_add(lifetime).unwrap_or(now),
+            },
+        );
+        Some(proxy)
+    }
+}
+
+impl UpstreamSelector for SessionRouter {
+    fn select(&self, params: &SessionParams) -> Option<Proxy> {
+        self.route(params, None)
+    }
+
+    fn select_for(&self, params: &SessionParams, host: &str) -> Option<Proxy> {
+        self.route(params, Some(host))
+    }
+
+    fn report(&self, proxy: &Proxy, ok: bool) {
+        if let Some(transition) = self.health.record(proxy, ok) {
+            warn!("Circuit breaker {:?} proxy: {}", transition, proxy);
+            apply_transition(&mut self.proxies.lock().unwrap(), proxy, transition);
+        }
+    }
+
+    fn report_destination(&self, proxy: &Proxy, host: &str, outcome: DestinationOutcome) {
+        self.bans.record(&proxy.addr(), host, &outcome);
+    }
+
+    fn is_cooling(&self, proxy: &Proxy, host: &str) -> bool {
+        self.bans.is_cooling(&proxy.addr(), host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::health::HealthConfig;
+
+    fn proxy(ip: &str, country: &str) -> Proxy {
+        Proxy {
+            ip: ip.to_string(),
+            port: 1080,
+            country: Some(country.to_string()),
+            is_working: true,
+            ..Default::default()
+        }
+    }
+
+    // selecting must not block the runtime it may be called from
+    #[tokio::test]
+    async fn test_sticky_session() {
+        let proxies = vec![proxy("10.0.0.1", "DE"), proxy("10.0.0.2", "DE"), proxy("10.0.0.3", "US")];
+        let router = SessionRouter::new(
+            Arc::new(Mutex::new(proxies)),
+            Arc::new(HealthTracker::default()),
+            Duration::from_secs(60),
+        );
+
+        let a = SessionParams::from_str("user-session-a-country-de").unwrap();
+        let first = router.select(&a).unwrap();
+        assert_eq!(first.country.as_deref(), Some("DE"));
+        for _ in 0..5 {
+            assert_eq!(router.select(&a).unwrap(), first);
+        }
+
+        let b = SessionParams::from_str("user-session-b-country-de").unwrap();
+        assert_ne!(router.select(&b).unwrap(), first);
+        assert_eq!(router.active_sessions(), 2);
+
+        let fr = SessionParams::from_str("user-session-c-country-fr").unwrap();
+        assert!(router.select(&fr).is_none());
+
+        let expired = SessionParams::from_str("user-session-d-lifetime-0s").unwrap();
+        router.select(&expired).unwrap();
+        let endless = SessionParams::from_str("user-session-e-lifetime-18446744073709551615s").unwrap();
+        router.select(&endless).unwrap();
+        assert_eq!(router.active_sessions(), 2);
+
+        // tripping the breaker moves the session to another upstream
+        for _ in 0..HealthConfig::default().eject_after {
+            router.report(&first, false);
+        }
+        let moved = router.select(&a).unwrap();
+        assert_ne!(moved, first);
+        assert_eq!(moved.country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn test_destination_cooldown() {
+        let proxies = vec![proxy("10.0.0.1", "DE"), proxy("10.0.0.2", "DE")];
+        let bans = BanTracker::new(crate::BanConfig {
+            threshold: 1,
+            ..Default::default()
+        });
+        let router = SessionRouter::new(
+            Arc::new(Mutex::new(proxies)),
+            Arc::new(HealthTracker::default()),
+            Duration::from_secs(60),
+        )
+        .with_bans(Arc::new(bans));
+
+        let a = SessionParams::from_str("user-session-a").unwrap();
+        let first = router.select_for(&a, "shop.example").unwrap();
+        router.report_destination(&first, "shop.example", DestinationOutcome::Reset);
+        assert!(router.is_cooling(&first, "shop.example"));
+        // the session moves for the site that banned its proxy only
+        let moved = router.select_for(&a, "shop.example").unwrap();
+        assert_ne!(moved, first);
+        for _ in 0..3 {
+            let any = SessionParams::from_str("user").unwrap();
+            assert_eq!(router.select_for(&any, "shop.example").unwrap(), moved);
+        }
+        assert!(!router.is_cooling(&first, "news.example"));
+    }
+
+    #[test]
+    fn test_distinct_egress() {
+        let mut proxies = [proxy("10.0.0.1", "DE"), proxy("10.0.0.2", "DE"), proxy("10.0.0.3", "DE")];
+        proxies[0].egress_ip = Some("203.0.113.1".to_string());
+        proxies[1].egress_ip = Some("203.0.113.1".to_string());
+        proxies[2].egress_ip = Some("203.0.113.2".to_string());
+        let refs: Vec<&Proxy> = proxies.iter().collect();
+        assert_eq!(distinct_egress(refs.clone(), false).len(), 3);
+        let distinct = distinct_egress(refs, true);
+        assert_eq!(distinct.len(), 2);
+        assert_ne!(distinct[0].egress_ip, distinct[1].egress_ip);
+    }
+
+    #[test]
+    fn test_min_anonymity() {
+        let mut elite = proxy("10.0.0.1", "DE");
+        elite.anonymity = Some(Anonymity::Elite);
+        let mut transparent = proxy("10.0.0.2", "DE");
+        transparent.anonymity = Some(Anonymity::Transparent);
+        let unknown = proxy("10.0.0.3", "DE");
+
+        let filter = ProxyFilter {
+            min_anonymity: Some(Anonymity::Anonymous),
+            ..Default::default()
+        };
+        assert!(filter.matches(&elite));
+        assert!(!filter.matches(&transparent));
+        assert!(!filter.matches(&unknown));
+
+        let session = ProxyFilter::from(&SessionParams::from_str("user-country-us").unwrap());
+        assert_eq!(session.or(&filter).min_anonymity, Some(Anonymity::Anonymous));
+    }
+
+    #[test]
+    fn test_scoped_router() {
+        let mut proxies = vec![proxy("10.0.0.1", "DE"), proxy("10.0.0.2", "DE"), proxy("10.0.0.3", "DE")];
+        proxies[0].pool = Some("datacenter".to_string());
+        proxies[1].pool = Some("residential".to_string());
+        proxies[2].pool = Some("residential".to_string());
+        proxies[2].tags.insert("team".to_string(), "ads".to_string());
+        let router = SessionRouter::new(
+            Arc::new(Mutex::new(proxies)),
+            Arc::new(HealthTracker::default()),
+            Duration::from_secs(60),
+        );
+
+        let datacenter = router.scoped(ProxyFilter::from_str("pool=datacenter").unwrap());
+        let any = SessionParams::from_str("user-session-a").unwrap();
+        for _ in 0..3 {
+            assert_eq!(datacenter.select(&any).unwrap().ip, "10.0.0.1");
+        }
+        // session parameters can't leave the listener's pool
+        let residential = SessionParams::from_str("user-pool-residential").unwrap();
+        assert!(datacenter.select(&residential).is_none());
+
+        let filter = "pool=residential,country!=FR,asn=AS64500,exclude-blocklisted,tag.team=ads";
+        assert_eq!(ProxyFilter::from_str(filter).unwrap().to_string(), filter);
+
+        let ads = router.scoped(ProxyFilter::from_str("tag.team=ads").unwrap());
+        assert_eq!(ads.select(&residential).unwrap().ip, "10.0.0.3");
+    }
+
+    #[test]
+    fn test_weight_and_expiry() {
+        let mut proxies = vec![proxy("10.0.0.1", "DE"), proxy("10.0.0.2", "DE"), proxy("10.0.0.3", "DE")];
+        proxies[0].weight = Some(3);
+        proxies[2].expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(1));
+        let router = SessionRouter::new(
+            Arc::new(Mutex::new(proxies)),
+            Arc::new(HealthTracker::default()),
+            Duration::from_secs(60),
+        );
+        let params = SessionParams::from_str("user-country-de").unwrap();
+        let picks: Vec<String> = (0..8).map(|_| router.select(&params).unwrap().ip).collect();
+        assert_eq!(picks.iter().filter(|ip| *ip == "10.0.0.1").count(), 6);
+        assert_eq!(picks.iter().filter(|ip| *ip == "10.0.0.2").count(), 2);
+    }
+
+    #[test]
+    fn test_filter_expression() {
+        let filter = ProxyFilter::from_str("country=us, asn!=AS16509, exclude-blocklisted").unwrap();
+        assert_eq!(filter.country.as_deref(), Some("US"));
+        assert_eq!(filter.exclude_asns, vec![16509]);
+
+        let mut p = proxy("10.0.0.1", "US");
+        assert!(filter.matches(&p));
+        p.asn = Some(16509);
+        assert!(!filter.matches(&p));
+        p.asn = Some(7922);
+        p.blocklisted = true;
+        assert!(!filter.matches(&p));
+
+        let no_cn = ProxyFilter::from_str("country!=cn").unwrap();
+        assert!(!no_cn.matches(&proxy("10.0.0.2", "CN")));
+        assert!(no_cn.matches(&proxy("10.0.0.2", "DE")));
+
+        assert!(ProxyFilter::from_str("colour=red").is_err());
+        assert!(ProxyFilter::from_str("asn=amazon").is_err());
+    }
+
+    #[test]
+    fn test_strategies() {
+        let mut proxies = vec![proxy("10.0.0.1", "DE"), proxy("10.0.0.2", "DE"), proxy("10.0.0.3", "DE")];
+        proxies[0].latency = Duration::from_millis(300);
+        proxies[1].latency = Duration::from_millis(100);
+        proxies[2].latency = Duration::from_millis(200);
+        let router = SessionRouter::new(
+            Arc::new(Mutex::new(proxies)),
+            Arc::new(HealthTracker::default()),
+            Duration::from_secs(60),
+        )
+        .with_strategy(Strategy::from_str("latency").unwrap());
+        let params = SessionParams::from_str("user-country-de").unwrap();
+        assert_eq!(router.select(&params).unwrap().ip, "10.0.0.2");
+
+        let random = router.scoped(ProxyFilter::default()).with_strategy(Strategy::Random);
+        assert!(random.select(&params).is_some());
+        assert!(Strategy::from_str("fastest").is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use crate::errors::ProxyError;
+use crate::manager::pool::PoolSource;
+use crate::manager::secrets::Secrets;
+use crate::{Protocol, Proxy, ProxyAuth};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{error, warn};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Layout of a proxy list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// One `ip:port[:user:pass]` or `scheme://user:pass@ip:port` per line,
+    /// `#` starts a comment.
+    Text,
+    /// An array of `ip:port` strings or of objects.
+    Json,
+    /// A header row naming the fields, one proxy per row.
+    Csv,
+    /// Like JSON.
+    Yaml,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            "csv" => Ok(ListFormat::Csv),
+            "yaml" | "yml" => Ok(ListFormat::Yaml),
+            _ => Err(format!("Unknown list format: {}", s)),
+        }
+    }
+}
+
+impl ListFormat {
+    /// Guess the format from a content type or a path, plain text otherwise.
+    /// The `.age` extension of encrypted lists is skipped.
+    pub fn detect(content_type: Option<&str>, path: &str) -> ListFormat {
+        let content_type = content_type.unwrap_or_default();
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let path = path.strip_suffix(".age").unwrap_or(path);
+        if content_type.contains("json") || path.ends_with(".json") {
+            ListFormat::Json
+        } else if content_type.contains("csv") || path.ends_with(".csv") {
+            ListFormat::Csv
+        } else if content_type.contains("yaml") || path.ends_with(".yaml") || path.ends_with(".yml") {
+            ListFormat::Yaml
+        } else {
+            ListFormat::Text
+        }
+    }
+}
+
+fn text(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) if s.is_empty() => None,
+        Value::String(s) => Some(s),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// Tags as an object, or as `key=value` pairs separated by `;` in CSV.
+fn tags(value: Value) -> Result<BTreeMap<String, String>, String> {
+    match value {
+        Value::Object(map) => Ok(map.into_iter().filter_map(|(k, v)| Some((k, text(v)?))).collect()),
+        Value::String(s) => s
+            .split(';')
+            .filter(|t| !t.trim().is_empty())
+            .map(|t| {
+                t.split_once('=')
+                    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                    .ok_or_else(|| format!("Invalid tag, expected key=value: {}", t))
+            })
+            .collect(),
+        Value::Null => Ok(BTreeMap::new()),
+        other => Err(format!("Invalid tags: {}", other)),
+    }
+}
+
+/// RFC 3339 timestamp or a bare date, which expires at its start (UTC).
+fn expiry(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|at| at.with_timezone(&Utc))
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|day| day.and_time(Default::default()).and_utc()))
+        .map_err(|_| format!("Invalid expiry date: {}", value))
+}
+
+/// Build a proxy from a record, `field` maps our field names to the source's.
+fn from_record(source: &PoolSource, get: impl Fn(&str) -> Option<Value>) -> Result<Proxy, String> {
+    let value = |name: &str| get(source.field(name)).and_then(text);
+    let ip = value("ip").ok_or("Missing proxy ip")?;
+    let mut proxy = match value("port") {
+        Some(port) => Proxy {
+            port: port.parse().map_err(|_| format!("Invalid proxy port: {}", port))?,
+            ip,
+            used: true,
+            ..Default::default()
+        },
+        // a combined `ip:port[:user:pass]` in the ip field
+        None => Proxy::from_str(&ip)?,
+    };
+    if let (Some(user), Some(pass)) = (value("user"), value("pass")) {
+        proxy.auth = Some(ProxyAuth { user, pass });
+    }
+    if let Some(protocol) = value("protocol") {
+        proxy.protocol = Protocol::from_str(&protocol)?;
+    }
+    if let Some(weight) = value("weight") {
+        proxy.weight = Some(weight.parse().map_err(|_| format!("Invalid weight: {}", weight))?);
+    }
+    if let Some(expires) = value("expires") {
+        proxy.expires_at = Some(expiry(&expires)?);
+    }
+    proxy.country = value("country").map(|c| c.to_ascii_uppercase());
+    proxy.provider = value("provider");
+    proxy.tags = tags(get(source.field("tags")).unwrap_or(Value::Null))?;
+    Ok(proxy)
+}
+
+/// Resolve the `${env:NAME}` and `${file:PATH}` credentials of an entry.
+fn resolve_auth(mut proxy: Proxy) -> Result<Proxy, String> {
+    if let Some(auth) = &proxy.auth {
+        proxy.auth = Some(Secrets::resolve_auth(auth)?);
+    }
+    Ok(proxy)
+}
+
+/// A line of a text list without its comment, which starts with a `#` at
+/// the start of the line or after whitespace. Passwords may contain `#`.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '#' && previous.is_whitespace() {
+            return line[..i].trim();
+        }
+        previous = c;
+    }
+    line.trim()
+}
+
+/// Parse a list body, invalid entries are logged and skipped.
+pub fn parse_list(body: &str, format: ListFormat, source: &PoolSource) -> Result<Vec<Proxy>, String> {
+    let entries: Vec<Result<Proxy, String>> = match format {
+        ListFormat::Text => body
+            .lines()
+            .map(strip_comment)
+            .filter(|line| !line.is_empty())
+            .map(Proxy::from_str)
+            .collect(),
+        ListFormat::Json | ListFormat::Yaml => {
+            let document: Value = match format {
+                ListFormat::Yaml => serde_yaml::from_str(body).map_err(|e| e.to_string())?,
+                _ => serde_json::from_str(body).map_err(|e| e.to_string())?,
+            };
+            let root = match &source.root {
+                Some(pointer) => document
+                    .pointer(pointer)
+                    .ok_or_else(|| format!("No {} in the document", pointer))?,
+                None => &document,
+            };
+            root.as_array()
+                .ok_or("Expected an array of proxies")?
+                .iter()
+                .map(|entry| match entry {
+                    Value::String(s) => Proxy::from_str(s),
+                    entry => from_record(source, |name| entry.get(name).cloned()),
+                })
+                .collect()
+        }
+        ListFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .comment(Some(b'#'))
+                .from_reader(body.as_bytes());
+            let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+            reader
+                .records()
+                .map(|record| {
+                    let record = record.map_err(|e| e.to_string())?;
+                    from_record(source, |name| {
+                        let i = headers.iter().position(|h| h == name)?;
+                        record.get(i).map(|v| Value::String(v.to_string()))
+                    })
+                })
+                .collect()
+        }
+    };
+    Ok(entries
+        .into_iter()
+        .map(|entry| entry.and_then(resolve_auth))
+        .filter_map(|entry| entry.map_err(|e| error!("Failed to parse proxy: {}", e)).ok())
+        .collect())
+}
+
+#[derive(Debug, Clone)]
+struct Fetched {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    at: Instant,
+    proxies: Vec<Proxy>,
+}
+
+/// Fetches remote lists and remembers the last answer of every URL, for
+/// conditional requests and to ride out provider outages.
+#[derive(Debug, Clone, Default)]
+pub struct Fetcher {
+    client: reqwest::Client,
+    cache: Arc<Mutex<HashMap<String, Fetched>>>,
+    secrets: Secrets,
+}
+
+impl Fetcher {
+    /// Decrypt encrypted lists with `secrets`.
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = secrets;
+        self
+    }
+
+    /// The proxies of a local list.
+    pub async fn read(&self, source: &PoolSource) -> Result<Vec<Proxy>, ProxyError> {
+        let body = tokio::fs::read(&source.path)
+            .await
+            .map_err(|e| ProxyError::LoadProxiesError(e.to_string()))?;
+        let body = self.secrets.unlock(body).map_err(ProxyError::LoadProxiesError)?;
+        let format = source.format.unwrap_or_else(|| ListFormat::detect(None, &source.path));
+        parse_list(&body, format, source).map_err(ProxyError::LoadProxiesError)
+    }
+
+    /// The proxies listed at the source's URL. Within the refresh interval the
+    /// last answer is reused without a request.
+    pub async fn fetch(&self, source: &PoolSource) -> Result<Vec<Proxy>, ProxyError> {
+        let cached = self.cache.lock().unwrap().get(&source.path).cloned();
+        if let Some(cached) = &cached {
+            if cached.at.elapsed() < source.refresh.unwrap_or_default() {
+                return Ok(cached.proxies.clone());
+            }
+        }
+        match self.request(source, cached.as_ref()).await {
+            Ok(fetched) => {
+                let proxies = fetched.proxies.clone();
+                self.cache.lock().unwrap().insert(source.path.clone(), fetched);
+                Ok(proxies)
+            }
+            Err(e) => match cached {
+                Some(cached) => {
+                    warn!("Failed to fetch {}, keeping the last list: {}", source.path, e);
+                    Ok(cached.proxies)
+                }
+                None => Err(ProxyError::LoadProxiesError(format!("{}: {}", source.path, e))),
+            },
+        }
+    }
+
+    async fn request(&self, source: &PoolSource, cached: Option<&Fetched>) -> Result<Fetched, String> {
+        let mut request = self.client.get(&source.path).timeout(Duration::from_secs(30));
+        for (name, value) in source.headers.iter() {
+            request = request.header(name, value);
+        }
+        if let Some(etag) = cached.and_then(|c| c.etag.as_ref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(modified) = cached.and_then(|c| c.last_modified.as_ref()) {
+            request = request.header(IF_MODIFIED_SINCE, modified);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+            return Ok(Fetched {
+                at: Instant::now(),
+                ..cached.clone()
+            });
+        }
+        if !response.status().is_success() {
+            return Err(format!("Unexpected status {}", response.status()));
+        }
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let format = source.format.unwrap_or_else(|| {
+            ListFormat::detect(header(reqwest::header::CONTENT_TYPE).as_deref(), &source.path)
+        });
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        let body = self.secrets.unlock(body.to_vec())?;
+        Ok(Fetched {
+            etag,
+            last_modified,
+            at: Instant::now(),
+            proxies: parse_list(&body, format, source)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::spawn_http;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_parse_formats() {
+        let source = PoolSource::from_str("p=x;field.ip=host;field.user=login;field.pass=password").unwrap();
+        let json = r#"[{"host": "10.0.0.1", "port": 1080, "login": "u", "password": "p"}, "10.0.0.2:1080", {"port": 1}]"#;
+        let proxies = parse_list(json, ListFormat::Json, &source).unwrap();
+        assert_eq!(proxies.len(), 2);
+        assert_eq!(proxies[0].auth.as_ref().unwrap().user, "u");
+        assert_eq!(proxies[1].addr(), "10.0.0.2:1080");
+
+        let csv = "host,port,country\n10.0.0.3,1080,de\n10.0.0.4,bad,us\n";
+        let proxies = parse_list(csv, ListFormat::Csv, &source).unwrap();
+        assert_eq!(proxies.len(), 1);
+        assert_eq!(proxies[0].country.as_deref(), Some("DE"));
+
+        let nested = PoolSource::from_str("p=x;root=/data").unwrap();
+        let proxies = parse_list(r#"{"data": [{"ip": "10.0.0.5:8080"}]}"#, ListFormat::Json, &nested).unwrap();
+        assert_eq!(proxies[0].port, 8080);
+        assert_eq!(ListFormat::detect(Some("text/csv"), "/list"), ListFormat::Csv);
+
+        let text = "# datacenter\n10.0.0.6:1080:user:p#ss # main\n  # spare\n10.0.0.7:1080\n";
+        let proxies = parse_list(text, ListFormat::Text, &source).unwrap();
+        assert_eq!(proxies.len(), 2);
+        assert_eq!(proxies[0].auth.as_ref().unwrap().pass, "p#ss");
+    }
+
+    #[test]
+    fn test_metadata_and_comments() {
+        let source = PoolSource::unnamed("proxies.yaml");
+        let yaml = r#"
+- ip: 10.0.0.1
+  port: 8080
+  protocol: http
+  user: u
+  pass: p
+  tags: {team: ads}
+  weight: 3
+  provider: acme
+  expires: 2999-01-31
+- ip: 10.0.0.2
+  port: 1080
+  expires: 2000-01-01T00:00:00Z
+"#;
+        let proxies = parse_list(yaml, ListFormat::Yaml, &source).unwrap();
+        assert_eq!(proxies[0].protocol, Protocol::Http);
+        assert_eq!(proxies[0].tags.get("team").map(String::as_str), Some("ads"));
+        assert_eq!(proxies[0].weight(), 3);
+        assert_eq!(proxies[0].provider.as_deref(), Some("acme"));
+        assert!(!proxies[0].is_expired());
+        assert!(proxies[1].is_expired());
+
+        let csv = "ip,port,tags,weight\n10.0.0.3,1080,team=ads;tier=gold,2\n";
+        let proxies = parse_list(csv, ListFormat::Csv, &source).unwrap();
+        assert_eq!(proxies[0].tags.len(), 2);
+
+        let text = "# provider acme\n\n10.0.0.4:1080 # spare\nhttp://u:p@10.0.0.5:3128\nsocks5://10.0.0.6:1080:u:p\nnot a proxy\n";
+        let proxies = parse_list(text, ListFormat::Text, &source).unwrap();
+        assert_eq!(proxies.len(), 3);
+        assert_eq!(proxies[1].protocol, Protocol::Http);
+        assert_eq!(proxies[1].auth.as_ref().unwrap().pass, "p");
+        assert_eq!(Proxy::from_str(&proxies[1].expose()).unwrap(), proxies[1]);
+        assert_eq!(proxies[1].to_string(), "http://10.0.0.5:3128:u:***");
+        assert_eq!(proxies[2].auth.as_ref().unwrap().user, "u");
+
+        std::env::set_var("QPROXY_TEST_LIST_PASS", "p:w");
+        let text = "10.0.0.7:1080:u:${env:QPROXY_TEST_LIST_PASS}\n10.0.0.8:1080:u:${env:QPROXY_TEST_UNSET}\n";
+        let proxies = parse_list(text, ListFormat::Text, &source).unwrap();
+        assert_eq!(proxies.len(), 1);
+        assert_eq!(proxies[0].auth.as_ref().unwrap().pass, "p:w");
+        assert_eq!(ListFormat::detect(None, "pool.csv.age"), ListFormat::Csv);
+        assert_eq!(ListFormat::detect(None, "pool.yml"), ListFormat::Yaml);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_fetch() {
+        let not_modified = Arc::new(AtomicUsize::new(0));
+        let counter = not_modified.clone();
+        let url = spawn_http(move |request| {
+            let request = request.to_ascii_lowercase();
+            if !request.contains("authorization: bearer secret") {
+                return "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n".to_string();
+            }
+            if request.contains("if-none-match: \"v1\"") {
+                counter.fetch_add(1, Ordering::SeqCst);
+                return "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_string();
+            }
+            let body = r#"["10.0.0.1:1080", "10.0.0.2:1080"]"#;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        });
+        let fetcher = Fetcher::default();
+        let source = PoolSource::from_str(&format!("remote={}/list;header=Authorization: Bearer secret", url)).unwrap();
+
+        assert_eq!(fetcher.fetch(&source).await.unwrap().len(), 2);
+        assert_eq!(fetcher.fetch(&source).await.unwrap().len(), 2);
+        assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+
+        let anonymous = PoolSource::from_str(&format!("remote={}/other", url)).unwrap();
+        assert!(fetcher.fetch(&anonymous).await.is_err());
+    }
+}
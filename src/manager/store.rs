@@ -0,0 +1,341 @@
+use crate::errors::ProxyError;
+use crate::Proxy;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Latency samples kept per proxy.
+const LATENCY_HISTORY: usize = 20;
+
+/// What is known about an upstream across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProxyRecord {
+    pub last_check: Option<DateTime<Utc>>,
+    /// Outcome of the last check.
+    pub last_ok: bool,
+    pub successes: u64,
+    pub failures: u64,
+    /// Latest check latencies in milliseconds, oldest first.
+    pub latency_ms: VecDeque<u64>,
+    pub egress_ip: Option<String>,
+    pub banned: bool,
+    pub ban_reason: Option<String>,
+}
+
+impl ProxyRecord {
+    pub fn mean_latency(&self) -> Duration {
+        if self.latency_ms.is_empty() {
+            return Duration::ZERO;
+        }
+        let sum: u64 = self.latency_ms.iter().sum();
+        Duration::from_millis(sum / self.latency_ms.len() as u64)
+    }
+}
+
+/// One line of the log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Entry {
+    Check {
+        addr: String,
+        at: DateTime<Utc>,
+        ok: bool,
+        latency_ms: Option<u64>,
+        egress_ip: Option<String>,
+    },
+    Ban {
+        addr: String,
+        reason: Option<String>,
+    },
+    Unban {
+        addr: String,
+    },
+    Snapshot {
+        addr: String,
+        record: ProxyRecord,
+    },
+}
+
+impl Entry {
+    fn apply(self, records: &mut HashMap<String, ProxyRecord>) {
+        match self {
+            Entry::Check {
+                addr,
+                at,
+                ok,
+                latency_ms,
+                egress_ip,
+            } => {
+                let record = records.entry(addr).or_default();
+                record.last_check = Some(at);
+                record.last_ok = ok;
+                if ok {
+                    record.successes += 1;
+                    if let Some(latency) = latency_ms {
+                        if record.latency_ms.len() == LATENCY_HISTORY {
+                            record.latency_ms.pop_front();
+                        }
+                        record.latency_ms.push_back(latency);
+                    }
+                    record.egress_ip = egress_ip.or(record.egress_ip.take());
+                } else {
+                    record.failures += 1;
+                }
+            }
+            Entry::Ban { addr, reason } => {
+                let record = records.entry(addr).or_default();
+                record.banned = true;
+                record.ban_reason = reason;
+            }
+            Entry::Unban { addr } => {
+                let record = records.entry(addr).or_default();
+                record.banned = false;
+                record.ban_reason = None;
+            }
+            Entry::Snapshot { addr, record } => {
+                records.insert(addr, record);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    records: HashMap<String, ProxyRecord>,
+    /// Lines written since the last compaction.
+    appended: usize,
+}
+
+/// Work for the writer thread, in the order the entries were applied.
+#[derive(Debug)]
+enum LogWrite {
+    Append(String),
+    /// Replace the log with these lines.
+    Compact(Vec<String>),
+}
+
+/// Thread writing the log, so that recording an event never waits on the disk.
+#[derive(Debug)]
+struct Writer {
+    sender: Option<Sender<LogWrite>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // closing the channel ends the thread once it has written everything
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Per-proxy state persisted as an append-only JSON log, one event per line.
+/// The log is replayed on open and compacted into one snapshot per proxy when
+/// it grows. Without a path the store only lives in memory.
+#[derive(Debug, Default)]
+pub struct StateStore {
+    ttl: Duration,
+    state: Mutex<State>,
+    writer: Option<Writer>,
+}
+
+/// Write `lines` to a new log at `path` and reopen it for appending.
+fn write_log(path: &Path, lines: &[String]) -> std::io::Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+fn run_writer(path: PathBuf, mut file: File, writes: Receiver<LogWrite>) {
+    for write in writes {
+        match write {
+            LogWrite::Append(line) => {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    error!("Failed to write proxy state: {}", e);
+                }
+            }
+            LogWrite::Compact(lines) => match write_log(&path, &lines) {
+                Ok(compacted) => file = compacted,
+                Err(e) => error!("Failed to compact proxy state: {}", e),
+            },
+        }
+    }
+}
+
+impl StateStore {
+    /// Open the log at `path`. Check results older than `ttl` aren't trusted.
+    pub fn open(path: &str, ttl: Duration) -> Result<Self, ProxyError> {
+        let failed = |e: std::io::Error| ProxyError::StateStoreError(format!("{}: {}", path, e));
+        let path = PathBuf::from(path);
+        let mut records = HashMap::new();
+        if let Ok(file) = File::open(&path) {
+            for (i, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(failed)?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => entry.apply(&mut records),
+                    Err(e) => error!("Skipping line {} of {}: {}", i + 1, path.display(), e),
+                }
+            }
+        }
+        // compacted right away, a path that can't be written fails here
+        let file = write_log(&path, &snapshot(&records)).map_err(failed)?;
+        let (sender, writes) = channel();
+        let thread = thread::spawn(move || run_writer(path, file, writes));
+        Ok(StateStore {
+            ttl,
+            state: Mutex::new(State {
+                records,
+                ..Default::default()
+            }),
+            writer: Some(Writer {
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    /// Apply `entry` and hand it to the writer, with a compaction of the log
+    /// when it has grown.
+    fn append(&self, entry: Entry) {
+        let line = serde_json::to_string(&entry);
+        let mut state = self.state.lock().unwrap();
+        entry.apply(&mut state.records);
+        let Some(sender) = self.writer.as_ref().and_then(|w| w.sender.as_ref()) else {
+            return;
+        };
+        state.appended += 1;
+        let write = if state.appended > 1000 + 10 * state.records.len() {
+            state.appended = 0;
+            LogWrite::Compact(snapshot(&state.records))
+        } else {
+            match line {
+                Ok(line) => LogWrite::Append(line),
+                Err(e) => {
+                    error!("Failed to write proxy state: {}", e);
+                    return;
+                }
+            }
+        };
+        // sent under the lock, the writer gets the entries in order
+        if sender.send(write).is_err() {
+            error!("Failed to write proxy state: the writer has stopped");
+        }
+    }
+
+    pub fn record(&self, addr: &str) -> Option<ProxyRecord> {
+        self.state.lock().unwrap().records.get(addr).cloned()
+    }
+
+    /// The record of `addr` when its last check passed within the TTL.
+    pub fn fresh(&self, addr: &str) -> Option<ProxyRecord> {
+        let record = self.record(addr)?;
+        let age = Utc::now() - record.last_check?;
+        let fresh = record.last_ok && !record.banned && age.to_std().is_ok_and(|age| age < self.ttl);
+        fresh.then_some(record)
+    }
+
+    /// Number of banned upstreams.
+    pub fn banned(&self) -> usize {
+        self.state.lock().unwrap().records.values().filter(|r| r.banned).count()
+    }
+
+    pub fn is_banned(&self, addr: &str) -> bool {
+        self.record(addr).is_some_and(|r| r.banned)
+    }
+
+    /// Remember the outcome of a check of `proxy`, with its latency and egress on success.
+    pub fn record_check(&self, proxy: &Proxy, ok: bool) {
+        self.append(Entry::Check {
+            addr: proxy.addr(),
+            at: Utc::now(),
+            ok,
+            latency_ms: ok.then_some(proxy.latency.as_millis() as u64),
+            egress_ip: proxy.egress_ip.clone().filter(|_| ok),
+        });
+    }
+
+    pub fn ban(&self, addr: &str, reason: Option<String>) {
+        self.append(Entry::Ban {
+            addr: addr.to_string(),
+            reason,
+        });
+    }
+
+    pub fn unban(&self, addr: &str) {
+        self.append(Entry::Unban { addr: addr.to_string() });
+    }
+}
+
+/// The lines of a log holding one snapshot per proxy.
+fn snapshot(records: &HashMap<String, ProxyRecord>) -> Vec<String> {
+    records
+        .iter()
+        .filter_map(|(addr, record)| {
+            let entry = Entry::Snapshot {
+                addr: addr.clone(),
+                record: record.clone(),
+            };
+            serde_json::to_string(&entry).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_replay_ttl_and_bans() {
+        let path = std::env::temp_dir().join(format!("qproxy-state-{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let mut proxy = Proxy::from_str("10.0.0.1:1080").unwrap();
+        proxy.egress_ip = Some("203.0.113.1".to_string());
+
+        let store = StateStore::open(&path, Duration::from_secs(60)).unwrap();
+        proxy.latency = Duration::from_millis(100);
+        store.record_check(&proxy, true);
+        proxy.latency = Duration::from_millis(300);
+        store.record_check(&proxy, true);
+        store.record_check(&proxy, false);
+        store.ban("10.0.0.2:1080", Some("abuse".to_string()));
+        drop(store);
+
+        let store = StateStore::open(&path, Duration::from_secs(60)).unwrap();
+        let record = store.record("10.0.0.1:1080").unwrap();
+        assert_eq!((record.successes, record.failures), (2, 1));
+        assert_eq!(record.mean_latency(), Duration::from_millis(200));
+        assert_eq!(record.egress_ip.as_deref(), Some("203.0.113.1"));
+        // the last check failed
+        assert!(store.fresh("10.0.0.1:1080").is_none());
+        proxy.latency = Duration::from_millis(200);
+        store.record_check(&proxy, true);
+        assert!(store.fresh("10.0.0.1:1080").is_some());
+        assert!(store.is_banned("10.0.0.2:1080"));
+        store.unban("10.0.0.2:1080");
+        drop(store);
+
+        let store = StateStore::open(&path, Duration::ZERO).unwrap();
+        assert!(!store.is_banned("10.0.0.2:1080"));
+        assert!(store.fresh("10.0.0.1:1080").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
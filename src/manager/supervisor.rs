@@ -0,0 +1,80 @@
+use crate::errors::ProxyError;
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Handle of a manager running on its own task, see `ProxyManager::spawn`.
+/// Dropping it leaves the manager running.
+#[derive(Debug)]
+pub struct RunHandle {
+    shutdown: CancellationToken,
+    task: JoinHandle<Result<(), ProxyError>>,
+}
+
+impl RunHandle {
+    pub(crate) fn new(shutdown: CancellationToken, task: JoinHandle<Result<(), ProxyError>>) -> Self {
+        RunHandle { shutdown, task }
+    }
+
+    /// Token that stops the manager when cancelled, to tie it to other tasks.
+    pub fn token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Ask the manager to stop, `wait` returns once it did.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Stop the manager on SIGINT or SIGTERM.
+    pub fn shutdown_on_signal(self) -> Self {
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminated() => shutdown.cancel(),
+                _ = shutdown.cancelled() => {}
+            }
+        });
+        self
+    }
+
+    /// Wait until the manager has stopped its listeners and background tasks.
+    pub async fn wait(self) -> Result<(), ProxyError> {
+        self.task
+            .await
+            .map_err(|e| ProxyError::ServerError(format!("Run loop failed: {}", e)))?
+    }
+
+    /// Shut down and wait for it.
+    pub async fn stop(self) -> Result<(), ProxyError> {
+        self.shutdown();
+        self.wait().await
+    }
+}
+
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            info!("SIGINT received, shutting down");
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down"),
+        _ = term.recv() => info!("SIGTERM received, shutting down"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+    info!("Ctrl-C received, shutting down");
+}
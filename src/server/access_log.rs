@@ -0,0 +1,271 @@
+use crate::server::handshake::Protocol;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessFormat {
+    /// One JSON object per line.
+    #[default]
+    Json,
+    /// Squid native `access.log` lines.
+    Squid,
+}
+
+impl FromStr for AccessFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(AccessFormat::Json),
+            "squid" => Ok(AccessFormat::Squid),
+            _ => Err(format!("Unknown access log format: {}, expected json or squid", s)),
+        }
+    }
+}
+
+impl Display for AccessFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessFormat::Json => write!(f, "json"),
+            AccessFormat::Squid => write!(f, "squid"),
+        }
+    }
+}
+
+/// How a client connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    /// The handshake failed or the client was not allowed.
+    Rejected,
+    /// No upstream matched the session parameters.
+    NoUpstream,
+    /// The upstream could not open the tunnel.
+    UpstreamError,
+}
+
+impl Outcome {
+    /// Squid result code and HTTP status.
+    fn squid(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "TCP_TUNNEL/200",
+            Outcome::Rejected => "TCP_DENIED/407",
+            Outcome::NoUpstream => "NONE/503",
+            Outcome::UpstreamError => "TCP_MISS/502",
+        }
+    }
+}
+
+/// One client connection, the upstream is its address only, never its credentials.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessRecord {
+    pub time: DateTime<Utc>,
+    /// Id of the connection, the same as on its tracing span.
+    pub id: u64,
+    pub listener: u16,
+    pub client: Option<SocketAddr>,
+    pub protocol: Option<Protocol>,
+    pub user: Option<String>,
+    pub method: Option<String>,
+    pub target: Option<String>,
+    pub upstream: Option<String>,
+    pub egress_ip: Option<String>,
+    pub sent: u64,
+    pub received: u64,
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+impl AccessRecord {
+    pub fn new(id: u64, listener: u16, client: Option<SocketAddr>) -> Self {
+        AccessRecord {
+            time: Utc::now(),
+            id,
+            listener,
+            client,
+            protocol: None,
+            user: None,
+            method: None,
+            target: None,
+            upstream: None,
+            egress_ip: None,
+            sent: 0,
+            received: 0,
+            duration_ms: 0,
+            outcome: Outcome::Rejected,
+            error: None,
+        }
+    }
+
+    /// `time elapsed client code/status bytes method URL user hierarchy/peer type`
+    pub fn to_squid(&self) -> String {
+        let dash = |value: &Option<String>| value.clone().filter(|v| !v.is_empty()).unwrap_or("-".to_string());
+        let hierarchy = match &self.upstream {
+            Some(upstream) => format!("FIRSTUP_PARENT/{}", upstream),
+            None => "HIER_NONE/-".to_string(),
+        };
+        format!(
+            "{}.{:03} {:>6} {} {} {} {} {} {} {} -",
+            self.time.timestamp(),
+            self.time.timestamp_subsec_millis(),
+            self.duration_ms,
+            self.client.map_or("-".to_string(), |c| c.ip().to_string()),
+            self.outcome.squid(),
+            self.received,
+            dash(&self.method),
+            dash(&self.target),
+            dash(&self.user),
+            hierarchy
+        )
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+/// Access log file, rotated once it grows past `max_size` bytes or gets older
+/// than `max_age`. Rotated files are renamed `<path>.1` to `<path>.<keep>`.
+pub struct AccessLog {
+    path: PathBuf,
+    format: AccessFormat,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    file: Mutex<LogFile>,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("path", &self.path)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+fn open_append(path: &Path) -> Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(LogFile {
+        size: file.metadata()?.len(),
+        file,
+        opened_at: Instant::now(),
+    })
+}
+
+impl AccessLog {
+    pub fn open(path: impl AsRef<Path>, format: AccessFormat) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(AccessLog {
+            file: Mutex::new(open_append(&path)?),
+            path,
+            format,
+            max_size: 0,
+            max_age: None,
+            keep: 5,
+        })
+    }
+
+    /// Rotate past `max_size` bytes or `max_age`, 0 and None never rotate.
+    pub fn with_rotation(mut self, max_size: u64, max_age: Option<Duration>, keep: usize) -> Self {
+        self.max_size = max_size;
+        self.max_age = max_age;
+        self.keep = keep;
+        self
+    }
+
+    pub fn write(&self, record: &AccessRecord) -> Result<()> {
+        let mut line = match self.format {
+            AccessFormat::Json => serde_json::to_string(record).map_err(std::io::Error::other)?,
+            AccessFormat::Squid => record.to_squid(),
+        };
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        let too_big = self.max_size > 0 && file.size > 0 && file.size + line.len() as u64 > self.max_size;
+        let too_old = self.max_age.is_some_and(|age| file.opened_at.elapsed() >= age);
+        if too_big || too_old {
+            self.rotate()?;
+            *file = open_append(&self.path)?;
+        }
+        file.file.write_all(line.as_bytes())?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Shift `<path>.n` to `<path>.n+1`, dropping the oldest, and move the
+    /// current file to `<path>.1`.
+    fn rotate(&self) -> Result<()> {
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_and_rotation() {
+        let dir = std::env::temp_dir().join(format!("qproxy-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut record = AccessRecord::new(1, 8100, Some("10.0.0.2:50000".parse().unwrap()));
+        record.protocol = Some(Protocol::Socks5);
+        record.user = Some("alice".to_string());
+        record.method = Some("CONNECT".to_string());
+        record.target = Some("example.com:443".to_string());
+        record.upstream = Some("1.2.3.4:1080".to_string());
+        record.received = 2048;
+        record.duration_ms = 150;
+        record.outcome = Outcome::Ok;
+
+        let squid = record.to_squid();
+        assert!(squid.ends_with(
+            "    150 10.0.0.2 TCP_TUNNEL/200 2048 CONNECT example.com:443 alice FIRSTUP_PARENT/1.2.3.4:1080 -"
+        ));
+
+        let line = serde_json::to_string(&record).unwrap();
+        let log = AccessLog::open(&path, AccessFormat::Json)
+            .unwrap()
+            .with_rotation(line.len() as u64 * 2 + 2, None, 2);
+        for _ in 0..7 {
+            log.write(&record).unwrap();
+        }
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        let value: serde_json::Value = serde_json::from_str(current.trim()).unwrap();
+        assert_eq!(value["outcome"], "ok");
+        assert_eq!(value["upstream"], "1.2.3.4:1080");
+        assert_eq!(std::fs::read_to_string(log.rotated(1)).unwrap().lines().count(), 2);
+        assert!(log.rotated(2).exists());
+        assert!(!log.rotated(3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
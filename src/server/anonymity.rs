@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// How much an upstream reveals about its clients, ordered from worst to best.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Anonymity {
+    /// Forwards the client's address.
+    Transparent,
+    /// Hides the client's address but announces itself as a proxy.
+    Anonymous,
+    /// Indistinguishable from a direct client.
+    Elite,
+}
+
+impl FromStr for Anonymity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "transparent" => Ok(Anonymity::Transparent),
+            "anonymous" => Ok(Anonymity::Anonymous),
+            "elite" => Ok(Anonymity::Elite),
+            _ => Err(format!("Unknown anonymity level: {}", s)),
+        }
+    }
+}
+
+impl Display for Anonymity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self {
+            Anonymity::Transparent => "transparent",
+            Anonymity::Anonymous => "anonymous",
+            Anonymity::Elite => "elite",
+        };
+        write!(f, "{}", level)
+    }
+}
+
+/// Headers that carry the address of the original client.
+const FORWARDING_HEADERS: [&str; 6] = [
+    "x-forwarded-for",
+    "forwarded",
+    "x-real-ip",
+    "client-ip",
+    "x-client-ip",
+    "x-originating-ip",
+];
+
+/// Headers that only reveal a proxy is in the path.
+const PROXY_HEADERS: [&str; 4] = ["via", "proxy-connection", "x-proxy-id", "x-bluecoat-via"];
+
+/// Read the headers out of a header-echo response: a JSON object, optionally
+/// nested under `headers` like httpbin's `/headers`, or raw `Name: value` lines.
+pub fn parse_echoed_headers(body: &str) -> Vec<(String, String)> {
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(body) {
+        let object = json.get("headers").unwrap_or(&json);
+        if let Some(object) = object.as_object() {
+            return object
+                .iter()
+                .map(|(k, v)| {
+                    let value = v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string());
+                    (k.to_ascii_lowercase(), value)
+                })
+                .collect();
+        }
+    }
+    body.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect()
+}
+
+fn addresses(value: &str) -> Vec<IpAddr> {
+    value
+        .split([',', ';', ' ', '"', '='])
+        .filter_map(|part| {
+            let part = part.trim_matches(|c| c == '[' || c == ']');
+            IpAddr::from_str(part).ok()
+        })
+        .collect()
+}
+
+/// Classify an upstream from the headers the echo endpoint received through it.
+/// Without a known client address any foreign address in a forwarding header
+/// counts as a leak.
+pub fn classify(headers: &[(String, String)], client_ip: Option<IpAddr>, egress_ip: Option<IpAddr>) -> Anonymity {
+    let mut announced = false;
+    for (name, value) in headers {
+        if FORWARDING_HEADERS.contains(&name.as_str()) {
+            announced = true;
+            let leaked = addresses(value).into_iter().any(|ip| match client_ip {
+                Some(client) => ip == client,
+                None => Some(ip) != egress_ip,
+            });
+            if leaked {
+                return Anonymity::Transparent;
+            }
+        } else if PROXY_HEADERS.contains(&name.as_str()) {
+            announced = true;
+        }
+    }
+    if announced {
+        Anonymity::Anonymous
+    } else {
+        Anonymity::Elite
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        IpAddr::from_str(s).ok()
+    }
+
+    #[test]
+    fn test_classify() {
+        let client = ip("198.51.100.10");
+        let egress = ip("203.0.113.5");
+
+        let elite = parse_echoed_headers(r#"{"headers": {"Host": "echo.test", "User-Agent": "qproxy"}}"#);
+        assert_eq!(classify(&elite, client, egress), Anonymity::Elite);
+
+        let anonymous = parse_echoed_headers("Host: echo.test\r\nVia: 1.1 squid\r\nX-Forwarded-For: 203.0.113.5\r\n");
+        assert_eq!(classify(&anonymous, client, egress), Anonymity::Anonymous);
+        assert_eq!(classify(&anonymous, None, egress), Anonymity::Anonymous);
+
+        let transparent = parse_echoed_headers(r#"{"Forwarded": "for=198.51.100.10;proto=http"}"#);
+        assert_eq!(classify(&transparent, client, egress), Anonymity::Transparent);
+        assert_eq!(classify(&transparent, None, egress), Anonymity::Transparent);
+
+        assert!(Anonymity::Elite > Anonymity::Anonymous);
+        assert_eq!(Anonymity::from_str("ELITE").unwrap(), Anonymity::Elite);
+    }
+}
@@ -0,0 +1,159 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A connection being relayed, as the registry lists it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Connection {
+    /// Id of the connection, the same as in the access log.
+    pub id: u64,
+    pub listener: u16,
+    pub client: Option<SocketAddr>,
+    pub target: String,
+    pub upstream: String,
+    pub started_at: DateTime<Utc>,
+    /// Bytes sent to the upstream so far.
+    pub sent: u64,
+    /// Bytes received from the upstream so far.
+    pub received: u64,
+}
+
+/// Bytes relayed one way, updated while the relay runs.
+pub(crate) type Counter = Arc<AtomicU64>;
+
+/// What a one way copy moved before it ended.
+#[derive(Debug, Default)]
+pub(crate) struct Copied {
+    pub(crate) total: u64,
+    /// The first bytes read, up to the `keep` of the copy.
+    pub(crate) head: Vec<u8>,
+    /// The reader ended with a connection reset.
+    pub(crate) reset: bool,
+}
+
+/// Copy until `reader` or `writer` closes, counting into `counter` as it goes.
+/// Past `limit` bytes the reader is still drained, but nothing more is written.
+pub(crate) fn copy_counted(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    counter: &Counter,
+    keep: usize,
+    limit: u64,
+) -> Copied {
+    let mut buf = [0u8; 16 * 1024];
+    let mut copied = Copied::default();
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return copied,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                copied.reset = e.kind() == ErrorKind::ConnectionReset;
+                return copied;
+            }
+        };
+        let n = n.min((limit - copied.total).min(usize::MAX as u64) as usize);
+        if n == 0 {
+            continue;
+        }
+        let kept = n.min(keep - copied.head.len());
+        copied.head.extend_from_slice(&buf[..kept]);
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        if writer.write_all(&buf[..n]).is_err() {
+            return copied;
+        }
+        copied.total += n as u64;
+    }
+}
+
+/// An entry of the registry, closed by shutting its streams down.
+#[derive(Debug)]
+pub(crate) struct Relay {
+    pub(crate) connection: Connection,
+    /// Goes through the server's current proxy rather than a session's.
+    pub(crate) rotating: bool,
+    pub(crate) streams: [TcpStream; 2],
+    pub(crate) sent: Counter,
+    pub(crate) received: Counter,
+}
+
+impl Relay {
+    fn close(&self) {
+        for stream in self.streams.iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn snapshot(&self) -> Connection {
+        Connection {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            ..self.connection.clone()
+        }
+    }
+}
+
+/// The relays of a server.
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    relays: Mutex<HashMap<u64, Relay>>,
+    /// Rotating connections through these upstreams with a lower id are closed.
+    retired: Mutex<HashMap<String, u64>>,
+}
+
+impl Registry {
+    /// Track a relay until `remove`, unless its upstream was retired while
+    /// the connection was being opened.
+    pub(crate) fn register(&self, relay: Relay) -> Result<()> {
+        // same lock order as retire, a connection is either seen there or refused here
+        let mut relays = self.relays.lock().unwrap();
+        let id = relay.connection.id;
+        let retired = self
+            .retired
+            .lock()
+            .unwrap()
+            .get(&relay.connection.upstream)
+            .is_some_and(|before| id < *before);
+        if relay.rotating && retired {
+            relay.close();
+            return Err(Error::other(format!("Upstream {} was rotated out", relay.connection.upstream)));
+        }
+        relays.insert(id, relay);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.relays.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn list(&self) -> Vec<Connection> {
+        let mut connections: Vec<Connection> = self.relays.lock().unwrap().values().map(Relay::snapshot).collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    /// Close the relays matching `filter`, returns how many.
+    pub(crate) fn close(&self, filter: impl Fn(&Relay) -> bool) -> usize {
+        let relays = self.relays.lock().unwrap();
+        relays.values().filter(|relay| filter(relay)).map(Relay::close).count()
+    }
+
+    /// Close the rotating relays through `upstream` opened before the
+    /// connection `before`, and refuse the ones still being opened.
+    pub(crate) fn retire(&self, upstream: &str, before: u64) -> usize {
+        let relays = self.relays.lock().unwrap();
+        let mut retired = self.retired.lock().unwrap();
+        let watermark = retired.entry(upstream.to_string()).or_default();
+        *watermark = before.max(*watermark);
+        drop(retired);
+        relays
+            .values()
+            .filter(|relay| relay.connection.id < before && relay.rotating && relay.connection.upstream == upstream)
+            .map(Relay::close)
+            .count()
+    }
+}
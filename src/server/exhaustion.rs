@@ -0,0 +1,37 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// What a server does with a new connection while no upstream is available.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Exhaustion {
+    /// Refuse it with a SOCKS failure or an HTTP 503.
+    #[default]
+    Fail,
+    /// Hold it until an upstream is back, failing it after the queue timeout.
+    Queue,
+    /// Connect to the target without a proxy, from the host's own address.
+    Direct,
+}
+
+impl FromStr for Exhaustion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Exhaustion::Fail),
+            "queue" => Ok(Exhaustion::Queue),
+            "direct" => Ok(Exhaustion::Direct),
+            _ => Err(format!("Unknown exhaustion policy: {}, expected fail, queue or direct", s)),
+        }
+    }
+}
+
+impl Display for Exhaustion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exhaustion::Fail => write!(f, "fail"),
+            Exhaustion::Queue => write!(f, "queue"),
+            Exhaustion::Direct => write!(f, "direct"),
+        }
+    }
+}
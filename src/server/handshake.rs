@@ -0,0 +1,553 @@
+use crate::server::proxy_model::ProxyAuth;
+use crate::server::session::SessionParams;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{BufReader, Error, Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+use std::str::FromStr;
+
+pub const SOCKS_VERSION: u8 = 0x05;
+pub const AUTHENTICATION_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Max size of an inbound HTTP request head.
+const MAX_HTTP_HEAD: usize = 8 * 1024;
+
+/// Headers of the client's connection to us, not passed on.
+const HOP_BY_HOP: [&str; 2] = ["connection", "keep-alive"];
+
+/// Protocol spoken by a client, or by an upstream proxy.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Socks5,
+    Http,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "socks5" | "socks" | "socks5h" => Ok(Protocol::Socks5),
+            "http" => Ok(Protocol::Http),
+            _ => Err(format!("Unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Socks5 => write!(f, "socks5"),
+            Protocol::Http => write!(f, "http"),
+        }
+    }
+}
+
+/// Destination requested by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match IpAddr::from_str(&self.host) {
+            Ok(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            _ => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Target {
+    /// Encode as a SOCKS5 CONNECT request.
+    pub fn to_socks_request(&self) -> Vec<u8> {
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+        match IpAddr::from_str(&self.host) {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                request.push(ATYP_DOMAIN);
+                request.push(self.host.len() as u8);
+                request.extend_from_slice(self.host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&self.port.to_be_bytes());
+        request
+    }
+
+    /// Parse `host[:port]`, IPv6 hosts in brackets. Names longer than a SOCKS5
+    /// request can carry are rejected.
+    pub(crate) fn parse_authority(authority: &str, default_port: u16) -> Result<Target> {
+        let target = Self::parse_host_port(authority, default_port)?;
+        if target.host.len() > u8::MAX as usize {
+            return Err(Error::other(format!("Target name longer than 255 bytes: {}", authority)));
+        }
+        Ok(target)
+    }
+
+    fn parse_host_port(authority: &str, default_port: u16) -> Result<Target> {
+        let invalid = || Error::other(format!("Invalid target: {}", authority));
+        if let Some(rest) = authority.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => port.parse().map_err(|_| invalid())?,
+                None => default_port,
+            };
+            return Ok(Target {
+                host: host.to_string(),
+                port,
+            });
+        }
+        match authority.rsplit_once(':') {
+            Some((host, port)) => Ok(Target {
+                host: host.to_string(),
+                port: port.parse().map_err(|_| invalid())?,
+            }),
+            None if !authority.is_empty() => Ok(Target {
+                host: authority.to_string(),
+                port: default_port,
+            }),
+            None => Err(invalid()),
+        }
+    }
+}
+
+/// Result of a completed inbound handshake.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub protocol: Protocol,
+    pub params: SessionParams,
+    /// `CONNECT` for tunnels, the request method when forwarding plain HTTP.
+    pub method: String,
+    pub target: Target,
+    /// Bytes that must be sent upstream once the tunnel is open: the rewritten
+    /// head of a forwarded request, and what the client sent early.
+    pub pending: Vec<u8>,
+    /// Bytes the client may still send upstream after `pending`, the body of
+    /// a forwarded request. Whatever follows it is dropped, tunnels are unlimited.
+    pub body_len: Option<u64>,
+}
+
+fn read_u8(stream: &mut TcpStream) -> Result<u8> {
+    let mut buffer = [0u8; 1];
+    stream.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_string(stream: &mut TcpStream) -> Result<String> {
+    let len = read_u8(stream)? as usize;
+    let mut buffer = vec![0u8; len];
+    stream.read_exact(&mut buffer)?;
+    String::from_utf8(buffer).map_err(|e| Error::other(e.to_string()))
+}
+
+fn read_address(stream: &mut TcpStream, atyp: u8) -> Result<String> {
+    match atyp {
+        ATYP_IPV4 => {
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer)?;
+            Ok(Ipv4Addr::from(buffer).to_string())
+        }
+        ATYP_IPV6 => {
+            let mut buffer = [0u8; 16];
+            stream.read_exact(&mut buffer)?;
+            Ok(Ipv6Addr::from(buffer).to_string())
+        }
+        ATYP_DOMAIN => read_string(stream),
+        _ => Err(Error::other(format!("Unsupported address type: {}", atyp))),
+    }
+}
+
+/// Whether `a` and `b` are equal, in a time that doesn't depend on where they differ.
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Validate inbound credentials and extract routing parameters from the username.
+/// The configured user is matched as a prefix, so it may contain `-`. Without
+/// credentials a username that doesn't parse is taken as is, without parameters.
+fn authorize(user: &str, pass: &str, credentials: Option<&ProxyAuth>) -> Result<SessionParams> {
+    let Some(credentials) = credentials else {
+        return Ok(SessionParams::from_str(user).unwrap_or_else(|_| SessionParams {
+            user: user.to_string(),
+            ..Default::default()
+        }));
+    };
+    let (prefix, rest) = user.split_at_checked(credentials.user.len()).unwrap_or((user, ""));
+    let params = rest.strip_prefix('-');
+    let user_ok = constant_time_eq(prefix, &credentials.user) & (rest.is_empty() || params.is_some());
+    if !(user_ok & constant_time_eq(pass, &credentials.pass)) {
+        return Err(Error::other("Invalid credentials"));
+    }
+    let params = params.unwrap_or_default();
+    SessionParams::parse(&credentials.user, params).map_err(Error::other)
+}
+
+/// Inbound protocol of a client, from its first byte.
+pub fn detect(stream: &TcpStream) -> Result<Protocol> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first)? == 0 {
+        return Err(Error::other("Client closed connection"));
+    }
+    if first[0] == SOCKS_VERSION {
+        Ok(Protocol::Socks5)
+    } else {
+        Ok(Protocol::Http)
+    }
+}
+
+/// Detect the inbound protocol and run its handshake.
+pub fn accept(stream: &mut TcpStream, credentials: Option<&ProxyAuth>) -> Result<Handshake> {
+    match detect(stream)? {
+        Protocol::Socks5 => accept_socks(stream, credentials),
+        Protocol::Http => accept_http(stream, credentials),
+    }
+}
+
+fn accept_socks(stream: &mut TcpStream, credentials: Option<&ProxyAuth>) -> Result<Handshake> {
+    // greeting header
+    let _version = read_u8(stream)?;
+    let number_of_methods = read_u8(stream)?;
+    let mut methods = vec![0u8; number_of_methods as usize];
+    stream.read_exact(&mut methods)?;
+
+    // username/password is preferred when offered, it carries the session parameters
+    let method = if methods.contains(&METHOD_USER_PASS) {
+        METHOD_USER_PASS
+    } else if credentials.is_none() && methods.contains(&METHOD_NO_AUTH) {
+        METHOD_NO_AUTH
+    } else {
+        // no acceptable methods were offered
+        stream.write_all(&[SOCKS_VERSION, METHOD_NOT_ACCEPTABLE])?;
+        return Err(Error::other("Method not supported"));
+    };
+    stream.write_all(&[SOCKS_VERSION, method])?;
+
+    let mut params = SessionParams::default();
+    if method == METHOD_USER_PASS {
+        let _version = read_u8(stream)?;
+        let user = read_string(stream)?;
+        let pass = read_string(stream)?;
+        match authorize(&user, &pass, credentials) {
+            Ok(p) => {
+                stream.write_all(&[AUTHENTICATION_VERSION, 0x00])?;
+                params = p;
+            }
+            Err(e) => {
+                stream.write_all(&[AUTHENTICATION_VERSION, 0x01])?;
+                return Err(e);
+            }
+        }
+    }
+
+    // request
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let host = read_address(stream, header[3])?;
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port)?;
+    if header[1] != CMD_CONNECT {
+        reply_socks_failure(stream, REPLY_COMMAND_NOT_SUPPORTED)?;
+        return Err(Error::other(format!("Command not supported: {}", header[1])));
+    }
+
+    Ok(Handshake {
+        protocol: Protocol::Socks5,
+        params,
+        method: "CONNECT".to_string(),
+        target: Target {
+            host,
+            port: u16::from_be_bytes(port),
+        },
+        pending: Vec::new(),
+        body_len: None,
+    })
+}
+
+/// Read up to the end of an HTTP head, byte by byte so that nothing past it is
+/// consumed. Give it a `BufReader` where the bytes past it are picked up.
+pub(crate) fn read_http_head(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_HEAD {
+            return Err(Error::other("HTTP request head too large"));
+        }
+        reader.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(head)
+}
+
+fn http_basic_credentials(value: &str) -> Option<(String, String)> {
+    let encoded = value.trim().strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+fn accept_http(stream: &mut TcpStream, credentials: Option<&ProxyAuth>) -> Result<Handshake> {
+    let mut reader = BufReader::new(&*stream);
+    let head = read_http_head(&mut reader)?;
+    // the client may not wait for our answer before sending more
+    let buffered = reader.buffer().to_vec();
+    drop(reader);
+    let head = String::from_utf8(head).map_err(|e| Error::other(e.to_string()))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut request = request_line.split_whitespace();
+    let (method, uri, version) = match (request.next(), request.next(), request.next()) {
+        (Some(m), Some(u), Some(v)) => (m, u, v),
+        _ => {
+            reply_http(stream, 400, "Bad Request")?;
+            return Err(Error::other(format!("Invalid request line: {}", request_line)));
+        }
+    };
+
+    let mut headers = Vec::new();
+    let mut auth = None;
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        let name = name.trim().to_ascii_lowercase();
+        if name == "proxy-authorization" {
+            auth = http_basic_credentials(value);
+        } else if !name.starts_with("proxy-") && !HOP_BY_HOP.contains(&name.as_str()) {
+            match name.as_str() {
+                "content-length" => content_length = Some(value.trim()),
+                "transfer-encoding" => chunked = true,
+                _ => {}
+            }
+            headers.push(line);
+        }
+    }
+
+    let params = match auth {
+        Some((user, pass)) => authorize(&user, &pass, credentials),
+        None if credentials.is_none() => Ok(SessionParams::default()),
+        None => Err(Error::other("Missing proxy credentials")),
+    };
+    let params = match params {
+        Ok(params) => params,
+        Err(e) => {
+            stream.write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                Proxy-Authenticate: Basic realm=\"qproxy\"\r\n\
+                Content-Length: 0\r\n\r\n",
+            )?;
+            return Err(e);
+        }
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        return Ok(Handshake {
+            protocol: Protocol::Http,
+            params,
+            method: "CONNECT".to_string(),
+            target: Target::parse_authority(uri, 443)?,
+            pending: buffered,
+            body_len: None,
+        });
+    }
+
+    // plain forward request: rewrite the absolute URI to origin form
+    let Some(rest) = uri.strip_prefix("http://") else {
+        reply_http(stream, 400, "Bad Request")?;
+        return Err(Error::other(format!("Unsupported request URI: {}", uri)));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let target = Target::parse_authority(authority, 80)?;
+    // one request per connection, the next one may be for another host
+    if chunked {
+        reply_http(stream, 411, "Length Required")?;
+        return Err(Error::other("Chunked request bodies are not forwarded"));
+    }
+    let body_len = match content_length.map(str::parse::<u64>) {
+        Some(Ok(len)) => len,
+        Some(Err(_)) => {
+            reply_http(stream, 400, "Bad Request")?;
+            return Err(Error::other("Invalid Content-Length"));
+        }
+        None => 0,
+    };
+    let early = buffered.len().min(body_len.min(usize::MAX as u64) as usize);
+    let mut pending = format!("{} {} {}\r\n", method, path, version);
+    for header in headers {
+        pending.push_str(header);
+        pending.push_str("\r\n");
+    }
+    pending.push_str("Connection: close\r\n\r\n");
+    let mut pending = pending.into_bytes();
+    pending.extend_from_slice(&buffered[..early]);
+
+    Ok(Handshake {
+        protocol: Protocol::Http,
+        params,
+        method: method.to_string(),
+        target,
+        pending,
+        body_len: Some(body_len - early as u64),
+    })
+}
+
+/// Read a SOCKS5 reply (VER REP RSV ATYP BND.ADDR BND.PORT) and return its raw bytes.
+pub fn read_socks_reply(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let mut reply = header.to_vec();
+    let addr_len = match header[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let len = read_u8(stream)?;
+            reply.push(len);
+            len as usize
+        }
+        atyp => return Err(Error::other(format!("Unsupported address type: {}", atyp))),
+    };
+    let mut rest = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut rest)?;
+    reply.extend_from_slice(&rest);
+    Ok(reply)
+}
+
+pub fn reply_socks_failure(stream: &mut TcpStream, code: u8) -> Result<()> {
+    stream.write_all(&[SOCKS_VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+pub fn reply_http(stream: &mut TcpStream, status: u16, reason: &str) -> Result<()> {
+    stream.write_all(
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status, reason
+        )
+        .as_bytes(),
+    )
+}
+
+/// Tell the client the tunnel is open, relaying the upstream SOCKS reply when possible.
+pub fn reply_success(stream: &mut TcpStream, handshake: &Handshake, upstream_reply: &[u8]) -> Result<()> {
+    match handshake.protocol {
+        Protocol::Socks5 => stream.write_all(upstream_reply),
+        Protocol::Http if handshake.method == "CONNECT" => {
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        }
+        Protocol::Http => Ok(()),
+    }
+}
+
+/// Tell the client no upstream is available to reach the target through.
+pub fn reply_unavailable(stream: &mut TcpStream, protocol: Protocol) -> Result<()> {
+    match protocol {
+        Protocol::Socks5 => reply_socks_failure(stream, REPLY_GENERAL_FAILURE),
+        Protocol::Http => reply_http(stream, 503, "Service Unavailable"),
+    }
+}
+
+/// Tell the client the upstream could not be reached.
+pub fn reply_failure(stream: &mut TcpStream, protocol: Protocol) -> Result<()> {
+    match protocol {
+        Protocol::Socks5 => reply_socks_failure(stream, REPLY_GENERAL_FAILURE),
+        Protocol::Http => reply_http(stream, 502, "Bad Gateway"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_authority() {
+        let target = Target::parse_authority("example.com:8443", 443).unwrap();
+        assert_eq!(target.to_string(), "example.com:8443");
+        let target = Target::parse_authority("example.com", 80).unwrap();
+        assert_eq!(target.port, 80);
+        let target = Target::parse_authority("[::1]:9000", 80).unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.to_string(), "[::1]:9000");
+
+        let longest = "a".repeat(255);
+        let target = Target::parse_authority(&format!("{}:443", longest), 80).unwrap();
+        assert_eq!(target.to_socks_request()[4], 255);
+        assert!(Target::parse_authority(&format!("{}a:443", longest), 80).is_err());
+    }
+
+    #[test]
+    fn test_authorize() {
+        let credentials = ProxyAuth::from_str("acme-prod:secret").unwrap();
+        let params = authorize("acme-prod", "secret", Some(&credentials)).unwrap();
+        assert!(params.is_empty());
+        let params = authorize("acme-prod-session-a", "secret", Some(&credentials)).unwrap();
+        assert_eq!((params.user.as_str(), params.session.as_deref()), ("acme-prod", Some("a")));
+        assert!(authorize("acme-prodx", "secret", Some(&credentials)).is_err());
+        assert!(authorize("acme-prod", "wrong", Some(&credentials)).is_err());
+
+        let params = authorize("my-laptop", "", None).unwrap();
+        assert_eq!(params.user, "my-laptop");
+        assert!(params.is_empty());
+        assert_eq!(authorize("user-country-de", "", None).unwrap().country.as_deref(), Some("DE"));
+    }
+
+    /// Run the handshake of a client sending `request` in one go.
+    fn accept_request(request: &'static [u8]) -> Handshake {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(request).unwrap();
+            // keep the connection open until the handshake is done
+            let _ = client.read(&mut [0u8; 1]);
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        accept(&mut stream, None).unwrap()
+    }
+
+    #[test]
+    fn test_early_bytes() {
+        let handshake = accept_request(b"CONNECT example.com:443 HTTP/1.1\r\n\r\nhello");
+        assert_eq!(handshake.pending, b"hello");
+
+        let handshake = accept_request(
+            b"POST http://example.com/ HTTP/1.1\r\nContent-Length: 6\r\n\r\nbodyGET http://other/ HTTP/1.1\r\n\r\n",
+        );
+        assert!(handshake.pending.ends_with(b"Connection: close\r\n\r\nbodyGE"));
+        assert_eq!(handshake.body_len, Some(0));
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        // user-session-a:secret
+        let value = "Basic dXNlci1zZXNzaW9uLWE6c2VjcmV0";
+        let (user, pass) = http_basic_credentials(value).unwrap();
+        assert_eq!(user, "user-session-a");
+        assert_eq!(pass, "secret");
+    }
+}
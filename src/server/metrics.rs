@@ -0,0 +1,170 @@
+use crate::Proxy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Label of an upstream error, by kind of failure.
+pub fn error_kind(e: &Error) -> &'static str {
+    match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => "timeout",
+        ErrorKind::ConnectionRefused => "refused",
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => {
+            "reset"
+        }
+        ErrorKind::PermissionDenied => "denied",
+        ErrorKind::InvalidData => "protocol",
+        _ => "other",
+    }
+}
+
+/// Prometheus metrics of the listeners and the pool. The `upstream` label is
+/// left empty unless per-proxy labels are on, which bounds the number of series.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    per_proxy: bool,
+    accepted: IntCounterVec,
+    active: IntGaugeVec,
+    dials: IntCounterVec,
+    handshake: HistogramVec,
+    relayed: IntCounterVec,
+    rotations: IntCounterVec,
+    checks: IntCounterVec,
+    pool: IntGaugeVec,
+}
+
+/// An open client connection, counted as active until dropped.
+pub struct Active(IntGauge);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new(false)
+    }
+}
+
+impl Metrics {
+    pub fn new(per_proxy: bool) -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let metric = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(metric.clone())).unwrap();
+            metric
+        };
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            let metric = IntGaugeVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(metric.clone())).unwrap();
+            metric
+        };
+        let handshake = HistogramVec::new(
+            HistogramOpts::new(
+                "qproxy_upstream_handshake_seconds",
+                "Time to connect to the target through the upstream",
+            )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["listener", "upstream"],
+        )
+        .unwrap();
+        registry.register(Box::new(handshake.clone())).unwrap();
+        Metrics {
+            accepted: counter(
+                "qproxy_connections_accepted_total",
+                "Client connections accepted",
+                &["listener"],
+            ),
+            active: gauge("qproxy_connections_active", "Client connections open", &["listener"]),
+            dials: counter(
+                "qproxy_upstream_dials_total",
+                "Connections to a target through an upstream",
+                &["listener", "upstream", "result", "error"],
+            ),
+            handshake,
+            relayed: counter(
+                "qproxy_relayed_bytes_total",
+                "Bytes relayed, sent to or received from the target",
+                &["listener", "upstream", "direction"],
+            ),
+            rotations: counter("qproxy_rotations_total", "Listener moves to another upstream", &["listener"]),
+            checks: counter(
+                "qproxy_health_checks_total",
+                "Proxy checks by outcome",
+                &["upstream", "result"],
+            ),
+            pool: gauge(
+                "qproxy_pool_proxies",
+                "Proxies of the pool by state",
+                &["state"],
+            ),
+            registry,
+            per_proxy,
+        }
+    }
+
+    fn upstream(&self, proxy: &Proxy) -> String {
+        if self.per_proxy {
+            proxy.addr()
+        } else {
+            String::new()
+        }
+    }
+
+    pub fn accepted(&self, listener: u16) -> Active {
+        let listener = listener.to_string();
+        self.accepted.with_label_values(&[&listener]).inc();
+        let active = self.active.with_label_values(&[&listener]);
+        active.inc();
+        Active(active)
+    }
+
+    pub fn dial(&self, listener: u16, proxy: &Proxy, result: Result<Duration, &Error>) {
+        let (listener, upstream) = (listener.to_string(), self.upstream(proxy));
+        match result {
+            Ok(elapsed) => {
+                self.dials.with_label_values(&[&listener, &upstream, "ok", ""]).inc();
+                self.handshake
+                    .with_label_values(&[&listener, &upstream])
+                    .observe(elapsed.as_secs_f64());
+            }
+            Err(e) => {
+                self.dials
+                    .with_label_values(&[&listener, &upstream, "error", error_kind(e)])
+                    .inc();
+            }
+        }
+    }
+
+    pub fn relayed(&self, listener: u16, proxy: &Proxy, sent: u64, received: u64) {
+        let (listener, upstream) = (listener.to_string(), self.upstream(proxy));
+        self.relayed.with_label_values(&[&listener, &upstream, "sent"]).inc_by(sent);
+        self.relayed.with_label_values(&[&listener, &upstream, "received"]).inc_by(received);
+    }
+
+    pub fn rotation(&self, listener: u16) {
+        self.rotations.with_label_values(&[&listener.to_string()]).inc();
+    }
+
+    pub fn check(&self, proxy: &Proxy, ok: bool) {
+        let result = if ok { "ok" } else { "error" };
+        self.checks.with_label_values(&[&self.upstream(proxy), result]).inc();
+    }
+
+    pub fn set_pool(&self, state: &str, count: usize) {
+        self.pool.with_label_values(&[state]).set(count as i64);
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8_lossy(&buffer).to_string()
+    }
+}
@@ -1,5 +1,24 @@
 mod proxy_server;
 mod proxy_model;
+mod handshake;
+mod session;
+mod probe;
+mod anonymity;
+mod metrics;
+mod access_log;
+mod connections;
+mod exhaustion;
+#[cfg(test)]
+pub(crate) mod testing;
 
-pub use proxy_server::ProxyServer;
-pub use proxy_model::Proxy;
\ No newline at end of file
+pub use proxy_server::{ProxyServer, Usage};
+pub use proxy_model::{Proxy, ProxyAuth};
+pub use handshake::{Protocol, Target};
+pub use session::{DestinationOutcome, SessionParams, UpstreamSelector};
+pub use probe::{Probe, ProbeConfig};
+pub use anonymity::Anonymity;
+pub use metrics::Metrics;
+pub use access_log::{AccessFormat, AccessLog, AccessRecord, Outcome};
+pub use connections::Connection;
+pub use exhaustion::Exhaustion;
+pub(crate) use handshake::constant_time_eq;
@@ -0,0 +1,434 @@
+use crate::server::anonymity::{self, Anonymity};
+use crate::server::handshake::{Protocol, Target};
+use crate::{Proxy, ProxyServer};
+use log::warn;
+use native_tls::TlsConnector;
+use reqwest::Url;
+use std::fmt::Display;
+use std::io::{Error, Read, Result, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Max bytes of an HTTP probe response that are inspected.
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// One health check performed through an upstream proxy.
+///
+/// Written as `kind[=value][;option...]`:
+///
+/// * `tcp` - TCP connect to the proxy only
+/// * `handshake` - SOCKS5 greeting and authentication
+/// * `connect=host:port` - open a tunnel to the target
+/// * `http=http://host/path;status=200;body=ok` - GET through the tunnel, `https` urls use TLS
+/// * `tls=host[:port][;insecure]` - TLS handshake with the target through the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    TcpConnect,
+    Handshake,
+    Connect(Target),
+    HttpGet {
+        url: String,
+        status: Option<u16>,
+        body: Option<String>,
+        insecure: bool,
+    },
+    Tls {
+        target: Target,
+        insecure: bool,
+    },
+}
+
+/// Handshake only, so an unconfigured check needs nothing but the proxy itself.
+impl Default for Probe {
+    fn default() -> Self {
+        Probe::Handshake
+    }
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut options = s.split(';');
+        let head = options.next().unwrap_or_default();
+        let (kind, value) = head.split_once('=').unwrap_or((head, ""));
+        let mut status = None;
+        let mut body = None;
+        let mut insecure = false;
+        for option in options {
+            match option.split_once('=') {
+                Some(("status", v)) => {
+                    status = Some(v.parse().map_err(|_| format!("Invalid status: {}", v))?)
+                }
+                Some(("body", v)) => body = Some(v.to_string()),
+                None if option == "insecure" => insecure = true,
+                _ => return Err(format!("Unknown probe option: {}", option)),
+            }
+        }
+        match kind {
+            "tcp" => Ok(Probe::TcpConnect),
+            "handshake" => Ok(Probe::Handshake),
+            "connect" => Ok(Probe::Connect(
+                Target::parse_authority(value, 80).map_err(|e| e.to_string())?,
+            )),
+            "http" => {
+                Url::parse(value).map_err(|e| format!("Invalid probe url {}: {}", value, e))?;
+                Ok(Probe::HttpGet {
+                    url: value.to_string(),
+                    status,
+                    body,
+                    insecure,
+                })
+            }
+            "tls" => Ok(Probe::Tls {
+                target: Target::parse_authority(value, 443).map_err(|e| e.to_string())?,
+                insecure,
+            }),
+            _ => Err(format!("Unknown probe: {}", kind)),
+        }
+    }
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::TcpConnect => write!(f, "tcp"),
+            Probe::Handshake => write!(f, "handshake"),
+            Probe::Connect(target) => write!(f, "connect={}", target),
+            Probe::HttpGet { url, .. } => write!(f, "http={}", url),
+            Probe::Tls { target, .. } => write!(f, "tls={}", target),
+        }
+    }
+}
+
+/// The probes run against every proxy and how many must pass.
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub probes: Vec<Probe>,
+    /// Passing probes required, `0` means all of them.
+    pub quorum: usize,
+    pub timeout: Duration,
+    /// "What is my IP" endpoint queried after the probes pass.
+    pub egress_url: Option<String>,
+    /// Header-echo endpoint used to classify the anonymity of the proxy.
+    pub anonymity_url: Option<String>,
+    /// Our own address, a proxy forwarding it is transparent.
+    pub client_ip: Option<IpAddr>,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            probes: vec![Probe::default()],
+            quorum: 0,
+            timeout: Duration::from_secs(10),
+            egress_url: None,
+            anonymity_url: None,
+            client_ip: None,
+        }
+    }
+}
+
+impl ProbeConfig {
+    fn required(&self) -> usize {
+        match self.quorum {
+            0 => self.probes.len(),
+            n => n.min(self.probes.len()),
+        }
+    }
+}
+
+fn tls_connector(insecure: bool) -> Result<TlsConnector> {
+    TlsConnector::builder()
+        .danger_accept_invalid_certs(insecure)
+        .danger_accept_invalid_hostnames(insecure)
+        .build()
+        .map_err(Error::other)
+}
+
+/// GET `url` on `stream`, as a forwarded request to the HTTP proxy `forward`
+/// when there is one.
+fn http_get<S: Read + Write>(stream: &mut S, url: &Url, forward: Option<&Proxy>) -> Result<(u16, String)> {
+    let host = url.host_str().unwrap_or_default();
+    let path = match (forward, url.query()) {
+        (Some(_), _) => url.as_str().to_string(),
+        (None, Some(query)) => format!("{}?{}", url.path(), query),
+        (None, None) => url.path().to_string(),
+    };
+    let authorization = forward.map(ProxyServer::proxy_authorization).unwrap_or_default();
+    stream.write_all(
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: qproxy\r\n{}Connection: close\r\n\r\n",
+            path, host, authorization
+        )
+        .as_bytes(),
+    )?;
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response).to_string();
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::other("Invalid HTTP response"))?;
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+    Ok((status, body))
+}
+
+/// GET `url` over a fresh connection to `proxy`. Plain `http` urls are sent
+/// to HTTP proxies as forwarded requests, like clients do, so the headers
+/// they add are seen. Everything else goes through a tunnel.
+pub(crate) fn fetch(proxy: &Proxy, url: &Url, insecure: bool, timeout: Duration) -> Result<(u16, String)> {
+    let mut stream = ProxyServer::dial(proxy, Some(timeout))?;
+    if proxy.protocol == Protocol::Http && url.scheme() == "http" {
+        return http_get(&mut stream, url, Some(proxy));
+    }
+    ProxyServer::negotiate(&mut stream, proxy)?;
+    let target = Target {
+        host: url.host_str().unwrap_or_default().to_string(),
+        port: url.port_or_known_default().unwrap_or(80),
+    };
+    ProxyServer::request(&mut stream, proxy, &target)?;
+    if url.scheme() == "https" {
+        let mut tls = tls_connector(insecure)?
+            .connect(&target.host, stream)
+            .map_err(|e| Error::other(format!("TLS handshake failed: {}", e)))?;
+        http_get(&mut tls, url, None)
+    } else {
+        http_get(&mut stream, url, None)
+    }
+}
+
+fn run_probe(proxy: &Proxy, probe: &Probe, timeout: Duration) -> Result<()> {
+    if let Probe::HttpGet {
+        url,
+        status,
+        body,
+        insecure,
+    } = probe
+    {
+        let url = Url::parse(url).map_err(Error::other)?;
+        let (code, text) = fetch(proxy, &url, *insecure, timeout)?;
+        let status_ok = match status {
+            Some(expected) => code == *expected,
+            None => (200..400).contains(&code),
+        };
+        if !status_ok {
+            return Err(Error::other(format!("Unexpected status {} from {}", code, url)));
+        }
+        if let Some(expected) = body {
+            if !text.contains(expected.as_str()) {
+                return Err(Error::other(format!("Body of {} misses {:?}", url, expected)));
+            }
+        }
+        return Ok(());
+    }
+
+    let mut stream = ProxyServer::dial(proxy, Some(timeout))?;
+    if *probe == Probe::TcpConnect {
+        return Ok(());
+    }
+    ProxyServer::negotiate(&mut stream, proxy)?;
+    match probe {
+        Probe::Connect(target) => ProxyServer::request(&mut stream, proxy, target).map(|_| ()),
+        Probe::Tls { target, insecure } => {
+            ProxyServer::request(&mut stream, proxy, target)?;
+            tls_connector(*insecure)?
+                .connect(&target.host, stream)
+                .map(|_| ())
+                .map_err(|e| Error::other(format!("TLS handshake failed: {}", e)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Extract the caller's address from a "what is my IP" response: a bare IP
+/// or a JSON object with an `ip`, `origin` or `query` field.
+fn parse_egress(body: &str) -> Option<IpAddr> {
+    let body = body.trim();
+    if let Ok(ip) = IpAddr::from_str(body) {
+        return Some(ip);
+    }
+    let json: serde_json::Value = serde_json::from_str(body).ok()?;
+    ["ip", "origin", "query"].iter().find_map(|key| {
+        let value = json.get(key)?.as_str()?;
+        // httpbin lists every hop in `origin`, the last one is the egress
+        IpAddr::from_str(value.rsplit(',').next()?.trim()).ok()
+    })
+}
+
+/// Ask the egress endpoint which address the proxy exits from.
+pub fn discover_egress(proxy: &Proxy, url: &str, timeout: Duration) -> Result<IpAddr> {
+    let url = Url::parse(url).map_err(Error::other)?;
+    let (code, body) = fetch(proxy, &url, false, timeout)?;
+    if !(200..300).contains(&code) {
+        return Err(Error::other(format!("Unexpected status {} from {}", code, url)));
+    }
+    parse_egress(&body).ok_or_else(|| Error::other(format!("No IP address in response of {}", url)))
+}
+
+/// Send a request to the header-echo endpoint and classify what came through.
+pub fn classify_anonymity(proxy: &Proxy, url: &str, config: &ProbeConfig) -> Result<Anonymity> {
+    let url = Url::parse(url).map_err(Error::other)?;
+    let (code, body) = fetch(proxy, &url, false, config.timeout)?;
+    if !(200..300).contains(&code) {
+        return Err(Error::other(format!("Unexpected status {} from {}", code, url)));
+    }
+    let headers = anonymity::parse_echoed_headers(&body);
+    let egress = proxy.egress_ip.as_ref().and_then(|ip| IpAddr::from_str(ip).ok());
+    Ok(anonymity::classify(&headers, config.client_ip, egress))
+}
+
+/// Run every probe against `proxy`, it passes when the quorum is reached.
+/// The latency is the mean of the passing probes.
+pub fn check(proxy: &Proxy, config: &ProbeConfig) -> Result<Proxy> {
+    let mut passed = Vec::new();
+    let mut last_error = None;
+    for probe in config.probes.iter() {
+        let start = Instant::now();
+        match run_probe(proxy, probe, config.timeout) {
+            Ok(_) => passed.push(start.elapsed()),
+            Err(e) => last_error = Some(Error::other(format!("{}: {}", probe, e))),
+        }
+    }
+    if passed.len() < config.required() {
+        return Err(last_error.unwrap_or_else(|| Error::other("No probes configured")));
+    }
+    let mut checked = proxy.clone();
+    checked.latency = passed.iter().sum::<Duration>() / passed.len().max(1) as u32;
+    checked.is_working = true;
+    if let Some(url) = &config.egress_url {
+        // a failed lookup keeps the last known egress, it doesn't fail the check
+        match discover_egress(proxy, url, config.timeout) {
+            Ok(ip) => checked.egress_ip = Some(ip.to_string()),
+            Err(e) => warn!("Failed to discover egress of {}: {}", proxy, e),
+        }
+    }
+    if let Some(url) = &config.anonymity_url {
+        match classify_anonymity(&checked, url, config) {
+            Ok(level) => checked.anonymity = Some(level),
+            Err(e) => warn!("Failed to classify anonymity of {}: {}", proxy, e),
+        }
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::{dead_proxy, spawn_http, spawn_upstream};
+
+    #[test]
+    fn test_parse_probe() {
+        assert_eq!(Probe::from_str("tcp").unwrap(), Probe::TcpConnect);
+        assert_eq!(
+            Probe::from_str("tls=example.com;insecure").unwrap(),
+            Probe::Tls {
+                target: Target {
+                    host: "example.com".to_string(),
+                    port: 443
+                },
+                insecure: true
+            }
+        );
+        assert_eq!(
+            Probe::from_str("connect=[::1]:8443").unwrap(),
+            Probe::Connect(Target {
+                host: "::1".to_string(),
+                port: 8443
+            })
+        );
+        assert_eq!(Probe::from_str("tls=[::1]").unwrap().to_string(), "tls=[::1]:443");
+        assert_eq!(
+            Probe::from_str("http=http://127.0.0.1:8000/health;status=204;body=ok").unwrap(),
+            Probe::HttpGet {
+                url: "http://127.0.0.1:8000/health".to_string(),
+                status: Some(204),
+                body: Some("ok".to_string()),
+                insecure: false,
+            }
+        );
+        assert!(Probe::from_str("http=not a url").is_err());
+        assert!(Probe::from_str("ping").is_err());
+        assert_eq!(ProbeConfig::default().probes, vec![Probe::Handshake]);
+    }
+
+    #[test]
+    fn test_probes_against_stand_in() {
+        let proxy = spawn_upstream("hello from stand-in");
+        let config = |probes: &[&str], quorum| ProbeConfig {
+            probes: probes.iter().map(|p| Probe::from_str(p).unwrap()).collect(),
+            quorum,
+            timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+
+        let all = config(
+            &["tcp", "handshake", "connect=example.com:80", "http=http://example.com/;body=stand-in"],
+            0,
+        );
+        let checked = check(&proxy, &all).unwrap();
+        assert!(checked.is_working);
+
+        let wrong_body = config(&["http=http://example.com/;body=missing"], 0);
+        assert!(check(&proxy, &wrong_body).is_err());
+        let wrong_status = config(&["http=http://example.com/;status=404"], 0);
+        assert!(check(&proxy, &wrong_status).is_err());
+
+        let quorum = config(&["handshake", "http=http://example.com/;status=404"], 1);
+        assert!(check(&proxy, &quorum).is_ok());
+        assert!(check(&dead_proxy(), &quorum).is_err());
+    }
+
+    #[test]
+    fn test_discover_egress() {
+        assert_eq!(parse_egress(" 203.0.113.7\n"), IpAddr::from_str("203.0.113.7").ok());
+        assert_eq!(
+            parse_egress(r#"{"origin": "10.0.0.1, 203.0.113.8"}"#),
+            IpAddr::from_str("203.0.113.8").ok()
+        );
+        assert_eq!(parse_egress("<html>"), None);
+
+        let proxy = spawn_upstream(r#"{"ip": "2001:db8::1"}"#);
+        let config = ProbeConfig {
+            probes: vec![Probe::Handshake],
+            egress_url: Some("http://whoami.test/".to_string()),
+            ..Default::default()
+        };
+        let checked = check(&proxy, &config).unwrap();
+        assert_eq!(checked.egress_ip.as_deref(), Some("2001:db8::1"));
+    }
+
+    #[test]
+    fn test_classify_anonymity() {
+        let proxy = spawn_upstream(r#"{"headers": {"Host": "echo.test", "Via": "1.1 proxy"}}"#);
+        let config = ProbeConfig {
+            probes: vec![Probe::Handshake],
+            anonymity_url: Some("http://echo.test/headers".to_string()),
+            ..Default::default()
+        };
+        let checked = check(&proxy, &config).unwrap();
+        assert_eq!(checked.anonymity, Some(Anonymity::Anonymous));
+
+        // an HTTP proxy only adds its headers to forwarded requests
+        let url = spawn_http(|request| {
+            if !request.starts_with("GET http://echo.test/headers ") {
+                return "HTTP/1.1 400 Bad Request\r\n\r\n".to_string();
+            }
+            let body = r#"{"headers": {"Host": "echo.test", "X-Forwarded-For": "198.51.100.7"}}"#;
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+        });
+        let forwarding = Proxy::from_str(&url).unwrap();
+        let config = ProbeConfig {
+            probes: vec![Probe::TcpConnect],
+            client_ip: IpAddr::from_str("198.51.100.7").ok(),
+            ..config
+        };
+        let checked = check(&forwarding, &config).unwrap();
+        assert_eq!(checked.anonymity, Some(Anonymity::Transparent));
+    }
+}
@@ -1,15 +1,25 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::str::FromStr;
 use std::time::Duration;
+use crate::server::anonymity::Anonymity;
+use crate::server::handshake::Protocol;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ProxyAuth {
     pub user: String,
     pub pass: String,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+impl std::fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth").field("user", &self.user).field("pass", &"***").finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Proxy {
     pub ip: String,
     pub port: u16,
@@ -17,47 +27,140 @@ pub struct Proxy {
     pub is_working: bool,
     pub latency: Duration,
     pub used: bool,
+    /// ISO country code of the exit, upper case.
+    #[serde(default)]
+    pub country: Option<String>,
+    /// Name of the pool this proxy belongs to.
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Address the proxy exits from, as seen by the egress endpoint.
+    #[serde(default)]
+    pub egress_ip: Option<String>,
+    /// Anonymity level found by the header-echo check.
+    #[serde(default)]
+    pub anonymity: Option<Anonymity>,
+    /// Autonomous system of the exit, from the ASN database.
+    #[serde(default)]
+    pub asn: Option<u32>,
+    #[serde(default)]
+    pub asn_org: Option<String>,
+    /// The proxy or its exit is listed in a blocklist.
+    #[serde(default)]
+    pub blocklisted: bool,
+    /// Free-form labels, from the list entry and the pool it was loaded from.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Protocol the upstream speaks.
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Relative share of the selections, 1 when unset.
+    #[serde(default)]
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// End of the rental, the proxy isn't selected afterwards.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Taken out of the selection by an operator, checks still run.
+    #[serde(default)]
+    pub paused: bool,
 }
 
+impl Proxy {
+    /// `ip:port`, identifies the upstream regardless of credentials and state.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    /// The list form with the password in clear, `Display` masks it. Only for
+    /// writing proxy lists, never for logs.
+    pub fn expose(&self) -> String {
+        self.format(true)
+    }
+
+    fn format(&self, password: bool) -> String {
+        let mut str = match &self.auth {
+            Some(auth) if password => format!("{}:{}:{}:{}", self.ip, self.port, auth.user, auth.pass),
+            Some(auth) => format!("{}:{}:{}:***", self.ip, self.port, auth.user),
+            None => format!("{}:{}", self.ip, self.port),
+        };
+        if self.protocol == Protocol::Http {
+            str = format!("http://{}", str);
+        }
+        str
+    }
+}
+
+/// Parses `user:pass`, the password may contain colons.
+impl FromStr for ProxyAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((user, pass)) if !user.is_empty() => Ok(ProxyAuth {
+                user: user.to_string(),
+                pass: pass.to_string(),
+            }),
+            _ => Err("Invalid credentials, expected user:pass".to_string()),
+        }
+    }
+}
+
+/// Parses `ip:port[:user:pass]` and `scheme://[user:pass@]ip:port`, the
+/// scheme being `socks5` or `http`. The password may contain colons.
 impl FromStr for Proxy {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split(":").collect();
+        let s = s.trim();
+        let (protocol, rest) = match s.split_once("://") {
+            Some((scheme, rest)) => (Protocol::from_str(scheme)?, rest),
+            None => (Protocol::Socks5, s),
+        };
+        let (auth, host) = match rest.rsplit_once('@') {
+            Some((auth, host)) => (Some(auth), host),
+            None => (None, rest),
+        };
+        let parts: Vec<&str> = host.trim_end_matches('/').splitn(4, ':').collect();
         if parts.len() < 2 {
-            return Err("Invalid proxy string".to_string());
-        }
-        let uri = parts[0].parse().expect("Invalid proxy IP");
-        let port = parts[1].parse().expect("Invalid proxy port");
-        if parts.len() == 2 {
-            return Ok(Proxy {
-                ip: uri,
-                port,
-                auth: None,
-                is_working: false,
-                latency: Duration::from_secs(0),
-                used: true,
-            });
+            return Err(format!("Invalid proxy string: {}", s));
         }
-        let user = parts[2].parse().expect("Invalid proxy user");
-        let pass = parts[3].parse().expect("Invalid proxy pass");
+        let port = parts[1]
+            .parse()
+            .map_err(|_| format!("Invalid proxy port: {}", parts[1]))?;
+        let auth = match (auth, parts.len()) {
+            (Some(auth), 2) => auth.split_once(':'),
+            (None, 2) => None,
+            (None, 4) => Some((parts[2], parts[3])),
+            _ => return Err(format!("Invalid proxy string: {}", s)),
+        };
         Ok(Proxy {
-            ip: uri,
+            ip: parts[0].to_string(),
             port,
-            auth: Some(ProxyAuth { user, pass }),
+            auth: auth.map(|(user, pass)| ProxyAuth {
+                user: user.to_string(),
+                pass: pass.to_string(),
+            }),
             is_working: false,
             latency: Duration::from_secs(0),
             used: true,
+            protocol,
+            ..Default::default()
         })
     }
 }
 
+/// `ip:port:user:***`, see [`Proxy::expose`] for the password.
 impl Display for Proxy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let str = match &self.auth {
-            Some(auth) => format!("{}:{}:{}:{}", self.ip, self.port, auth.user, auth.pass),
-            None => format!("{}:{}", self.ip, self.port),
-        };
-        write!(f, "{}", str)
+        write!(f, "{}", self.format(false))
     }
 }
\ No newline at end of file
@@ -1,45 +1,190 @@
+use crate::server::access_log::{AccessLog, AccessRecord, Outcome};
+use crate::server::connections::{copy_counted, Connection, Copied, Counter, Registry, Relay};
+use crate::server::exhaustion::Exhaustion;
+use crate::server::handshake::{self, Handshake, Protocol, Target, AUTHENTICATION_VERSION, SOCKS_VERSION};
+use crate::server::metrics::Metrics;
+use crate::server::probe::{self, ProbeConfig};
+use crate::server::proxy_model::ProxyAuth;
+use crate::server::session::{DestinationOutcome, SessionParams, UpstreamSelector};
 use crate::Proxy;
-use log::{error, info};
-use std::io::{copy, Error, Read, Result, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use base64::Engine;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{field, info_span};
 
-const SOCKS_VERSION: u8 = 0x05;
-const AUTHENTICATION_VERSION: u8 = 0x01;
+/// Id of the next client connection, shared by every listener.
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Bytes of a forwarded HTTP response kept to tell a ban apart.
+const RESPONSE_HEAD_LEN: usize = 4096;
+
+/// How often a queued connection looks for an upstream again.
+const QUEUE_POLL: Duration = Duration::from_millis(100);
+
+/// Upstream of the connections made without a proxy.
+const DIRECT: &str = "direct";
+
+/// SOCKS success reply for tunnels that did not get one from a SOCKS upstream.
+const REPLY_SUCCEEDED: [u8; 10] = [SOCKS_VERSION, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+
+/// Traffic through the current proxy of a server since it switched to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub connections: u64,
+    /// Bytes relayed both ways, counted once a connection closes.
+    pub bytes: u64,
+    /// Consecutive connections the proxy failed to open.
+    pub failures: u32,
+}
 
 #[derive(Debug, Clone)]
 pub struct ProxyServer {
     addr: SocketAddr,
-    proxy: Arc<Mutex<Proxy>>,
+    /// None while no upstream is available for the server.
+    proxy: Arc<Mutex<Option<Proxy>>>,
     should_stop: Arc<Mutex<bool>>,
     started_at: Arc<Mutex<std::time::Instant>>,
+    usage: Arc<Mutex<Usage>>,
+    usage_changed: Option<Arc<Notify>>,
+    connections: Arc<Registry>,
+    credentials: Option<ProxyAuth>,
+    selector: Option<Arc<dyn UpstreamSelector>>,
+    exhaustion: Exhaustion,
+    queue_timeout: Duration,
+    probes: Arc<ProbeConfig>,
+    protocols: Vec<Protocol>,
+    metrics: Option<Arc<Metrics>>,
+    access_log: Option<Arc<AccessLog>>,
 }
 
 impl ProxyServer {
-    pub fn new_with_proxy(port: i16, proxy: Proxy) -> Result<ProxyServer> {
+    pub fn new_with_proxy(port: u16, proxy: Proxy) -> Result<ProxyServer> {
+        let server = ProxyServer::new(port)?;
+        *server.proxy.lock().unwrap() = Some(proxy);
+        Ok(server)
+    }
+
+    /// A server without a proxy, its connections follow the exhaustion policy
+    /// until `set_proxy`.
+    pub fn new(port: u16) -> Result<ProxyServer> {
         let addr = format!("127.0.0.1:{port}").parse().unwrap();
         Ok(ProxyServer {
             addr,
-            proxy: Arc::new(Mutex::new(proxy)),
+            proxy: Arc::new(Mutex::new(None)),
             should_stop: Arc::new(Mutex::new(false)),
             started_at: Arc::new(Mutex::new(std::time::Instant::now())),
+            usage: Arc::new(Mutex::new(Usage::default())),
+            usage_changed: None,
+            connections: Arc::new(Registry::default()),
+            credentials: None,
+            selector: None,
+            exhaustion: Exhaustion::default(),
+            queue_timeout: Duration::ZERO,
+            probes: Arc::new(ProbeConfig::default()),
+            protocols: Vec::new(),
+            metrics: None,
+            access_log: None,
         })
     }
 
+    /// Listen on `ip` instead of the loopback address.
+    pub fn with_bind(mut self, ip: IpAddr) -> Self {
+        self.addr.set_ip(ip);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Write one record per client connection to `access_log`.
+    pub fn with_access_log(mut self, access_log: Option<Arc<AccessLog>>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Notify `usage_changed` whenever the usage of the current proxy changes,
+    /// so rotation triggers on it fire right away.
+    pub fn with_usage_changed(mut self, usage_changed: Arc<Notify>) -> Self {
+        self.usage_changed = Some(usage_changed);
+        self
+    }
+
+    /// Inbound protocols accepted, all of them when empty.
+    pub fn with_protocols(mut self, protocols: Vec<Protocol>) -> Self {
+        self.protocols = protocols;
+        self
+    }
+
+    /// Require clients to authenticate with these credentials. The username may
+    /// still carry session parameters after the account name.
+    pub fn with_credentials(mut self, credentials: Option<ProxyAuth>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Route connections carrying session parameters through `selector`
+    /// instead of the server's current proxy.
+    pub fn with_selector(mut self, selector: Arc<dyn UpstreamSelector>) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// What happens to new connections while no upstream is available,
+    /// `queue_timeout` is how long `Exhaustion::Queue` holds them.
+    pub fn with_exhaustion(mut self, exhaustion: Exhaustion, queue_timeout: Duration) -> Self {
+        self.exhaustion = exhaustion;
+        self.queue_timeout = queue_timeout;
+        self
+    }
+
+    /// Probes a new proxy has to pass before the server switches to it.
+    pub fn with_probes(mut self, probes: Arc<ProbeConfig>) -> Self {
+        self.probes = probes;
+        self
+    }
+
+    /// Open the TCP connection to the proxy itself.
+    pub(crate) fn dial(proxy: &Proxy, timeout: Option<Duration>) -> Result<TcpStream> {
+        let proxy_url = format!("{}:{}", proxy.ip, proxy.port);
+        let connected = match timeout {
+            Some(timeout) => proxy_url
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| Error::other(format!("Invalid proxy address: {}", proxy_url)))
+                .and_then(|addr| TcpStream::connect_timeout(&addr, timeout)),
+            None => TcpStream::connect(proxy_url),
+        };
+        let stream = connected.map_err(|e| Error::other(format!("Failed to connect to proxy: {}", e)))?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(stream)
+    }
+
     fn remote(proxy: Proxy) -> Result<TcpStream> {
         // create a connection
-        let proxy_url = format!("{}:{}", proxy.ip, proxy.port);
-        let mut remote_stream = TcpStream::connect(proxy_url).map_err(|e| {
-            Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to connect to proxy: {}", e),
-            )
-        })?;
+        let mut remote_stream = info_span!("upstream_connect").in_scope(|| Self::dial(&proxy, None))?;
+        info_span!("upstream_auth").in_scope(|| Self::negotiate(&mut remote_stream, &proxy))?;
+        Ok(remote_stream)
+    }
 
+    /// SOCKS5 greeting and username/password authentication with the proxy,
+    /// HTTP proxies authenticate with the CONNECT request instead.
+    pub(crate) fn negotiate(remote_stream: &mut TcpStream, proxy: &Proxy) -> Result<()> {
+        if proxy.protocol == Protocol::Http {
+            return Ok(());
+        }
         // greeting header
-        remote_stream.write(&[
+        remote_stream.write_all(&[
             SOCKS_VERSION, // SOCKS version
             0x01,          // Number of authentication methods
             0x02,          // Username/password authentication
@@ -47,31 +192,25 @@ impl ProxyServer {
 
         // Receive the servers reply
         let mut buffer: [u8; 2] = [0; 2];
-        remote_stream.read(&mut buffer)?;
+        remote_stream.read_exact(&mut buffer)?;
 
         // Check the SOCKS version
         if buffer[0] != SOCKS_VERSION {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                format!("Server does not support socks version: {}", SOCKS_VERSION),
-            ));
+            return Err(Error::other(format!(
+                "Server does not support socks version: {}",
+                SOCKS_VERSION
+            )));
         }
 
         // Check the authentication method
         if buffer[1] != 0x02 {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
+            return Err(Error::other(
                 "Server does not support username/password authentication",
             ));
         }
-        if proxy.auth.is_none() {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Proxy requires authentication",
-            ));
-        }
-
-        let proxy_auth = proxy.auth.as_ref().unwrap();
+        let Some(proxy_auth) = proxy.auth.as_ref() else {
+            return Err(Error::other("Proxy requires authentication"));
+        };
 
         // Create a username/password negotiation request
         let username: &str = proxy_auth.user.as_str();
@@ -87,140 +226,468 @@ impl ProxyServer {
         auth_request.extend_from_slice(password.as_bytes());
 
         // Send the username/password negotiation request
-        remote_stream.write(&auth_request)?;
+        remote_stream.write_all(&auth_request)?;
 
         // Receive the username/password negotiation reply/welcome message
         let mut buffer: [u8; 2] = [0; 2];
-        remote_stream.read(&mut buffer)?;
+        remote_stream.read_exact(&mut buffer)?;
 
         // Check the username/password authentication version
         if buffer[0] != AUTHENTICATION_VERSION {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "Unsupported username/password authentication version: {}",
-                    buffer[0]
-                ),
-            ));
+            return Err(Error::other(format!(
+                "Unsupported username/password authentication version: {}",
+                buffer[0]
+            )));
         }
 
         // Check the username/password authentication status
         if buffer[1] != 0x00 {
+            return Err(Error::other("Username/password authentication failed"));
+        }
+
+        Ok(())
+    }
+
+    /// Open a tunnel to `target` through `proxy`, returning the stream and the raw SOCKS reply.
+    fn connect(proxy: Proxy, target: &Target) -> Result<(TcpStream, Vec<u8>)> {
+        let mut remote_stream = Self::remote(proxy.clone())?;
+        let reply = info_span!("connect_reply").in_scope(|| Self::request(&mut remote_stream, &proxy, target))?;
+        Ok((remote_stream, reply))
+    }
+
+    /// Send the CONNECT request on a negotiated stream and read the reply. HTTP
+    /// proxies answer with a status line, their success is turned into a SOCKS reply.
+    pub(crate) fn request(remote_stream: &mut TcpStream, proxy: &Proxy, target: &Target) -> Result<Vec<u8>> {
+        if proxy.protocol == Protocol::Http {
+            return Self::request_http(remote_stream, proxy, target);
+        }
+        remote_stream.write_all(&target.to_socks_request())?;
+        let reply = handshake::read_socks_reply(remote_stream)?;
+        if reply[1] != 0x00 {
+            // network/host unreachable, refused and TTL expired are about the target
+            let kind = match reply[1] {
+                0x03..=0x06 => ErrorKind::ConnectionRefused,
+                _ => ErrorKind::Other,
+            };
             return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Username/password authentication failed",
+                kind,
+                format!("Proxy refused connection to {}: {}", target, reply[1]),
             ));
         }
+        Ok(reply)
+    }
 
-        // Return the stream
-        Ok(remote_stream)
+    /// `Proxy-Authorization` header line for an HTTP proxy, empty without credentials.
+    pub(crate) fn proxy_authorization(proxy: &Proxy) -> String {
+        match &proxy.auth {
+            Some(auth) => {
+                let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", auth.user, auth.pass));
+                format!("Proxy-Authorization: Basic {}\r\n", credentials)
+            }
+            None => String::new(),
+        }
     }
 
-    fn client(mut local_stream: TcpStream, mut remote_stream: TcpStream) -> Result<()> {
-        // greeting header
-        let mut buffer: [u8; 2] = [0; 2];
-        local_stream.read(&mut buffer[..])?;
-        let _version = buffer[0]; // should be the same as SOCKS_VERSION
-        let number_of_methods = buffer[1];
-
-        // authentication methods
-        let mut methods: Vec<u8> = vec![];
-        for _ in 0..number_of_methods {
-            let mut next_method: [u8; 1] = [0; 1];
-            local_stream.read(&mut next_method[..])?;
-            methods.push(next_method[0]);
+    fn request_http(remote_stream: &mut TcpStream, proxy: &Proxy, target: &Target) -> Result<Vec<u8>> {
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        request.push_str(&Self::proxy_authorization(proxy));
+        request.push_str("\r\n");
+        remote_stream.write_all(request.as_bytes())?;
+
+        let head = handshake::read_http_head(remote_stream)?;
+        let head = String::from_utf8_lossy(&head);
+        let status: u16 = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| Error::other("Invalid HTTP proxy response"))?;
+        match status {
+            200..=299 => Ok(REPLY_SUCCEEDED.to_vec()),
+            // the proxy could not reach the target
+            502..=504 => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Proxy refused connection to {}: {}", target, status),
+            )),
+            _ => Err(Error::other(format!("Proxy rejected CONNECT to {}: {}", target, status))),
         }
+    }
 
-        // only accept no authentication
-        if !methods.contains(&0x00) {
-            // no acceptable methods were offered
-            local_stream.write(&[SOCKS_VERSION, 0xFF])?;
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Method not supported",
-            ));
+    /// Feed the outcome of an upstream dial to the selector, target-side refusals
+    /// don't count against the proxy.
+    fn report(&self, proxy: &Proxy, result: &Result<(TcpStream, Vec<u8>)>) {
+        let Some(selector) = &self.selector else {
+            return;
+        };
+        match result {
+            Ok(_) => selector.report(proxy, true),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+            Err(_) => selector.report(proxy, false),
         }
+    }
 
-        // we choose no authentication
-        local_stream.write(&[SOCKS_VERSION, 0x00])?;
+    /// Tell the selector what `host` made of a connection through `proxy`.
+    fn report_destination(&self, proxy: &Proxy, host: &str, outcome: DestinationOutcome) {
+        if let Some(selector) = &self.selector {
+            selector.report_destination(proxy, host, outcome);
+        }
+    }
+
+    /// Copy both ways until the streams close, returns what was sent to the
+    /// remote and what came back, keeping the first `keep` bytes of the latter.
+    /// No more than `limit` bytes are sent. `sent` and `received` follow along.
+    fn relay(
+        mut local_stream: TcpStream,
+        mut remote_stream: TcpStream,
+        sent: Counter,
+        received: Counter,
+        keep: usize,
+        limit: u64,
+    ) -> Result<(Copied, Copied)> {
         // clone our streams
         let mut incoming_local = local_stream.try_clone()?;
         let mut incoming_remote = remote_stream.try_clone()?;
 
         // copy the data from one to the other
-        let handle_outgoing = thread::spawn(move || -> Result<()> {
-            copy(&mut local_stream, &mut remote_stream)?;
-            Ok(())
-        });
+        let handle_outgoing = thread::spawn(move || copy_counted(&mut local_stream, &mut remote_stream, &sent, 0, limit));
 
-        let handle_incoming = thread::spawn(move || -> Result<()> {
-            copy(&mut incoming_remote, &mut incoming_local)?;
-            Ok(())
-        });
+        let handle_incoming =
+            thread::spawn(move || copy_counted(&mut incoming_remote, &mut incoming_local, &received, keep, u64::MAX));
 
-        _ = handle_outgoing.join();
-        _ = handle_incoming.join();
+        let sent = handle_outgoing.join().unwrap_or_default();
+        let received = handle_incoming.join().unwrap_or_default();
 
         // The End.
+        Ok((sent, received))
+    }
+
+    fn session_routed(&self, params: &SessionParams) -> bool {
+        self.selector.is_some() && !params.is_empty()
+    }
+
+    /// Upstream of a connection to `host`. Rotating connections leave the
+    /// current proxy only while it is cooling down for `host`.
+    fn upstream_for(&self, params: &SessionParams, host: &str) -> Option<Proxy> {
+        let Some(selector) = &self.selector else {
+            return self.get_proxy();
+        };
+        if self.session_routed(params) {
+            return selector.select_for(params, host);
+        }
+        match self.get_proxy() {
+            Some(proxy) if selector.is_cooling(&proxy, host) => selector.select_for(params, host),
+            current => current,
+        }
+    }
+
+    /// `upstream_for`, retried until the queue timeout when the policy is to
+    /// queue connections.
+    fn await_upstream(&self, params: &SessionParams, host: &str) -> Option<Proxy> {
+        let deadline = Instant::now() + self.queue_timeout;
+        loop {
+            let upstream = self.upstream_for(params, host);
+            let queued = self.exhaustion == Exhaustion::Queue && Instant::now() < deadline && !self.is_stopped();
+            if upstream.is_some() || !queued {
+                return upstream;
+            }
+            thread::sleep(QUEUE_POLL);
+        }
+    }
+
+    fn is_current(&self, proxy: &Proxy) -> bool {
+        self.get_proxy().is_some_and(|current| current.addr() == proxy.addr())
+    }
+
+    /// Count traffic of `proxy` while it is the current one, session routed
+    /// connections do not wear it out.
+    fn track(&self, proxy: &Proxy, update: impl FnOnce(&mut Usage)) {
+        if !self.is_current(proxy) {
+            return;
+        }
+        update(&mut self.usage.lock().unwrap());
+        if let Some(usage_changed) = &self.usage_changed {
+            usage_changed.notify_one();
+        }
+    }
+
+    fn client(&self, local_stream: TcpStream) -> Result<()> {
+        let started = Instant::now();
+        let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let mut record = AccessRecord::new(id, self.addr.port(), local_stream.peer_addr().ok());
+        let span = info_span!(
+            "connection",
+            id,
+            listener = self.addr.port(),
+            client = field::debug(record.client),
+            protocol = field::Empty,
+            user = field::Empty,
+            target = field::Empty,
+            upstream = field::Empty,
+            outcome = field::Empty,
+        );
+        let result = span.in_scope(|| self.serve_client(local_stream, &mut record));
+        span.record("outcome", field::debug(record.outcome));
+        if let Some(access_log) = &self.access_log {
+            record.duration_ms = started.elapsed().as_millis() as u64;
+            record.error = result.as_ref().err().map(|e| e.to_string());
+            if let Err(e) = access_log.write(&record) {
+                error!("Failed to write access log: {}", e);
+            }
+        }
+        result
+    }
+
+    /// Handshake, dial and relay, filling `record` in as the connection goes.
+    fn serve_client(&self, mut local_stream: TcpStream, record: &mut AccessRecord) -> Result<()> {
+        let port = self.addr.port();
+        let _active = self.metrics.as_ref().map(|m| m.accepted(port));
+        let protocol = handshake::detect(&local_stream)?;
+        record.protocol = Some(protocol);
+        if !self.protocols.is_empty() && !self.protocols.contains(&protocol) {
+            return Err(Error::other(format!("{} is not accepted on {}", protocol, self.addr)));
+        }
+        let handshake =
+            info_span!("handshake").in_scope(|| handshake::accept(&mut local_stream, self.credentials.as_ref()))?;
+        record.user = Some(handshake.params.user.clone()).filter(|u| !u.is_empty());
+        record.method = Some(handshake.method.clone());
+        record.target = Some(handshake.target.to_string());
+        let span = tracing::Span::current();
+        span.record("protocol", field::display(protocol));
+        span.record("user", &handshake.params.user);
+        span.record("target", field::display(&handshake.target));
+        let host = handshake.target.host.as_str();
+        let Some(proxy) = self.await_upstream(&handshake.params, host) else {
+            // only the listener running out of proxies goes direct, not a
+            // session nothing matches
+            let exhausted = self.get_proxy().is_none() && !self.session_routed(&handshake.params);
+            if self.exhaustion == Exhaustion::Direct && exhausted {
+                return self.serve_direct(local_stream, &handshake, record);
+            }
+            record.outcome = Outcome::NoUpstream;
+            handshake::reply_unavailable(&mut local_stream, handshake.protocol)?;
+            return Err(Error::other(format!(
+                "No upstream available for {:?}",
+                handshake.params
+            )));
+        };
+        record.upstream = Some(proxy.addr());
+        span.record("upstream", proxy.addr());
+        record.egress_ip = proxy.egress_ip.clone();
+        let started = Instant::now();
+        let result = Self::connect(proxy.clone(), &handshake.target);
+        self.report(&proxy, &result);
+        if let Some(metrics) = &self.metrics {
+            metrics.dial(port, &proxy, result.as_ref().map(|_| started.elapsed()));
+        }
+        self.track(&proxy, |usage| {
+            usage.connections += 1;
+            usage.failures = if result.is_ok() { 0 } else { usage.failures + 1 };
+        });
+        let (remote_stream, reply) = match result {
+            Ok(remote) => remote,
+            Err(e) => {
+                if e.kind() == ErrorKind::ConnectionRefused {
+                    self.report_destination(&proxy, host, DestinationOutcome::Refused);
+                }
+                record.outcome = Outcome::UpstreamError;
+                handshake::reply_failure(&mut local_stream, handshake.protocol)?;
+                return Err(e);
+            }
+        };
+        let rotating = !self.session_routed(&handshake.params);
+        let (sent, received) = self.relay_client(local_stream, remote_stream, &handshake, &reply, record, rotating)?;
+        let forwarded = handshake.method != "CONNECT";
+        let outcome = if received.reset {
+            DestinationOutcome::Reset
+        } else if forwarded {
+            DestinationOutcome::Response(received.head)
+        } else {
+            DestinationOutcome::Ok
+        };
+        self.report_destination(&proxy, host, outcome);
+        let received = received.total;
+        record.sent = sent.total + handshake.pending.len() as u64;
+        record.received = received;
+        self.track(&proxy, |usage| usage.bytes += record.sent + received);
+        if let Some(metrics) = &self.metrics {
+            metrics.relayed(port, &proxy, record.sent, received);
+        }
         Ok(())
     }
 
+    /// Register the connection, open the client's side of the tunnel and relay
+    /// until either side closes. The upstream is the one of `record`.
+    fn relay_client(
+        &self,
+        mut local_stream: TcpStream,
+        mut remote_stream: TcpStream,
+        handshake: &Handshake,
+        reply: &[u8],
+        record: &mut AccessRecord,
+        rotating: bool,
+    ) -> Result<(Copied, Copied)> {
+        let relay = Relay {
+            connection: Connection {
+                id: record.id,
+                listener: self.addr.port(),
+                client: record.client,
+                target: handshake.target.to_string(),
+                upstream: record.upstream.clone().unwrap_or_default(),
+                started_at: record.time,
+                sent: 0,
+                received: 0,
+            },
+            rotating,
+            streams: [local_stream.try_clone()?, remote_stream.try_clone()?],
+            sent: Arc::new(AtomicU64::new(handshake.pending.len() as u64)),
+            received: Arc::new(AtomicU64::new(0)),
+        };
+        let (sent, received) = (relay.sent.clone(), relay.received.clone());
+        self.connections.register(relay)?;
+        record.outcome = Outcome::Ok;
+        // forwarded HTTP responses are looked at for signs of a ban
+        let keep = if handshake.method != "CONNECT" { RESPONSE_HEAD_LEN } else { 0 };
+        let limit = handshake.body_len.unwrap_or(u64::MAX);
+        let relayed = handshake::reply_success(&mut local_stream, handshake, reply)
+            .and_then(|_| remote_stream.write_all(&handshake.pending))
+            .and_then(|_| {
+                info_span!("relay")
+                    .in_scope(|| Self::relay(local_stream, remote_stream, sent, received, keep, limit))
+            });
+        self.connections.remove(record.id);
+        relayed
+    }
+
+    /// Connect to the target without a proxy, only the direct exhaustion
+    /// policy gets here.
+    fn serve_direct(&self, mut local_stream: TcpStream, handshake: &Handshake, record: &mut AccessRecord) -> Result<()> {
+        debug!("No upstream on {}, connecting to {} directly", self.addr, handshake.target);
+        record.upstream = Some(DIRECT.to_string());
+        tracing::Span::current().record("upstream", DIRECT);
+        let remote_stream = match TcpStream::connect((handshake.target.host.as_str(), handshake.target.port)) {
+            Ok(remote_stream) => remote_stream,
+            Err(e) => {
+                record.outcome = Outcome::UpstreamError;
+                handshake::reply_failure(&mut local_stream, handshake.protocol)?;
+                return Err(e);
+            }
+        };
+        let (sent, received) =
+            self.relay_client(local_stream, remote_stream, handshake, &REPLY_SUCCEEDED, record, false)?;
+        record.sent = sent.total + handshake.pending.len() as u64;
+        record.received = received.total;
+        Ok(())
+    }
+
+    /// Close the connections the server's proxy `upstream` was relaying
+    /// before now, after `grace` or right away when it is zero. Connections
+    /// routed to it by session parameters stay.
+    pub fn drain(&self, upstream: &str, grace: Duration) {
+        let before = CONNECTION_ID.load(Ordering::Relaxed);
+        let server = self.clone();
+        let upstream = upstream.to_string();
+        if grace.is_zero() {
+            server.retire(&upstream, before);
+            return;
+        }
+        thread::spawn(move || {
+            thread::sleep(grace);
+            server.retire(&upstream, before);
+        });
+    }
+
+    fn retire(&self, upstream: &str, before: u64) {
+        let closed = self.connections.retire(upstream, before);
+        if closed > 0 {
+            info!("Closed {} connections through {} on {}", closed, upstream, self.addr);
+        }
+    }
+
+    /// Connections being relayed, oldest first.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.connections.list()
+    }
+
+    /// Close the connection `id`, returns whether the server relayed it.
+    pub fn close_connection(&self, id: u64) -> bool {
+        self.connections.close(|relay| relay.connection.id == id) > 0
+    }
+
+    /// Close every connection of the client `ip`, returns how many.
+    pub fn close_client(&self, ip: IpAddr) -> usize {
+        self.connections.close(|relay| relay.connection.client.is_some_and(|c| c.ip() == ip))
+    }
+
+    /// Close every connection through `upstream`, session routed ones
+    /// included, returns how many.
+    pub fn close_upstream(&self, upstream: &str) -> usize {
+        self.connections.close(|relay| relay.connection.upstream == upstream)
+    }
+
     pub fn check_proxy(proxy: Proxy) -> Result<Proxy> {
-        let start = std::time::Instant::now();
-        let mut remote = Self::remote(proxy.clone())?;
-        let dest = "httpbin.org:80";
-        remote.write(&[
-            SOCKS_VERSION,    // SOCKS version
-            0x01,             // Connect
-            0x00,             // Reserved
-            0x03,             // Domain name
-            dest.len() as u8, // Domain name length
-        ])?;
-        remote.write(dest.as_bytes())?;
-        remote.write(&[0x00, 0x50])?;
-        let mut buffer: [u8; 10] = [0; 10];
-        remote.read(&mut buffer)?;
-        let latency = start.elapsed();
-        let mut new_proxy = proxy.clone();
-        new_proxy.latency = latency;
-        new_proxy.is_working = true;
-        Ok(new_proxy)
+        Self::check_proxy_with(proxy, &ProbeConfig::default())
+    }
+
+    pub fn check_proxy_with(proxy: Proxy, probes: &ProbeConfig) -> Result<Proxy> {
+        probe::check(&proxy, probes)
     }
 
     pub fn get_proxy(&self) -> Option<Proxy> {
-        self.proxy.lock().map_or(None, |p| Some(p.clone()))
+        self.proxy.lock().map_or(None, |p| p.clone())
     }
 
     pub fn get_addr(&self) -> SocketAddr {
-        self.addr.clone()
+        self.addr
     }
 
     pub fn get_duration(&self) -> std::time::Duration {
         self.started_at.lock().unwrap().elapsed()
     }
 
+    pub fn get_usage(&self) -> Usage {
+        *self.usage.lock().unwrap()
+    }
+
     pub fn set_proxy(&self, new_proxy: Proxy) -> Result<()> {
-        match ProxyServer::check_proxy(new_proxy.clone()) {
+        match ProxyServer::check_proxy_with(new_proxy.clone(), &self.probes) {
             Ok(p) => {
-                let mut proxy = self.proxy.lock().unwrap();
-                *proxy = p;
+                info!("Proxy changed to: {}", p);
+                *self.proxy.lock().unwrap() = Some(p);
                 let mut started_at = self.started_at.lock().unwrap();
                 *started_at = std::time::Instant::now();
-                info!("Proxy changed to: {}", proxy);
+                *self.usage.lock().unwrap() = Usage::default();
                 Ok(())
             }
             Err(e) => {
                 error!("Failed to check proxy: {:?}", e);
-                return Err(e);
+                Err(e)
             }
         }
     }
 
+    /// Drop the current proxy when no other one is left, new connections
+    /// follow the exhaustion policy until `set_proxy`.
+    pub fn clear_proxy(&self) {
+        if self.proxy.lock().unwrap().take().is_none() {
+            return;
+        }
+        *self.started_at.lock().unwrap() = std::time::Instant::now();
+        *self.usage.lock().unwrap() = Usage::default();
+        warn!("No proxy left for server {}, new connections {}", self.addr, self.exhaustion_summary());
+    }
+
+    fn exhaustion_summary(&self) -> String {
+        match self.exhaustion {
+            Exhaustion::Fail => "fail".to_string(),
+            Exhaustion::Queue => format!("queue for up to {}s", self.queue_timeout.as_secs()),
+            Exhaustion::Direct => "go direct".to_string(),
+        }
+    }
+
     pub fn start(&self) -> Result<()> {
         info!(
             "Starting proxy server on: {} | Proxy {}",
             self.addr,
-            self.proxy.lock().unwrap().ip
+            self.get_proxy().map_or_else(|| "none".to_string(), |p| p.ip)
         );
         let server = TcpListener::bind(self.addr)?;
         for stream in server.incoming() {
@@ -229,21 +696,15 @@ impl ProxyServer {
                 break;
             }
             match stream {
-                Ok(stream) => match self.proxy.lock() {
-                    Ok(proxy) => {
-                        let remote_stream: TcpStream = Self::remote(proxy.clone())?;
-                        thread::spawn(move || match Self::client(stream, remote_stream) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                error!("Failed to handle client: {:?}", e);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        error!("Failed to get proxy: {:?}", e);
-                        continue;
-                    }
-                },
+                Ok(stream) => {
+                    let server = self.clone();
+                    thread::spawn(move || match server.client(stream) {
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("Failed to handle client: {:?}", e);
+                        }
+                    });
+                }
                 Err(e) => {
                     error!("Failed to accept connection: {:?}", e);
                     return Err(e);
@@ -259,28 +720,198 @@ impl ProxyServer {
         Ok(())
     }
 
+    /// Whether `stop` was called, the accept loop may also end on an error.
+    pub fn is_stopped(&self) -> bool {
+        *self.should_stop.lock().unwrap()
+    }
+
     pub fn stop(&self) {
         *self.should_stop.lock().unwrap() = true;
         info!("Stopping proxy server on: {}", self.addr);
-        let stream = TcpStream::connect(self.addr).unwrap();
-        drop(stream);
+        // wake up the accept loop, it may never have bound
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(IpAddr::from([127, 0, 0, 1]));
+        }
+        if let Ok(stream) = TcpStream::connect(addr) {
+            drop(stream);
+        }
     }
 }
 
-impl TryFrom<(i16, Proxy)> for ProxyServer {
+impl TryFrom<(u16, Proxy)> for ProxyServer {
     type Error = Error;
 
-    fn try_from(value: (i16, Proxy)) -> Result<Self> {
+    fn try_from(value: (u16, Proxy)) -> Result<Self> {
         ProxyServer::new_with_proxy(value.0, value.1)
     }
 }
 
-impl TryFrom<(i16, String)> for ProxyServer {
+impl TryFrom<(u16, String)> for ProxyServer {
     type Error = Error;
 
-    fn try_from((port, proxy_str): (i16, String)) -> Result<Self> {
-        let proxy =
-            Proxy::from_str(&proxy_str).map_err(|e| Error::new(std::io::ErrorKind::Other, e))?;
-        return ProxyServer::try_from((port, proxy));
+    fn try_from((port, proxy_str): (u16, String)) -> Result<Self> {
+        let proxy = Proxy::from_str(&proxy_str).map_err(Error::other)?;
+        ProxyServer::try_from((port, proxy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::{connect, free_port, spawn_http, spawn_upstream};
+
+    #[test]
+    fn test_http_upstream() {
+        let url = spawn_http(|request| {
+            // "user:pass"
+            if request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz") {
+                "HTTP/1.1 200 Connection established\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_string()
+            }
+        });
+        let proxy = Proxy::from_str(&url.replace("http://", "http://user:pass@")).unwrap();
+        let target = Target {
+            host: "example.com".to_string(),
+            port: 443,
+        };
+
+        let mut stream = ProxyServer::dial(&proxy, Some(Duration::from_secs(2))).unwrap();
+        ProxyServer::negotiate(&mut stream, &proxy).unwrap();
+        let reply = ProxyServer::request(&mut stream, &proxy, &target).unwrap();
+        assert_eq!(reply[1], 0x00);
+
+        let anonymous = Proxy { auth: None, ..proxy };
+        let mut stream = ProxyServer::dial(&anonymous, Some(Duration::from_secs(2))).unwrap();
+        assert!(ProxyServer::request(&mut stream, &anonymous, &target).is_err());
+    }
+
+    /// Send a plain HTTP request for `url` through `port` and read the
+    /// response up to `until`, the relay stays open.
+    fn open_relay(port: u16, url: &str, until: &str) -> TcpStream {
+        let mut client = connect(port);
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", url).as_bytes())
+            .unwrap();
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(until.as_bytes()) && client.read(&mut byte).unwrap() > 0 {
+            response.push(byte[0]);
+        }
+        assert!(response.ends_with(until.as_bytes()), "{}", String::from_utf8_lossy(&response));
+        client
+    }
+
+    #[test]
+    fn test_exhaustion_policies() {
+        let failing = ProxyServer::new(free_port()).unwrap();
+        let runner = failing.clone();
+        thread::spawn(move || runner.start());
+        open_relay(failing.get_addr().port(), "http://example.com/", "503 Service Unavailable");
+        failing.stop();
+
+        // queued until a proxy shows up
+        let queued = ProxyServer::new(free_port())
+            .unwrap()
+            .with_exhaustion(Exhaustion::Queue, Duration::from_secs(5));
+        let runner = queued.clone();
+        thread::spawn(move || runner.start());
+        let refill = queued.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            refill.set_proxy(spawn_upstream("old")).unwrap();
+        });
+        open_relay(queued.get_addr().port(), "http://example.com/", "old");
+        queued.stop();
+
+        let origin = spawn_http(|_| "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ndirect".to_string());
+        let direct = ProxyServer::new(free_port())
+            .unwrap()
+            .with_exhaustion(Exhaustion::Direct, Duration::ZERO);
+        let runner = direct.clone();
+        thread::spawn(move || runner.start());
+        let _client = open_relay(direct.get_addr().port(), &format!("{}/", origin), "direct");
+        assert_eq!(direct.connections()[0].upstream, DIRECT);
+        direct.stop();
+    }
+
+    #[test]
+    fn test_forwards_one_request() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let other = spawn_http(|_| "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nother".to_string());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            // anything after the first request would leak to this origin
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            sender.send(String::from_utf8_lossy(&received).to_string()).unwrap();
+        });
+
+        let server = ProxyServer::new(free_port())
+            .unwrap()
+            .with_exhaustion(Exhaustion::Direct, Duration::ZERO);
+        let runner = server.clone();
+        thread::spawn(move || runner.start());
+        let mut client = connect(server.get_addr().port());
+        client
+            .write_all(
+                format!(
+                    "GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\nConnection: keep-alive\r\n\r\n\
+                    GET {other}/secret HTTP/1.1\r\nHost: other\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let mut response = [0u8; 64];
+        let n = client.read(&mut response).unwrap();
+        assert!(String::from_utf8_lossy(&response[..n]).ends_with("ok"));
+        drop(client);
+
+        let received = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received.matches("GET ").count(), 1, "{}", received);
+        assert!(received.contains("Connection: close\r\n"));
+        assert!(!received.contains("keep-alive") && !received.contains("secret"));
+        server.stop();
+    }
+
+    #[test]
+    fn test_hard_rotation_closes_relays() {
+        let port = free_port();
+        let old = spawn_upstream("old");
+        let server = ProxyServer::new_with_proxy(port, old.clone()).unwrap();
+        let runner = server.clone();
+        thread::spawn(move || runner.start());
+        let mut client = open_relay(port, "http://example.com/", "old");
+
+        server.set_proxy(spawn_upstream("new")).unwrap();
+        // soft, the relay keeps going
+        server.drain(&old.addr(), Duration::from_secs(60));
+        assert_eq!(server.connections()[0].upstream, old.addr());
+        server.drain(&old.addr(), Duration::ZERO);
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap_or(0), 0);
+        for _ in 0..50 {
+            if server.connections().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(server.connections().is_empty());
+        server.stop();
     }
 }
@@ -0,0 +1,153 @@
+use crate::{Anonymity, Proxy};
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What a destination made of a connection through an upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestinationOutcome {
+    Ok,
+    /// The upstream could not open the tunnel to it.
+    Refused,
+    /// The remote end reset the relayed connection.
+    Reset,
+    /// First bytes of the response to a forwarded HTTP request.
+    Response(Vec<u8>),
+}
+
+/// Picks the upstream for a connection whose username carried session parameters.
+pub trait UpstreamSelector: Send + Sync + Debug {
+    fn select(&self, params: &SessionParams) -> Option<Proxy>;
+
+    /// Like `select`, skipping the upstreams cooling down for `host`.
+    fn select_for(&self, params: &SessionParams, _host: &str) -> Option<Proxy> {
+        self.select(params)
+    }
+
+    /// Outcome of an in-band connection attempt through `proxy`.
+    fn report(&self, _proxy: &Proxy, _ok: bool) {}
+
+    /// What `host` made of a connection through `proxy`.
+    fn report_destination(&self, _proxy: &Proxy, _host: &str, _outcome: DestinationOutcome) {}
+
+    /// Whether `proxy` looks banned by `host` for now.
+    fn is_cooling(&self, _proxy: &Proxy, _host: &str) -> bool {
+        false
+    }
+}
+
+/// Routing parameters encoded in an inbound username, in the style of
+/// commercial rotating gateways:
+///
+/// `user-session-abc123-country-de-pool-resi-lifetime-30m`
+///
+/// The first `-` separated token is the account name, the rest are
+/// `key-value` pairs. Keys: `session`, `country`, `pool`, `lifetime` and
+/// `anonymity` (minimum level: `transparent`, `anonymous`, `elite`).
+/// A bare lifetime number is read as minutes, `s`/`m`/`h` suffixes are accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionParams {
+    pub user: String,
+    pub session: Option<String>,
+    pub country: Option<String>,
+    pub pool: Option<String>,
+    pub lifetime: Option<Duration>,
+    pub anonymity: Option<Anonymity>,
+}
+
+impl SessionParams {
+    /// True when the username carried no routing parameters at all.
+    pub fn is_empty(&self) -> bool {
+        self.session.is_none()
+            && self.country.is_none()
+            && self.pool.is_none()
+            && self.lifetime.is_none()
+            && self.anonymity.is_none()
+    }
+}
+
+fn parse_lifetime(value: &str) -> Result<Duration, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c),
+        _ => (value, 'm'),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("Invalid session lifetime: {}", value))?;
+    let secs = match unit {
+        's' => Some(n),
+        'm' => n.checked_mul(60),
+        'h' => n.checked_mul(3600),
+        _ => return Err(format!("Invalid session lifetime unit: {}", value)),
+    };
+    secs.map(Duration::from_secs)
+        .ok_or_else(|| format!("Invalid session lifetime: {}", value))
+}
+
+impl SessionParams {
+    /// The `-` separated `key-value` pairs of `params` for the account `user`,
+    /// which may contain `-` itself.
+    pub fn parse(user: &str, params: &str) -> Result<Self, String> {
+        if user.is_empty() {
+            return Err("Empty username".to_string());
+        }
+        let mut parsed = SessionParams {
+            user: user.to_string(),
+            ..Default::default()
+        };
+        let mut parts = params.split('-').filter(|_| !params.is_empty());
+        while let Some(key) = parts.next() {
+            let value = parts
+                .next()
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| format!("Missing value for session parameter: {}", key))?;
+            match key {
+                "session" => parsed.session = Some(value.to_string()),
+                "country" => parsed.country = Some(value.to_ascii_uppercase()),
+                "pool" => parsed.pool = Some(value.to_string()),
+                "lifetime" => parsed.lifetime = Some(parse_lifetime(value)?),
+                "anonymity" => parsed.anonymity = Some(Anonymity::from_str(value)?),
+                _ => return Err(format!("Unknown session parameter: {}", key)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl FromStr for SessionParams {
+    type Err = String;
+
+    /// A username whose first `-` separated token is the account name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, params) = s.split_once('-').unwrap_or((s, ""));
+        SessionParams::parse(user, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_session_params() {
+        let params = SessionParams::from_str("user-session-abc123-country-de-pool-resi").unwrap();
+        assert_eq!(params.user, "user");
+        assert_eq!(params.session.as_deref(), Some("abc123"));
+        assert_eq!(params.country.as_deref(), Some("DE"));
+        assert_eq!(params.pool.as_deref(), Some("resi"));
+        assert_eq!(params.lifetime, None);
+
+        let params = SessionParams::from_str("user-session-x-lifetime-90s").unwrap();
+        assert_eq!(params.lifetime, Some(Duration::from_secs(90)));
+        let params = SessionParams::from_str("user-lifetime-10").unwrap();
+        assert_eq!(params.lifetime, Some(Duration::from_secs(600)));
+
+        assert!(SessionParams::from_str("user").unwrap().is_empty());
+        assert!(SessionParams::from_str("user-session").is_err());
+        assert!(SessionParams::from_str("user-colour-red").is_err());
+        let params = SessionParams::parse("acme-prod", "session-a").unwrap();
+        assert_eq!((params.user.as_str(), params.session.as_deref()), ("acme-prod", Some("a")));
+        let overflow = SessionParams::from_str("user-session-x-lifetime-99999999999999999h").unwrap_err();
+        assert!(overflow.contains("Invalid session lifetime"), "{}", overflow);
+    }
+}
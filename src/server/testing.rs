@@ -0,0 +1,137 @@
+//! Local stand-ins for upstream proxies used by the tests.
+use crate::Proxy;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+fn serve(mut stream: TcpStream, body: &str) -> std::io::Result<()> {
+    // greeting, username/password
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods)?;
+    stream.write_all(&[0x05, 0x02])?;
+    let mut auth = [0u8; 2];
+    stream.read_exact(&mut auth)?;
+    let mut user = vec![0u8; auth[1] as usize];
+    stream.read_exact(&mut user)?;
+    let mut pass_len = [0u8; 1];
+    stream.read_exact(&mut pass_len)?;
+    let mut pass = vec![0u8; pass_len[0] as usize];
+    stream.read_exact(&mut pass)?;
+    stream.write_all(&[0x01, 0x00])?;
+
+    // CONNECT request, any target is accepted
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let addr_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        _ => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+    };
+    let mut rest = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut rest)?;
+    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])?;
+
+    // the tunnel answers every HTTP request itself
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(());
+        }
+        head.push(byte[0]);
+    }
+    stream.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )
+}
+
+/// Start a SOCKS5 upstream on a random local port that answers HTTP requests
+/// sent through its tunnels with `body`.
+pub fn spawn_upstream(body: &str) -> Proxy {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let body = body.to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let body = body.clone();
+            thread::spawn(move || serve(stream, &body));
+        }
+    });
+    Proxy::from_str(&format!("127.0.0.1:{}:user:pass", port)).unwrap()
+}
+
+/// `spawn_upstream` as a checked pool entry.
+pub fn live_upstream(body: &str) -> Proxy {
+    Proxy {
+        is_working: true,
+        ..spawn_upstream(body)
+    }
+}
+
+/// A local port nobody listens on yet.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Connect to a listener on `port`, retrying while its thread binds.
+pub fn connect(port: u16) -> TcpStream {
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => return stream,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// `connect` for async tests.
+pub async fn connect_async(port: u16) -> tokio::net::TcpStream {
+    loop {
+        match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => return stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Start an HTTP server on a random local port answering every request with
+/// the response `respond` builds from the request head, returns its base URL.
+pub fn spawn_http(respond: impl Fn(&str) -> String + Send + Sync + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let respond = std::sync::Arc::new(respond);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let respond = respond.clone();
+            thread::spawn(move || -> std::io::Result<()> {
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if stream.read(&mut byte)? == 0 {
+                        return Ok(());
+                    }
+                    head.push(byte[0]);
+                }
+                stream.write_all(respond(&String::from_utf8_lossy(&head)).as_bytes())
+            });
+        }
+    });
+    format!("http://127.0.0.1:{}", port)
+}
+
+/// A proxy on a local port nobody listens on.
+pub fn dead_proxy() -> Proxy {
+    Proxy::from_str(&format!("127.0.0.1:{}:user:pass", free_port())).unwrap()
+}
@@ -0,0 +1,134 @@
+use crate::errors::ProxyError;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Exports the `tracing` spans of the connection lifecycle to an OTLP collector.
+/// `log` output goes through `env_logger` as before. Spans still queued are
+/// flushed when this is dropped.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Install the exporter as the global subscriber. `endpoint` is the base URL
+    /// of the collector's OTLP/HTTP receiver, e.g. `http://localhost:4318`.
+    pub fn init(endpoint: &str) -> Result<Self, ProxyError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let endpoint = if endpoint.ends_with("/v1/traces") {
+            endpoint.to_string()
+        } else {
+            format!("{}/v1/traces", endpoint)
+        };
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| ProxyError::ConfigError(format!("OTLP exporter: {}", e)))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("qproxy").build())
+            .build();
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("qproxy")))
+            .try_init()
+            .map_err(|e| ProxyError::ConfigError(format!("Tracing already set up: {}", e)))?;
+        Ok(Telemetry { provider })
+    }
+
+    /// Export the spans still queued.
+    pub fn flush(&self) {
+        if let Err(e) = self.provider.force_flush() {
+            log::error!("Failed to export spans: {}", e);
+        }
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            log::error!("Failed to shut down tracing: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::{connect, free_port, spawn_upstream};
+    use crate::ProxyServer;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Stand-in collector, sends the body of every export request.
+    fn spawn_collector() -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                if reader.read_exact(&mut body).is_ok() {
+                    let _ = sender.send(body);
+                }
+                let _ = reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            }
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn test_export_connection_spans() {
+        let (url, bodies) = spawn_collector();
+        let telemetry = Telemetry::init(&url).unwrap();
+
+        let port = free_port();
+        let server = ProxyServer::new_with_proxy(port, spawn_upstream("ok")).unwrap();
+        let runner = server.clone();
+        thread::spawn(move || runner.start());
+        let mut client = connect(port);
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        // the relay waits for the client to close, read the body only
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"ok") && client.read(&mut byte).unwrap() > 0 {
+            response.push(byte[0]);
+        }
+        assert!(response.ends_with(b"ok"));
+        drop(client);
+        server.stop();
+
+        // the connection span closes once the relay is done
+        let mut exported = String::new();
+        for _ in 0..50 {
+            telemetry.flush();
+            let body: Vec<u8> = bodies.try_iter().flatten().collect();
+            exported.push_str(&String::from_utf8_lossy(&body));
+            if exported.contains("connection") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(exported.contains("connection"), "no connection span exported");
+        for name in ["handshake", "upstream_connect", "upstream_auth", "connect_reply", "relay"] {
+            assert!(exported.contains(name), "missing span {}", name);
+        }
+    }
+}